@@ -0,0 +1,53 @@
+// Compares walking the expression tree with `eval_in` against running the
+// compiled bytecode, evaluating the same formula against many different
+// environments. Run with `cargo bench`.
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use rust_book_google::epression_evaluation::bytecode::compile;
+use rust_book_google::epression_evaluation::number::Number;
+use rust_book_google::epression_evaluation::parser::parse;
+use rust_book_google::epression_evaluation::{Environment, Evaluator};
+
+const FORMULA: &str = "let base = if hours > 40 then 40 * rate + (hours - 40) * rate * 3 / 2 \
+                       else hours * rate in base + bonus * (base > 1000) - (tax * base) / 100";
+const ROUNDS: i64 = 200_000;
+
+fn time(name: &str, mut f: impl FnMut(&Environment) -> Number, envs: &[Environment]) -> Duration {
+    let start = Instant::now();
+    let mut total = 0.0;
+    for round in 0..ROUNDS {
+        total += f(&envs[round as usize % envs.len()]).to_f64();
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "{name:>8}: {elapsed:?} for {ROUNDS} evaluations ({:?} each, checksum {total})",
+        elapsed / ROUNDS as u32
+    );
+    elapsed
+}
+
+fn main() {
+    let e = parse(FORMULA).unwrap();
+    let program = compile(&e);
+    let evaluator = Evaluator::new();
+
+    let envs: Vec<Environment> = (0..64)
+        .map(|i| {
+            Environment::new()
+                .with("rate", 15 + i % 7)
+                .with("hours", 20 + i)
+                .with("bonus", 100 * (i % 3))
+                .with("tax", 20 + i % 5)
+        })
+        .collect();
+
+    // both have to agree before their speed means anything
+    for env in &envs {
+        assert_eq!(evaluator.eval_in(&e, env), evaluator.run(&program, env));
+    }
+
+    let tree = time("tree", |env| evaluator.eval_in(black_box(&e), env).unwrap(), &envs);
+    let vm = time("bytecode", |env| evaluator.run(black_box(&program), env).unwrap(), &envs);
+    println!("bytecode runs {:.2}x as fast as walking the tree", tree.as_secs_f64() / vm.as_secs_f64());
+}
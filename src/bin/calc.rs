@@ -0,0 +1,175 @@
+// An interactive calculator on top of the expression evaluator. Every line
+// is either an expression to evaluate, a `let name = value` that stays
+// bound for the lines after it, or a command starting with `:`.
+
+use std::io::{self, BufRead, IsTerminal, Write};
+
+use rust_book_google::epression_evaluation::parser::{parse, ParseError};
+use rust_book_google::epression_evaluation::{Environment, Evaluator, Expression};
+
+const HELP: &str = "\
+enter an expression to evaluate it, for example `(1 + 2) * 3` or `7 / 2`
+  let x = 4       bind x for the following lines
+  max(x, 2)       call abs, min, max, clamp, ln, exp or `let f(x) = ... in f(3)`
+  :ast <expr>     show the syntax tree of an expression
+  :trace <expr>   show every step of evaluating an expression
+  :help           show this message
+  :quit           leave (so does end of input)";
+
+/// The state kept between lines.
+#[derive(Default)]
+struct Calculator {
+    env: Environment,
+    evaluator: Evaluator,
+}
+
+impl Calculator {
+    /// Handle one line of input and return what to print, or `None` to
+    /// stop. Errors are printed like any other output, so a mistake never
+    /// ends the session.
+    fn line(&mut self, line: &str) -> Option<String> {
+        let line = line.trim();
+        let output = match line.split_once(' ').unwrap_or((line, "")) {
+            ("", _) => Ok(String::new()),
+            (":quit" | ":q", _) => return None,
+            (":help", _) => Ok(HELP.to_string()),
+            (":ast", "") => Err("usage: :ast <expression>".to_string()),
+            (":ast", input) => {
+                parse(input).map(|e| format!("{e:#?}")).map_err(|error| report(input, error))
+            }
+            (":trace", "") => Err("usage: :trace <expression>".to_string()),
+            (":trace", input) => self.trace(input),
+            (command, _) if command.starts_with(':') => {
+                Err(format!("unknown command {command}, try :help"))
+            }
+            _ => self.evaluate(line),
+        };
+        Some(output.unwrap_or_else(|error| format!("error: {error}")))
+    }
+
+    fn evaluate(&mut self, line: &str) -> Result<String, String> {
+        let error = match parse(line) {
+            Ok(e) => {
+                let value = self.evaluator.eval_in(&e, &self.env).map_err(|e| e.to_string())?;
+                return Ok(value.to_string());
+            }
+            Err(error) => error,
+        };
+
+        // `let x = 4` without an `in` binds `x` for good
+        let Some((name, start)) = binding(line) else {
+            return Err(report(line, error));
+        };
+        let value = parse(&line[start..]).map_err(|mut error| {
+            error.offset += start;
+            report(line, error)
+        })?;
+        let value = self.evaluator.eval_in(&value, &self.env).map_err(|e| e.to_string())?;
+        self.env.bind(name, value);
+        Ok(format!("{name} = {value}"))
+    }
+
+    /// Each step of evaluating `input`, then the result or the error.
+    fn trace(&self, input: &str) -> Result<String, String> {
+        let e = parse(input).map_err(|error| report(input, error))?;
+        let (result, trace) = self.evaluator.trace_in(&e, &self.env);
+        let last = match result {
+            Ok(value) => format!("= {value}"),
+            Err(error) => format!("error: {error}"),
+        };
+        Ok(if trace.0.is_empty() { last } else { format!("{trace}\n{last}") })
+    }
+}
+
+/// If `line` starts like `let name =`, the name and where its value starts.
+fn binding(line: &str) -> Option<(&str, usize)> {
+    let rest = line.strip_prefix("let").filter(|rest| rest.starts_with(char::is_whitespace))?;
+    let (name, _) = rest.split_once('=')?;
+    let name = name.trim();
+    // anything that parses as a variable is a name, keywords do not
+    if !matches!(parse(name), Ok(Expression::Variable(_))) {
+        return None;
+    }
+    Some((name, line.find('=')? + 1))
+}
+
+/// Describe a parse error, pointing at where in the input it happened.
+fn report(input: &str, error: ParseError) -> String {
+    let column = input[..error.offset].chars().count();
+    format!("{error}\n  {input}\n  {:column$}^", "")
+}
+
+fn main() -> io::Result<()> {
+    let mut calculator = Calculator::default();
+    let interactive = io::stdin().is_terminal();
+    let mut stdout = io::stdout();
+
+    // only prompt a person, not a pipe
+    if interactive {
+        println!("type :help for help");
+        write!(stdout, "> ")?;
+        stdout.flush()?;
+    }
+    for line in io::stdin().lock().lines() {
+        let Some(output) = calculator.line(&line?) else {
+            break;
+        };
+        if !output.is_empty() {
+            println!("{output}");
+        }
+        if interactive {
+            write!(stdout, "> ")?;
+            stdout.flush()?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Feed the lines to a fresh calculator and collect what it prints.
+    fn session(lines: &[&str]) -> Vec<String> {
+        let mut calculator = Calculator::default();
+        lines.iter().map(|line| calculator.line(line).unwrap()).collect()
+    }
+
+    #[test]
+    fn evaluates_expressions() {
+        assert_eq!(session(&["10 * 9 + (3 - 4) * 5", "7 / 2", "  ", "0.5 + 1"]), ["85", "7/2", "", "1.5"]);
+    }
+
+    #[test]
+    fn keeps_bindings() {
+        assert_eq!(
+            session(&["let x = 4", "let y = x * 2", "x + y", "let x = 1 in x", "x", "let z = let a = 1 in a + 1"]),
+            ["x = 4", "y = 8", "12", "1", "4", "z = 2"]
+        );
+    }
+
+    #[test]
+    fn reports_errors_and_carries_on() {
+        let output = session(&["1 / 0", "missing + 1", "let x = ", "1 + $", "letx = 2", "let x = 2", "x"]);
+        assert_eq!(output[0], "error: division by zero at root");
+        assert_eq!(output[1], "error: unknown variable \"missing\" at left");
+        assert_eq!(output[2], "error: unexpected end of input at offset 7\n  let x =\n         ^");
+        assert_eq!(output[3], "error: unexpected character '$' at offset 4\n  1 + $\n      ^");
+        assert_eq!(output[4], "error: unexpected token \"=\" at offset 5\n  letx = 2\n       ^");
+        assert_eq!(output[5..], ["x = 2", "2"]);
+    }
+
+    #[test]
+    fn commands() {
+        let output =
+            session(&[":help", ":ast 1 + x", ":ast", ":nope", ":trace (3 - 4) * 5", ":trace 1 / 0"]);
+        assert_eq!(output[0], HELP);
+        assert!(output[1].starts_with("Op {\n    op: Add,"), "{}", output[1]);
+        assert!(output[1].contains("Variable(\n        \"x\",\n    )"), "{}", output[1]);
+        assert_eq!(output[2], "error: usage: :ast <expression>");
+        assert_eq!(output[3], "error: unknown command :nope, try :help");
+        assert_eq!(output[4], "3 - 4 => -1\n-1 * 5 => -5\n= -5");
+        assert_eq!(output[5], "error: division by zero at root");
+        assert_eq!(Calculator::default().line(":quit"), None);
+    }
+}
@@ -0,0 +1,280 @@
+// Rotate the letters of a stream, rot13 unless told otherwise, from stdin
+// to stdout or between files. The input is taken a chunk at a time, so it
+// can be as large as it likes. `rot crack` needs no rotation to be given:
+// it guesses one from the letter frequencies, decodes with it and says on
+// stderr which it picked.
+
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::process::ExitCode;
+
+use rust_book_google::rot13::{crack, Crack, RotDecoder};
+
+const USAGE: &str = "\
+usage: rot [--shift N] [--] [INPUT [OUTPUT]]
+       rot crack [--] [INPUT]
+  --shift N  rotate letters N places along the alphabet (default 13),
+             negative N rotates back
+  --         end of the options, so that a file may start with `-`
+  -h, --help show this
+  INPUT      read from this file instead of stdin, `-` for stdin
+  OUTPUT     write to this file instead of stdout, `-` for stdout,
+             which cannot be the input
+  crack      guess the rotation from English letter frequencies and
+             write the input decoded to stdout";
+
+/// What the command line asks for. Files are `None` for stdin or stdout.
+#[derive(Debug, PartialEq)]
+enum Command {
+    Rotate { shift: u8, input: Option<String>, output: Option<String> },
+    Crack { input: Option<String> },
+    Help,
+}
+
+fn parse_args(args: &[String]) -> Result<Command, String> {
+    let file = |arg: &String| (arg != "-").then(|| arg.clone());
+    let (crack, args) = match args.split_first() {
+        Some((first, rest)) if first == "crack" => (true, rest),
+        _ => (false, args),
+    };
+
+    let mut shift = None;
+    let mut files = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let value = match arg.strip_prefix("--shift") {
+            _ if arg == "-h" || arg == "--help" => return Ok(Command::Help),
+            _ if arg == "--" => {
+                files.extend(args.by_ref().map(file));
+                break;
+            }
+            Some("") => args.next().ok_or("--shift needs a number")?,
+            Some(value) if value.starts_with('=') => &value[1..],
+            _ if arg.starts_with("--") => return Err(format!("unknown option {arg}")),
+            _ => {
+                files.push(file(arg));
+                continue;
+            }
+        };
+        let n: i64 = value.parse().map_err(|_| format!("--shift needs a number, not {value:?}"))?;
+        shift = Some(n.rem_euclid(26) as u8);
+    }
+
+    if crack {
+        return match (shift, files.as_slice()) {
+            (Some(_), _) => Err("crack guesses the rotation, it takes no --shift".to_string()),
+            (None, []) => Ok(Command::Crack { input: None }),
+            (None, [input]) => Ok(Command::Crack { input: input.clone() }),
+            _ => Err("crack takes one file at most".to_string()),
+        };
+    }
+    if files.len() > 2 {
+        return Err("too many files, give an input and an output at most".to_string());
+    }
+    let mut files = files.into_iter();
+    let (input, output) = (files.next().flatten(), files.next().flatten());
+    Ok(Command::Rotate { shift: shift.unwrap_or(13), input, output })
+}
+
+/// Rotate all of `input` into `output`.
+fn rotate(input: impl Read, mut output: impl Write, shift: u8) -> io::Result<()> {
+    io::copy(&mut RotDecoder::new(input, shift), &mut output)?;
+    output.flush()
+}
+
+/// Decode `input` into `output` with the rotation that makes it look the
+/// most like English. All of it is read before anything is written, the
+/// whole text counts towards the guess.
+fn auto_decode(mut input: impl Read, mut output: impl Write) -> io::Result<Crack> {
+    let mut text = Vec::new();
+    input.read_to_end(&mut text)?;
+    let crack = crack(&text);
+    io::copy(&mut RotDecoder::new(text.as_slice(), crack.rot), &mut output)?;
+    output.flush()?;
+    Ok(crack)
+}
+
+/// Name the file in errors about it, which io errors do not do by
+/// themselves.
+fn in_file(path: &str) -> impl Fn(io::Error) -> io::Error + '_ {
+    move |e| io::Error::new(e.kind(), format!("{path}: {e}"))
+}
+
+/// Whether two paths lead to the same file, which creating the output would
+/// empty before any of the input is read.
+fn same_file(a: &str, b: &str) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        // one of them does not exist yet
+        _ => false,
+    }
+}
+
+fn input(path: Option<&str>) -> io::Result<Box<dyn Read>> {
+    match path {
+        None => Ok(Box::new(io::stdin().lock())),
+        Some(path) => Ok(Box::new(File::open(path).map_err(in_file(path))?)),
+    }
+}
+
+fn output(path: Option<&str>) -> io::Result<Box<dyn Write>> {
+    match path {
+        None => Ok(Box::new(io::stdout().lock())),
+        Some(path) => Ok(Box::new(File::create(path).map_err(in_file(path))?)),
+    }
+}
+
+fn run(command: Command) -> io::Result<()> {
+    match command {
+        Command::Rotate { shift, input: from, output: to } => {
+            let input = input(from.as_deref())?;
+            if let (Some(from), Some(to)) = (&from, &to) {
+                if same_file(from, to) {
+                    let message = format!("{to}: is the input as well, and would be emptied");
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+                }
+            }
+            rotate(input, output(to.as_deref())?, shift)
+        }
+        Command::Crack { input: from } => {
+            let crack = auto_decode(input(from.as_deref())?, io::stdout().lock())?;
+            eprintln!("rot {} (confidence {:.2})", crack.rot, crack.confidence);
+            Ok(())
+        }
+        Command::Help => writeln!(io::stdout(), "{USAGE}"),
+    }
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let result = match parse_args(&args) {
+        Ok(command) => run(command),
+        Err(error) => {
+            eprintln!("rot: {error}\n{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("rot: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rust_book_google::rot13::RotEncoder;
+
+    /// Hands out a few bytes a read, failing with `Interrupted` before each.
+    struct Stutter<'a> {
+        data: &'a [u8],
+        interrupted: bool,
+    }
+
+    impl Read for Stutter<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.interrupted = !self.interrupted;
+            if self.interrupted {
+                return Err(io::ErrorKind::Interrupted.into());
+            }
+            let size = buf.len().min(5).min(self.data.len());
+            buf[..size].copy_from_slice(&self.data[..size]);
+            self.data = &self.data[size..];
+            Ok(size)
+        }
+    }
+
+    #[test]
+    fn decodes_without_being_told_the_rotation() {
+        let plain = "Call me Ishmael. Some years ago, never mind how long precisely, having \
+            little or no money in my purse, and nothing particular to interest me on shore, \
+            I thought I would sail about a little and see the watery part of the world.\n";
+        let mut encoder = RotEncoder::new(Vec::new(), 7);
+        encoder.write_all(plain.as_bytes()).unwrap();
+        let secret = encoder.into_inner();
+
+        let mut output = Vec::new();
+        let crack = auto_decode(secret.as_slice(), &mut output).unwrap();
+        assert_eq!(crack.rot, 7);
+        assert_eq!(String::from_utf8(output).unwrap(), plain);
+    }
+
+    fn args(line: &str) -> Result<Command, String> {
+        parse_args(&line.split_whitespace().map(String::from).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn command_line() {
+        let rotate = |shift, input: Option<&str>, output: Option<&str>| {
+            let (input, output) = (input.map(String::from), output.map(String::from));
+            Ok(Command::Rotate { shift, input, output })
+        };
+        assert_eq!(args(""), rotate(13, None, None));
+        assert_eq!(args("--shift 3 in.txt"), rotate(3, Some("in.txt"), None));
+        assert_eq!(args("in.txt --shift=-1 out.txt"), rotate(25, Some("in.txt"), Some("out.txt")));
+        assert_eq!(args("- out.txt --shift 53"), rotate(1, None, Some("out.txt")));
+        assert_eq!(args("crack"), Ok(Command::Crack { input: None }));
+        assert_eq!(args("crack secret.txt"), Ok(Command::Crack { input: Some("secret.txt".into()) }));
+        assert_eq!(args("in.txt --help"), Ok(Command::Help));
+        assert_eq!(args("crack -h"), Ok(Command::Help));
+        // past `--` nothing is an option
+        assert_eq!(args("--shift 3 -- -h --help"), rotate(3, Some("-h"), Some("--help")));
+        assert_eq!(args("-- - --shift"), rotate(13, None, Some("--shift")));
+        assert_eq!(args("crack -- -h"), Ok(Command::Crack { input: Some("-h".into()) }));
+        assert_eq!(args("-- crack"), rotate(13, Some("crack"), None));
+
+        assert_eq!(args("--shift"), Err("--shift needs a number".into()));
+        assert_eq!(args("--shift x"), Err("--shift needs a number, not \"x\"".into()));
+        assert_eq!(args("--shift 1 --verbose"), Err("unknown option --verbose".into()));
+        assert!(args("a b c").is_err());
+        assert!(args("crack a b").is_err());
+        assert!(args("crack --shift 3").is_err());
+        assert!(args("-- a b c").is_err());
+    }
+
+    #[test]
+    fn streams_in_chunks() {
+        // more than io::copy takes at a time
+        let text: Vec<u8> = b"Gb trg gb gur bgure fvqr! ".repeat(8 * 1024);
+        let mut output = Vec::new();
+        rotate(text.as_slice(), &mut output, 13).unwrap();
+        assert_eq!(output, b"To get to the other side! ".repeat(8 * 1024));
+
+        let mut output = Vec::new();
+        let stutter = Stutter { data: b"Uryyb, jbeyq!", interrupted: false };
+        rotate(stutter, &mut output, 13).unwrap();
+        assert_eq!(output, b"Hello, world!");
+    }
+
+    #[test]
+    fn refuses_to_overwrite_the_input() {
+        let dir = std::env::temp_dir().join(format!("rot-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("a.txt");
+        fs::write(&path, "Uryyb").unwrap();
+        let (plain, dotted) = (path.to_str().unwrap(), dir.join(".").join("a.txt"));
+        let rotate = |input: &str, output: &str| {
+            run(Command::Rotate { shift: 13, input: Some(input.into()), output: Some(output.into()) })
+        };
+        for output in [plain, dotted.to_str().unwrap()] {
+            let error = rotate(plain, output).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+            assert_eq!(fs::read_to_string(&path).unwrap(), "Uryyb");
+        }
+
+        let other = dir.join("b.txt");
+        rotate(plain, other.to_str().unwrap()).unwrap();
+        assert_eq!(fs::read_to_string(&other).unwrap(), "Hello");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn missing_file() {
+        let error = input(Some("no/such/file")).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        assert!(error.to_string().starts_with("no/such/file: "));
+    }
+}
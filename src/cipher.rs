@@ -0,0 +1,322 @@
+// Classical letter substitution ciphers, as streaming adapters: a `Decoder`
+// deciphers whatever is read through it and an `Encoder` enciphers
+// whatever is written through it. `rot13::RotDecoder` is a read only
+// Caesar cipher, but it rotates forward as enciphering does, so
+// `RotDecoder::new(r, n)` reads like `Decoder::new(r, Caesar::new(-n))`;
+// with the shift of 13 the sign makes no difference.
+//
+// Only the ASCII letters are touched, keeping their case; every other byte
+// passes through unchanged, and does not count as a letter either.
+
+use std::io::{self, Read, Write};
+
+const ALPHABET: u8 = 26;
+
+/// How much an `Encoder` enciphers at a time.
+const CHUNK: usize = 512;
+
+/// A way of substituting letters. Letters are given as their place in the
+/// alphabet (`0` for `a` or `A`), along with how many letters came before
+/// them in the stream, for ciphers whose substitution changes as it goes.
+pub trait Cipher {
+    fn encipher(&self, letter: u8, index: usize) -> u8;
+
+    /// Undo `encipher`.
+    fn decipher(&self, letter: u8, index: usize) -> u8;
+}
+
+/// So that the cipher can be picked at run time.
+impl<C: Cipher + ?Sized> Cipher for Box<C> {
+    fn encipher(&self, letter: u8, index: usize) -> u8 {
+        (**self).encipher(letter, index)
+    }
+
+    fn decipher(&self, letter: u8, index: usize) -> u8 {
+        (**self).decipher(letter, index)
+    }
+}
+
+/// Shift every letter the same number of places along the alphabet.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Caesar {
+    shift: u8,
+}
+
+impl Caesar {
+    /// Any shift works, negative ones shift backwards: `Caesar::new(-1)` is
+    /// the same as `Caesar::new(25)`.
+    pub fn new(shift: i64) -> Self {
+        Caesar { shift: shift.rem_euclid(ALPHABET.into()) as u8 }
+    }
+
+    /// The shift, between 0 and 25.
+    pub fn shift(&self) -> u8 {
+        self.shift
+    }
+}
+
+impl Cipher for Caesar {
+    fn encipher(&self, letter: u8, _: usize) -> u8 {
+        (letter + self.shift) % ALPHABET
+    }
+
+    fn decipher(&self, letter: u8, _: usize) -> u8 {
+        (letter + ALPHABET - self.shift) % ALPHABET
+    }
+}
+
+/// Shift each letter by the matching letter of a keyword, repeated as
+/// often as needed: with the keyword `lemon` the first letter is shifted
+/// by 11 places, the second by 4, and so on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Vigenere {
+    shifts: Vec<u8>,
+}
+
+impl Vigenere {
+    /// `None` unless the keyword is made of ASCII letters only, and at
+    /// least one.
+    pub fn new(keyword: &str) -> Option<Self> {
+        if keyword.is_empty() || !keyword.bytes().all(|b| b.is_ascii_alphabetic()) {
+            return None;
+        }
+        let shifts = keyword.bytes().map(|b| b.to_ascii_lowercase() - b'a').collect();
+        Some(Vigenere { shifts })
+    }
+
+    fn shift(&self, index: usize) -> u8 {
+        self.shifts[index % self.shifts.len()]
+    }
+}
+
+impl Cipher for Vigenere {
+    fn encipher(&self, letter: u8, index: usize) -> u8 {
+        (letter + self.shift(index)) % ALPHABET
+    }
+
+    fn decipher(&self, letter: u8, index: usize) -> u8 {
+        (letter + ALPHABET - self.shift(index)) % ALPHABET
+    }
+}
+
+/// Mirror the alphabet, `a` becomes `z`, `b` becomes `y` and so on. This
+/// is its own inverse.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Atbash;
+
+impl Cipher for Atbash {
+    fn encipher(&self, letter: u8, _: usize) -> u8 {
+        ALPHABET - 1 - letter
+    }
+
+    fn decipher(&self, letter: u8, index: usize) -> u8 {
+        self.encipher(letter, index)
+    }
+}
+
+/// Run every letter of `bytes` through `f`, given the number of letters
+/// before it, which is counted on in `letters`.
+fn substitute(bytes: &mut [u8], letters: &mut usize, f: impl Fn(u8, usize) -> u8) {
+    for b in bytes {
+        if b.is_ascii_alphabetic() {
+            let base = if b.is_ascii_uppercase() { b'A' } else { b'a' };
+            *b = f(*b - base, *letters) + base;
+            *letters += 1;
+        }
+    }
+}
+
+/// Deciphers what is read from the reader it wraps.
+pub struct Decoder<R, C> {
+    input: R,
+    cipher: C,
+    /// How many letters went through so far.
+    letters: usize,
+}
+
+impl<R: Read, C: Cipher> Decoder<R, C> {
+    pub fn new(input: R, cipher: C) -> Self {
+        Decoder { input, cipher, letters: 0 }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.input
+    }
+}
+
+impl<R: Read, C: Cipher> Read for Decoder<R, C> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = self.input.read(buf)?;
+        let cipher = &self.cipher;
+        substitute(&mut buf[..size], &mut self.letters, |letter, index| {
+            cipher.decipher(letter, index)
+        });
+        Ok(size)
+    }
+}
+
+/// Enciphers what is written to it before passing it on.
+pub struct Encoder<W, C> {
+    output: W,
+    cipher: C,
+    /// How many letters were passed on so far.
+    letters: usize,
+}
+
+impl<W: Write, C: Cipher> Encoder<W, C> {
+    pub fn new(output: W, cipher: C) -> Self {
+        Encoder { output, cipher, letters: 0 }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.output
+    }
+}
+
+impl<W: Write, C: Cipher> Write for Encoder<W, C> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // the inner writer may take only part of it, so only what it took
+        // counts towards the letters seen
+        let len = buf.len().min(CHUNK);
+        let mut chunk = [0u8; CHUNK];
+        chunk[..len].copy_from_slice(&buf[..len]);
+        let mut letters = self.letters;
+        let cipher = &self.cipher;
+        substitute(&mut chunk[..len], &mut letters, |letter, index| cipher.encipher(letter, index));
+        let size = self.output.write(&chunk[..len])?;
+        self.letters += buf[..size].iter().filter(|b| b.is_ascii_alphabetic()).count();
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rot13::RotDecoder;
+    use crate::transform::conformance::{self, Narrow, Trickle};
+
+    fn encode(text: &str, cipher: impl Cipher) -> String {
+        let mut encoder = Encoder::new(Vec::new(), cipher);
+        encoder.write_all(text.as_bytes()).unwrap();
+        String::from_utf8(encoder.into_inner()).unwrap()
+    }
+
+    fn decode(text: &str, cipher: impl Cipher) -> String {
+        let mut result = String::new();
+        Decoder::new(text.as_bytes(), cipher).read_to_string(&mut result).unwrap();
+        result
+    }
+
+    #[test]
+    fn caesar() {
+        assert_eq!(encode("Hello, World!", Caesar::new(3)), "Khoor, Zruog!");
+        assert_eq!(decode("Khoor, Zruog!", Caesar::new(3)), "Hello, World!");
+        let joke = "Gb trg gb gur bgure fvqr!";
+        assert_eq!(decode(joke, Caesar::new(13)), "To get to the other side!");
+        assert_eq!(Caesar::new(-1), Caesar::new(25));
+        assert_eq!(Caesar::new(26 * 1000 + 4).shift(), 4);
+        assert_eq!(encode("xyz", Caesar::new(i64::MIN)), encode("xyz", Caesar::new(i64::MIN % 26)));
+    }
+
+    #[test]
+    fn caesar_and_rot() {
+        let text = "The quick brown fox jumps over the lazy dog, 1234 times.";
+        for shift in [1, 3, 13, 25, 40] {
+            let mut rotated = String::new();
+            RotDecoder::new(text.as_bytes(), shift).read_to_string(&mut rotated).unwrap();
+            assert_eq!(rotated, decode(text, Caesar::new(-i64::from(shift))), "shift {shift}");
+            assert_eq!(rotated, encode(text, Caesar::new(shift.into())), "shift {shift}");
+        }
+    }
+
+    #[test]
+    fn vigenere() {
+        let lemon = || Vigenere::new("LEMON").unwrap();
+        assert_eq!(encode("ATTACKATDAWN", lemon()), "LXFOPVEFRNHR");
+        // only letters move the keyword along
+        assert_eq!(encode("attack at dawn!", lemon()), "lxfopv ef rnhr!");
+        assert_eq!(decode("lxfopv ef rnhr!", lemon()), "attack at dawn!");
+        assert_eq!(Vigenere::new(""), None);
+        assert_eq!(Vigenere::new("two words"), None);
+    }
+
+    #[test]
+    fn atbash() {
+        assert_eq!(encode("abc XYZ", Atbash), "zyx CBA");
+        assert_eq!(decode("zyx CBA", Atbash), "abc XYZ");
+    }
+
+    #[test]
+    fn state_carries_across_reads_and_writes() {
+        let plain = "The quick brown fox jumps over the lazy dog, 1234 times.";
+        let secret = encode(plain, Vigenere::new("key").unwrap());
+        for chunk in [1, 2, 7, 100] {
+            let trickle = Trickle::new(secret.as_bytes(), chunk);
+            let mut result = String::new();
+            let mut decoder = Decoder::new(trickle, Vigenere::new("key").unwrap());
+            decoder.read_to_string(&mut result).unwrap();
+            assert_eq!(result, plain, "reading {chunk} at a time");
+
+            let narrow = Narrow::new(chunk);
+            let mut encoder = Encoder::new(narrow, Vigenere::new("key").unwrap());
+            encoder.write_all(plain.as_bytes()).unwrap();
+            encoder.flush().unwrap();
+            let written = encoder.into_inner().written;
+            assert_eq!(written, secret.as_bytes(), "writing {chunk} at a time");
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        let text: Vec<u8> = (0..=255u8).cycle().take(1000).collect();
+        let ciphers: [Box<dyn Fn() -> Box<dyn Cipher>>; 4] = [
+            Box::new(|| Box::new(Caesar::new(7))),
+            Box::new(|| Box::new(Caesar::new(-30))),
+            Box::new(|| Box::new(Vigenere::new("Crate").unwrap())),
+            Box::new(|| Box::new(Atbash)),
+        ];
+        for cipher in ciphers {
+            let mut encoder = Encoder::new(Vec::new(), cipher());
+            encoder.write_all(&text).unwrap();
+            let secret = encoder.into_inner();
+            assert_ne!(secret, text);
+            // nothing but letters changes
+            for (a, b) in text.iter().zip(&secret) {
+                assert!(a == b || (a.is_ascii_alphabetic() && b.is_ascii_alphabetic()));
+            }
+
+            let mut decoded = Vec::new();
+            Decoder::new(secret.as_slice(), cipher()).read_to_end(&mut decoded).unwrap();
+            assert_eq!(decoded, text);
+        }
+    }
+
+    #[test]
+    fn conforming() {
+        let ciphers: [fn() -> Box<dyn Cipher>; 3] = [
+            || Box::new(Caesar::new(7)),
+            || Box::new(Vigenere::new("Crate").unwrap()),
+            || Box::new(Atbash),
+        ];
+        for cipher in ciphers {
+            // against the whole input deciphered or enciphered at once
+            let decoded = |input: &[u8]| {
+                let mut decoded = Vec::new();
+                Decoder::new(input, cipher()).read_to_end(&mut decoded).unwrap();
+                decoded
+            };
+            conformance::reads(decoded, |trickle| Decoder::new(trickle, cipher()));
+            let encoded = |input: &[u8]| {
+                let mut encoder = Encoder::new(Vec::new(), cipher());
+                encoder.write_all(input).unwrap();
+                encoder.into_inner()
+            };
+            let into_inner = |encoder: Encoder<_, _>| Ok(encoder.into_inner());
+            conformance::writes(encoded, |narrow| Encoder::new(narrow, cipher()), into_inner);
+        }
+    }
+}
@@ -1,66 +1,1084 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+
+pub mod bytecode;
+pub mod derivative;
+pub mod functions;
+pub mod json;
+pub mod number;
+pub mod optimizer;
+pub mod parser;
+pub mod printer;
+pub mod trace;
+#[cfg(test)]
+mod random;
+
+use functions::{Arity, Functions};
+use number::{Number, Rational};
+use trace::Step;
+
 /// An operation to perform on two subexpressions.
-#[derive(Debug)]
-enum Operation {
+///
+/// Comparisons and the logical operators produce `1` for true and `0` for
+/// false, and treat any nonzero operand as true. Comparisons work across
+/// kinds of number, so `1 / 2 == 0.5`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operation {
     Add,
     Sub,
     Mul,
     Div,
+    /// Remainder of a division, with the sign of the left operand.
+    Rem,
+    /// Exponentiation. A negative integer exponent gives the exact
+    /// reciprocal, so `2 ** -1` is `1/2`, and a fractional one gives a
+    /// float.
+    Pow,
+    /// The bitwise operators and shifts only work on integers.
+    BitAnd,
+    BitOr,
+    BitXor,
+    /// Shift left, by `0..64` bits.
+    Shl,
+    /// Arithmetic shift right, by `0..64` bits.
+    Shr,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+    /// Logical and. The right operand is only evaluated if the left one
+    /// is true.
+    And,
+    /// Logical or. The right operand is only evaluated if the left one is
+    /// false.
+    Or,
 }
 
-/// An expression, in tree form.
+/// An expression, in tree form. Trees may be far deeper than the stack:
+/// evaluating, cloning, comparing and dropping them does not recurse,
+/// although formatting with `{:?}` does.
 #[derive(Debug)]
-enum Expression {
+pub enum Expression {
     /// An operation on two subexpressions.
     Op { op: Operation, left: Box<Expression>, right: Box<Expression> },
 
     /// A literal value
-    Value(i64),
+    Value(Number),
+
+    /// A named value, looked up when the expression is evaluated.
+    Variable(String),
+
+    /// Evaluate `value`, then evaluate `body` with `name` bound to the
+    /// result. The binding shadows any outer variable of the same name.
+    Let { name: String, value: Box<Expression>, body: Box<Expression> },
+
+    /// Evaluate `then` if `cond` is nonzero, otherwise `otherwise`. Only
+    /// the chosen branch is evaluated.
+    If { cond: Box<Expression>, then: Box<Expression>, otherwise: Box<Expression> },
+
+    /// Call the function `name` with the values of `args`: one that the
+    /// expression defines itself if there is one of that name in scope,
+    /// otherwise one registered with the evaluator.
+    Call { name: String, args: Vec<Expression> },
+
+    /// Define the function `name` for use in `rest`. A call evaluates
+    /// `body` with `params` bound to the arguments. Besides those, the body
+    /// sees what is in scope where the function is defined, including the
+    /// function itself, so it may recurse.
+    Function { name: String, params: Vec<String>, body: Box<Expression>, rest: Box<Expression> },
 }
 
-fn eval(e: Expression) -> Result<i64, String> {
-    match e {
-        Expression::Op { op, left, right } =>
-        {
-            let Result::Ok(left_result) = eval(*left) else {
-                return Result::Err("Left tree failed".to_string());
-            };
+impl Expression {
+    /// Move the children of this node out into `into`, leaving cheap
+    /// leaves in their place.
+    fn take_children(&mut self, into: &mut Vec<Expression>) {
+        let mut take = |child: &mut Box<Expression>| {
+            into.push(std::mem::replace(&mut **child, Expression::Value(Number::Int(0))));
+        };
+        match self {
+            Expression::Op { left, right, .. } => {
+                take(left);
+                take(right);
+            }
+            Expression::Let { value, body, .. } => {
+                take(value);
+                take(body);
+            }
+            Expression::If { cond, then, otherwise } => {
+                take(cond);
+                take(then);
+                take(otherwise);
+            }
+            Expression::Call { args, .. } => into.append(args),
+            Expression::Function { body, rest, .. } => {
+                take(body);
+                take(rest);
+            }
+            Expression::Value(_) | Expression::Variable(_) => {}
+        }
+    }
 
-            let Result::Ok(right_result) = eval(*right) else {
-                return Result::Err("Right tree failed".to_string());
-            };
-            
-            match op {
-                Operation::Add => {Result::Ok(left_result + right_result)},
-                Operation::Div => {
-                    if right_result == 0 {
-                        return Result::Err("division by zero".to_string())
+    /// Push the children of this node onto `onto`, in order.
+    fn push_children<'e>(&'e self, onto: &mut Vec<&'e Expression>) {
+        match self {
+            Expression::Op { left, right, .. } => onto.extend([&**left, right]),
+            Expression::Let { value, body, .. } => onto.extend([&**value, body]),
+            Expression::If { cond, then, otherwise } => onto.extend([&**cond, then, otherwise]),
+            Expression::Call { args, .. } => onto.extend(args),
+            Expression::Function { body, rest, .. } => onto.extend([&**body, rest]),
+            Expression::Value(_) | Expression::Variable(_) => {}
+        }
+    }
+
+    /// A copy of this node with the last of `built` as its children, which
+    /// are taken off it.
+    fn with_children(&self, built: &mut Vec<Expression>) -> Expression {
+        let count = match self {
+            Expression::Op { .. } | Expression::Let { .. } | Expression::Function { .. } => 2,
+            Expression::If { .. } => 3,
+            Expression::Call { args, .. } => args.len(),
+            Expression::Value(_) | Expression::Variable(_) => 0,
+        };
+        let mut children = built.split_off(built.len() - count).into_iter();
+        if let Expression::Call { name, .. } = self {
+            return Expression::Call { name: name.clone(), args: children.collect() };
+        }
+        let mut next = || Box::new(children.next().unwrap());
+        match self {
+            Expression::Op { op, .. } => Expression::Op { op: *op, left: next(), right: next() },
+            Expression::Value(val) => Expression::Value(*val),
+            Expression::Variable(name) => Expression::Variable(name.clone()),
+            Expression::Let { name, .. } => {
+                Expression::Let { name: name.clone(), value: next(), body: next() }
+            }
+            Expression::If { .. } => Expression::If { cond: next(), then: next(), otherwise: next() },
+            Expression::Function { name, params, .. } => Expression::Function {
+                name: name.clone(),
+                params: params.clone(),
+                body: next(),
+                rest: next(),
+            },
+            Expression::Call { .. } => unreachable!(),
+        }
+    }
+
+    /// Whether two nodes are the same, leaving their children aside.
+    fn same_node(&self, other: &Expression) -> bool {
+        match (self, other) {
+            (Expression::Op { op: a, .. }, Expression::Op { op: b, .. }) => a == b,
+            (Expression::Value(a), Expression::Value(b)) => a == b,
+            (Expression::Variable(a), Expression::Variable(b)) => a == b,
+            (Expression::Let { name: a, .. }, Expression::Let { name: b, .. }) => a == b,
+            (Expression::If { .. }, Expression::If { .. }) => true,
+            (Expression::Call { name: a, args: x }, Expression::Call { name: b, args: y }) => {
+                a == b && x.len() == y.len()
+            }
+            (
+                Expression::Function { name: a, params: x, .. },
+                Expression::Function { name: b, params: y, .. },
+            ) => a == b && x == y,
+            _ => false,
+        }
+    }
+}
+
+// the derived `Clone` and `PartialEq` would recurse once per level of the
+// tree like the drop glue below, so these keep a stack of the nodes still
+// to do instead
+
+impl Clone for Expression {
+    fn clone(&self) -> Self {
+        enum Task<'e> {
+            /// Copy the children of the node, then the node itself.
+            Visit(&'e Expression),
+            /// The copies of the children are the last ones built.
+            Build(&'e Expression),
+        }
+
+        let mut tasks = vec![Task::Visit(self)];
+        let mut built = Vec::new();
+        let mut children = Vec::new();
+        while let Some(task) = tasks.pop() {
+            match task {
+                Task::Visit(node) => {
+                    tasks.push(Task::Build(node));
+                    node.push_children(&mut children);
+                    // in reverse, so that the first child is built first
+                    tasks.extend(children.drain(..).rev().map(Task::Visit));
+                }
+                Task::Build(node) => {
+                    let copy = node.with_children(&mut built);
+                    built.push(copy);
+                }
+            }
+        }
+        built.pop().expect("cloning left no copy")
+    }
+}
+
+impl PartialEq for Expression {
+    fn eq(&self, other: &Self) -> bool {
+        let mut pairs = vec![(self, other)];
+        let (mut ours, mut theirs) = (Vec::new(), Vec::new());
+        while let Some((a, b)) = pairs.pop() {
+            if !a.same_node(b) {
+                return false;
+            }
+            // the same kind of node, so as many children on both sides
+            a.push_children(&mut ours);
+            b.push_children(&mut theirs);
+            pairs.extend(ours.drain(..).zip(theirs.drain(..)));
+        }
+        true
+    }
+}
+
+// the drop glue the compiler generates for a `Box<Expression>` drops the
+// children first, recursing once per level of the tree, which overflows
+// the stack on very deep trees. Instead we pull the whole tree apart onto
+// a heap allocated stack, so every node is dropped without any children
+// left in it.
+impl Drop for Expression {
+    fn drop(&mut self) {
+        let mut pending = Vec::new();
+        self.take_children(&mut pending);
+        while let Some(mut node) = pending.pop() {
+            node.take_children(&mut pending);
+        }
+    }
+}
+
+/// Which child of a node evaluation descended into.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Branch {
+    Left,
+    Right,
+    /// The value being bound by a `Let`.
+    Value,
+    /// The body of a `Let`.
+    Body,
+    /// The condition of an `If`.
+    Cond,
+    /// The branch of an `If` taken when the condition holds.
+    Then,
+    /// The branch of an `If` taken when the condition does not hold.
+    Else,
+    /// An argument of a `Call`, counting from zero.
+    Arg(usize),
+    /// The body of the function a `Call` called. This leaves the tree, and
+    /// the rest of the path is relative to that body.
+    Call,
+    /// The part of a `Function` that the function is defined for.
+    Rest,
+}
+
+/// The branches taken from the root of a tree to reach a node. An empty
+/// path is the root itself.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Path(pub Vec<Branch>);
+
+impl fmt::Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0.is_empty() {
+            return write!(f, "root");
+        }
+        for (i, branch) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ".")?;
+            }
+            match branch {
+                Branch::Left => write!(f, "left")?,
+                Branch::Right => write!(f, "right")?,
+                Branch::Value => write!(f, "value")?,
+                Branch::Body => write!(f, "body")?,
+                Branch::Cond => write!(f, "cond")?,
+                Branch::Then => write!(f, "then")?,
+                Branch::Else => write!(f, "else")?,
+                Branch::Arg(n) => write!(f, "arg{n}")?,
+                Branch::Call => write!(f, "call")?,
+                Branch::Rest => write!(f, "rest")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Why evaluating an expression failed. Every variant records the path to
+/// the node that failed, so an error deep inside the tree is reported as
+/// is rather than being blamed on its parent.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EvalError {
+    /// The right hand side of a division was zero.
+    DivisionByZero { path: Path },
+
+    /// The result of `op` does not fit. Integer overflow is only reported
+    /// in `ArithmeticMode::Checked`, a rational whose numerator or
+    /// denominator does not fit in an `i64` is reported in every mode.
+    Overflow { op: Operation, path: Path },
+
+    /// A variable that is neither bound by an enclosing `Let` nor by the
+    /// environment.
+    UnknownVariable { name: String, path: Path },
+
+    /// A bitwise operator or shift applied to a float or rational.
+    NotAnInteger { op: Operation, path: Path },
+
+    /// A shift by an amount outside of `0..64`.
+    InvalidShift { amount: i64, path: Path },
+
+    /// A call to a function that is neither defined by the expression nor
+    /// registered with the evaluator.
+    UnknownFunction { name: String, path: Path },
+
+    /// A call with a number of arguments the function does not take.
+    WrongArity { name: String, expected: Arity, found: usize, path: Path },
+
+    /// A registered function reported a failure.
+    FunctionFailed { name: String, message: String, path: Path },
+
+    /// Calls nested deeper than the evaluator allows, usually because of
+    /// runaway recursion.
+    CallTooDeep { path: Path },
+}
+
+impl EvalError {
+    /// The path to the node that failed.
+    pub fn path(&self) -> &Path {
+        match self {
+            EvalError::DivisionByZero { path } => path,
+            EvalError::Overflow { path, .. } => path,
+            EvalError::UnknownVariable { path, .. } => path,
+            EvalError::NotAnInteger { path, .. } => path,
+            EvalError::InvalidShift { path, .. } => path,
+            EvalError::UnknownFunction { path, .. } => path,
+            EvalError::WrongArity { path, .. } => path,
+            EvalError::FunctionFailed { path, .. } => path,
+            EvalError::CallTooDeep { path } => path,
+        }
+    }
+
+    fn path_mut(&mut self) -> &mut Path {
+        match self {
+            EvalError::DivisionByZero { path } => path,
+            EvalError::Overflow { path, .. } => path,
+            EvalError::UnknownVariable { path, .. } => path,
+            EvalError::NotAnInteger { path, .. } => path,
+            EvalError::InvalidShift { path, .. } => path,
+            EvalError::UnknownFunction { path, .. } => path,
+            EvalError::WrongArity { path, .. } => path,
+            EvalError::FunctionFailed { path, .. } => path,
+            EvalError::CallTooDeep { path } => path,
+        }
+    }
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EvalError::DivisionByZero { path } => write!(f, "division by zero at {path}"),
+            EvalError::Overflow { op, path } => write!(f, "{op} overflowed at {path}"),
+            EvalError::UnknownVariable { name, path } => {
+                write!(f, "unknown variable {name:?} at {path}")
+            }
+            EvalError::NotAnInteger { op, path } => write!(f, "{op} needs integers at {path}"),
+            EvalError::InvalidShift { amount, path } => {
+                write!(f, "cannot shift by {amount} bits at {path}")
+            }
+            EvalError::UnknownFunction { name, path } => {
+                write!(f, "unknown function {name:?} at {path}")
+            }
+            EvalError::WrongArity { name, expected, found, path } => {
+                write!(f, "{name} takes {expected} but was given {found} at {path}")
+            }
+            EvalError::FunctionFailed { name, message, path } => {
+                write!(f, "{name} failed: {message} at {path}")
+            }
+            EvalError::CallTooDeep { path } => write!(f, "calls nested too deeply at {path}"),
+        }
+    }
+}
+
+impl std::error::Error for EvalError {}
+
+/// Variable bindings for evaluation, organised as a stack of scopes.
+/// Lookups search from the innermost scope outwards, so a binding in an
+/// inner scope shadows one of the same name further out.
+#[derive(Debug, Clone)]
+pub struct Environment {
+    scopes: Vec<HashMap<String, Number>>,
+}
+
+impl Default for Environment {
+    fn default() -> Self {
+        // there is always at least the outermost scope to bind into
+        Environment { scopes: vec![HashMap::new()] }
+    }
+}
+
+impl Environment {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Bind `name` in the innermost scope, builder style.
+    pub fn with(mut self, name: impl Into<String>, value: impl Into<Number>) -> Self {
+        self.bind(name, value);
+        self
+    }
+
+    /// Bind `name` in the innermost scope, replacing any binding it
+    /// already has there.
+    pub fn bind(&mut self, name: impl Into<String>, value: impl Into<Number>) {
+        self.scopes.last_mut().unwrap().insert(name.into(), value.into());
+    }
+
+    /// Look `name` up, innermost scope first.
+    pub fn get(&self, name: &str) -> Option<Number> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name).copied())
+    }
+
+    /// Start a new, empty scope.
+    pub fn push_scope(&mut self) {
+        self.scopes.push(HashMap::new());
+    }
+
+    /// Throw away the innermost scope and its bindings. The outermost
+    /// scope is never removed.
+    pub fn pop_scope(&mut self) {
+        if self.scopes.len() > 1 {
+            self.scopes.pop();
+        }
+    }
+}
+
+/// How integer arithmetic whose result does not fit in an `i64` is
+/// handled. Floats and rationals are not affected.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ArithmeticMode {
+    /// Overflow is reported as `EvalError::Overflow`.
+    #[default]
+    Checked,
+    /// Results wrap around at the boundaries of an `i64`.
+    Wrapping,
+    /// Results are clamped to `i64::MIN` or `i64::MAX`.
+    Saturating,
+}
+
+/// How deeply calls to functions an expression defines may nest, unless
+/// the evaluator is told otherwise.
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 1_000;
+
+/// Evaluates expressions. The default evaluator uses checked arithmetic
+/// and knows the built-in functions, the builder-style methods change
+/// that.
+#[derive(Debug)]
+pub struct Evaluator {
+    mode: ArithmeticMode,
+    functions: Functions,
+    max_call_depth: usize,
+}
+
+impl Default for Evaluator {
+    fn default() -> Self {
+        Evaluator {
+            mode: ArithmeticMode::default(),
+            functions: Functions::builtins(),
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+        }
+    }
+}
+
+/// A piece of work for the evaluator. Instead of recursing once per level
+/// of the tree (which overflows the stack on deep trees), evaluation keeps
+/// its own stack of these, with the values computed so far on a second
+/// stack next to it.
+enum Task<'e> {
+    /// Evaluate the child of the current node reached through `Branch`,
+    /// leaving its value on the value stack.
+    Eval(Branch, &'e Expression),
+    /// A child is done, step back up to its parent.
+    Leave,
+    /// Both operands are on the value stack, combine them.
+    Apply(Operation),
+    /// The left operand of a logical operator is on the value stack, see
+    /// whether the right one is needed at all.
+    ShortCircuit(Operation, &'e Expression),
+    /// The condition of an `If` is on the value stack, pick a branch.
+    Choose { then: &'e Expression, otherwise: &'e Expression },
+    /// The value of a `Let` is on the value stack, bind it for the body.
+    Bind { name: &'e str, body: &'e Expression },
+    /// The body of a `Let` or the rest of a `Function` is done, drop its
+    /// binding again.
+    Unbind,
+    /// The arguments of a call are on the value stack, make the call.
+    Call { name: &'e str, args: usize },
+    /// The body of a called function is done, drop its frame.
+    Return,
+    /// Only when tracing: the value on top of the value stack is what the
+    /// given reduced form of the current node came to.
+    Record(String),
+}
+
+/// What the expression itself binds a name to.
+#[derive(Clone, Copy)]
+enum Binding<'e> {
+    Value(Number),
+    /// A function, along with where to look up the names its body uses
+    /// besides its parameters: the frame it was defined in, and how many
+    /// of the locals were visible there (itself included).
+    Function { params: &'e [String], body: &'e Expression, frame: usize, visible: usize },
+}
+
+/// The locals of a function call, which start at `base`. `outer` is where
+/// the function was defined, as a frame and how many locals it saw; the
+/// outermost frame has nothing around it.
+#[derive(Clone, Copy)]
+struct Frame {
+    base: usize,
+    outer: Option<(usize, usize)>,
+}
+
+/// Everything evaluation needs besides the tree itself.
+struct State<'e, 'env> {
+    env: &'env Environment,
+    /// Bindings made by the `Let`s and `Function`s we are currently inside
+    /// of and the parameters of the calls we are in, innermost last. These
+    /// live here rather than in `env` so that evaluation never has to
+    /// modify the caller's environment.
+    locals: Vec<(&'e str, Binding<'e>)>,
+    /// One frame for every call in progress, plus the outermost one.
+    frames: Vec<Frame>,
+    path: Vec<Branch>,
+    tasks: Vec<Task<'e>>,
+    values: Vec<Number>,
+    /// The steps so far, if the evaluation is being traced.
+    trace: Option<Vec<Step>>,
+}
+
+impl<'e> State<'e, '_> {
+    /// Find the innermost binding of `name` that `wanted` accepts. From a
+    /// function body the search goes on where the function was defined
+    /// rather than where it was called from.
+    fn lookup<T>(&self, name: &str, wanted: impl Fn(&Binding<'e>) -> Option<T>) -> Option<T> {
+        let mut frame = self.frames.len() - 1;
+        let mut end = self.locals.len();
+        loop {
+            let Frame { base, outer } = self.frames[frame];
+            let found = self.locals[base..end]
+                .iter()
+                .rev()
+                .filter(|(local, _)| *local == name)
+                .find_map(|(_, binding)| wanted(binding));
+            if found.is_some() {
+                return found;
+            }
+            (frame, end) = outer?;
+        }
+    }
+
+    /// Variables and functions have names of their own, `max` can be both.
+    fn variable(&self, name: &str) -> Option<Number> {
+        let value = |binding: &Binding| match binding {
+            Binding::Value(value) => Some(*value),
+            Binding::Function { .. } => None,
+        };
+        self.lookup(name, value).or_else(|| self.env.get(name))
+    }
+
+    /// Note down that the current node, reduced to `reduced`, came to
+    /// `value`. The text is only made when tracing.
+    fn record(&mut self, reduced: impl FnOnce() -> String, value: Number) {
+        if let Some(trace) = &mut self.trace {
+            trace.push(Step { path: Path(self.path.clone()), reduced: reduced(), value });
+        }
+    }
+
+    fn pop_value(&mut self) -> Number {
+        self.values.pop().expect("evaluation left no value on the stack")
+    }
+}
+
+impl Evaluator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set how overflowing arithmetic is handled.
+    pub fn mode(mut self, mode: ArithmeticMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Make a Rust function callable from expressions, see
+    /// `Functions::register`.
+    pub fn function(
+        mut self,
+        name: impl Into<String>,
+        arity: impl Into<Arity>,
+        f: impl Fn(&[Number]) -> Result<Number, String> + 'static,
+    ) -> Self {
+        self.functions.register(name, arity, f);
+        self
+    }
+
+    /// Replace all registered functions, for example with
+    /// `Functions::new()` to do without the built-in ones.
+    pub fn functions(mut self, functions: Functions) -> Self {
+        self.functions = functions;
+        self
+    }
+
+    /// Set how deeply calls to functions that expressions define may nest.
+    pub fn max_call_depth(mut self, depth: usize) -> Self {
+        self.max_call_depth = depth;
+        self
+    }
+
+    /// Evaluate an expression that has no free variables.
+    pub fn eval(&self, e: &Expression) -> Result<Number, EvalError> {
+        self.eval_in(e, &Environment::new())
+    }
+
+    /// Evaluate an expression, looking variables up in `env`. The same
+    /// expression can be evaluated against as many environments as needed.
+    ///
+    /// This does not recurse, so trees of any depth can be evaluated.
+    pub fn eval_in(&self, e: &Expression, env: &Environment) -> Result<Number, EvalError> {
+        self.evaluate(e, env, None).0
+    }
+
+    /// Evaluate `e`, adding the steps taken to `trace` if there is one.
+    fn evaluate(
+        &self,
+        e: &Expression,
+        env: &Environment,
+        trace: Option<Vec<Step>>,
+    ) -> (Result<Number, EvalError>, Option<Vec<Step>>) {
+        let mut state = State {
+            env,
+            locals: Vec::new(),
+            frames: vec![Frame { base: 0, outer: None }],
+            path: Vec::new(),
+            tasks: Vec::new(),
+            values: Vec::new(),
+            trace,
+        };
+        let result = self.eval_tree(e, &mut state);
+        (result, state.trace)
+    }
+
+    fn eval_tree<'e>(&self, e: &'e Expression, state: &mut State<'e, '_>) -> Result<Number, EvalError> {
+        self.eval_node(e, state)?;
+
+        while let Some(task) = state.tasks.pop() {
+            // `?` hands a failure from further down back up untouched
+            self.run_task(task, state)?;
+        }
+
+        Ok(state.pop_value())
+    }
+
+    /// Start evaluating `e`, which sits at `state.path` in the tree. Leaves
+    /// are dealt with straight away, other nodes push the tasks for their
+    /// children. Tasks run in the reverse order they were pushed in.
+    fn eval_node<'e>(&self, e: &'e Expression, state: &mut State<'e, '_>) -> Result<(), EvalError> {
+        match e {
+            Expression::Op { op, left, right } => {
+                match op {
+                    Operation::And | Operation::Or => {
+                        state.tasks.push(Task::ShortCircuit(*op, right));
                     }
-                    Result::Ok(left_result / right_result)},
-                Operation::Mul => {Result::Ok(left_result * right_result)},
-                Operation::Sub => {Result::Ok(left_result - right_result)},
+                    _ => {
+                        state.tasks.push(Task::Apply(*op));
+                        state.tasks.push(Task::Eval(Branch::Right, right));
+                    }
+                }
+                state.tasks.push(Task::Eval(Branch::Left, left));
+            }
+            Expression::Value(val) => state.values.push(*val),
+            Expression::Variable(name) => {
+                let value = state.variable(name).ok_or_else(|| EvalError::UnknownVariable {
+                    name: name.clone(),
+                    path: Path(state.path.clone()),
+                })?;
+                state.record(|| name.clone(), value);
+                state.values.push(value);
+            }
+            Expression::Let { name, value, body } => {
+                state.tasks.push(Task::Bind { name, body });
+                state.tasks.push(Task::Eval(Branch::Value, value));
+            }
+            Expression::If { cond, then, otherwise } => {
+                state.tasks.push(Task::Choose { then, otherwise });
+                state.tasks.push(Task::Eval(Branch::Cond, cond));
+            }
+            Expression::Call { name, args } => {
+                state.tasks.push(Task::Call { name, args: args.len() });
+                for (i, arg) in args.iter().enumerate().rev() {
+                    state.tasks.push(Task::Eval(Branch::Arg(i), arg));
+                }
             }
+            Expression::Function { name, params, body, rest } => {
+                let frame = state.frames.len() - 1;
+                let visible = state.locals.len() + 1;
+                state.locals.push((name, Binding::Function { params, body, frame, visible }));
+                state.tasks.push(Task::Unbind);
+                state.tasks.push(Task::Eval(Branch::Rest, rest));
+            }
+        }
+        Ok(())
+    }
 
-        },
-        Expression::Value(val) => {
-            Result::Ok(val)
+    fn run_task<'e>(&self, task: Task<'e>, state: &mut State<'e, '_>) -> Result<(), EvalError> {
+        match task {
+            Task::Eval(branch, e) => {
+                // the path is only copied into an error when something
+                // actually fails
+                state.path.push(branch);
+                state.tasks.push(Task::Leave);
+                self.eval_node(e, state)?;
+            }
+            Task::Leave => {
+                state.path.pop();
+            }
+            Task::Apply(op) => {
+                let right = state.pop_value();
+                let left = state.pop_value();
+                let value = self.mode.apply(op, left, right, &state.path)?;
+                state.record(|| trace::operation(op, left, right), value);
+                state.values.push(value);
+            }
+            Task::ShortCircuit(op, right) => {
+                let left = state.pop_value();
+                // the logical operators may already know their answer
+                let known = match (op, !left.is_zero()) {
+                    (Operation::And, false) => Some(Number::Int(0)),
+                    (Operation::Or, true) => Some(Number::Int(1)),
+                    _ => None,
+                };
+                match known {
+                    Some(value) => {
+                        state.record(|| trace::short_circuit(op, left), value);
+                        state.values.push(value);
+                    }
+                    None => {
+                        state.values.push(left);
+                        state.tasks.push(Task::Apply(op));
+                        state.tasks.push(Task::Eval(Branch::Right, right));
+                    }
+                }
+            }
+            Task::Choose { then, otherwise } => {
+                if !state.pop_value().is_zero() {
+                    state.tasks.push(Task::Eval(Branch::Then, then));
+                } else {
+                    state.tasks.push(Task::Eval(Branch::Else, otherwise));
+                }
+            }
+            Task::Bind { name, body } => {
+                let bound = state.pop_value();
+                state.locals.push((name, Binding::Value(bound)));
+                state.tasks.push(Task::Unbind);
+                state.tasks.push(Task::Eval(Branch::Body, body));
+            }
+            Task::Unbind => {
+                state.locals.pop();
+            }
+            Task::Call { name, args } => {
+                let args = state.values.split_off(state.values.len() - args);
+                self.call(name, args, state)?;
+            }
+            Task::Return => {
+                let frame = state.frames.pop().expect("returned from the outermost frame");
+                state.locals.truncate(frame.base);
+            }
+            Task::Record(reduced) => {
+                let value = *state.values.last().expect("recorded without a value");
+                state.record(|| reduced, value);
+            }
         }
+        Ok(())
     }
+
+    /// Call the function `name` with the values of its arguments. A
+    /// function the expression defines goes ahead of a registered one.
+    fn call<'e>(
+        &self,
+        name: &'e str,
+        args: Vec<Number>,
+        state: &mut State<'e, '_>,
+    ) -> Result<(), EvalError> {
+        let path = || Path(state.path.clone());
+        let defined = state.lookup(name, |binding| match binding {
+            Binding::Function { params, body, frame, visible } => {
+                Some((*params, *body, *frame, *visible))
+            }
+            Binding::Value(_) => None,
+        });
+
+        let Some((params, body, frame, visible)) = defined else {
+            let value = self.call_native(name, &args, &state.path)?;
+            state.record(|| trace::call(name, &args), value);
+            state.values.push(value);
+            return Ok(());
+        };
+
+        if params.len() != args.len() {
+            let (expected, found) = (Arity::Exactly(params.len()), args.len());
+            return Err(EvalError::WrongArity { name: name.to_string(), expected, found, path: path() });
+        }
+        // the outermost frame is not a call
+        if state.frames.len() > self.max_call_depth {
+            return Err(EvalError::CallTooDeep { path: path() });
+        }
+        if state.trace.is_some() {
+            // once the body is done and the frame dropped
+            state.tasks.push(Task::Record(trace::call(name, &args)));
+        }
+        state.frames.push(Frame { base: state.locals.len(), outer: Some((frame, visible)) });
+        let bindings = params.iter().zip(args).map(|(param, arg)| (param.as_str(), Binding::Value(arg)));
+        state.locals.extend(bindings);
+        state.tasks.push(Task::Return);
+        state.tasks.push(Task::Eval(Branch::Call, body));
+        Ok(())
+    }
+
+    /// Call the registered function `name`.
+    fn call_native(&self, name: &str, args: &[Number], path: &[Branch]) -> Result<Number, EvalError> {
+        let path = || Path(path.to_vec());
+        let Some((arity, native)) = self.functions.get(name) else {
+            return Err(EvalError::UnknownFunction { name: name.to_string(), path: path() });
+        };
+        if !arity.accepts(args.len()) {
+            let found = args.len();
+            let expected = *arity;
+            return Err(EvalError::WrongArity { name: name.to_string(), expected, found, path: path() });
+        }
+        native(args).map_err(|message| EvalError::FunctionFailed {
+            name: name.to_string(),
+            message,
+            path: path(),
+        })
+    }
+}
+
+// the arithmetic itself only depends on the mode, so that the optimizer can
+// fold constants without an evaluator
+impl ArithmeticMode {
+    /// Apply `op` to two already evaluated operands. Operands of different
+    /// kinds are promoted as described on `Number`.
+    fn apply(
+        self,
+        op: Operation,
+        left: Number,
+        right: Number,
+        path: &[Branch],
+    ) -> Result<Number, EvalError> {
+        use Operation::*;
+        let path = || Path(path.to_vec());
+        let truth = |holds: bool| Ok(Number::Int(holds as i64));
+        let order = left.compare(right);
+
+        match op {
+            Lt => return truth(order == Some(Ordering::Less)),
+            Le => return truth(matches!(order, Some(Ordering::Less | Ordering::Equal))),
+            Gt => return truth(order == Some(Ordering::Greater)),
+            Ge => return truth(matches!(order, Some(Ordering::Greater | Ordering::Equal))),
+            Eq => return truth(order == Some(Ordering::Equal)),
+            Ne => return truth(order != Some(Ordering::Equal)),
+            // the short circuit has already been handled by the caller
+            And => return truth(!left.is_zero() && !right.is_zero()),
+            Or => return truth(!left.is_zero() || !right.is_zero()),
+            // no sensible answer for any kind of number, in any mode
+            Div | Rem if right.is_zero() => return Err(EvalError::DivisionByZero { path: path() }),
+            _ => {}
+        }
+
+        let result = match (op, left, right) {
+            (_, Number::Int(l), Number::Int(r)) => return self.apply_integers(op, l, r, path),
+            (BitAnd | BitOr | BitXor | Shl | Shr, ..) => {
+                return Err(EvalError::NotAnInteger { op, path: path() });
+            }
+            // a fractional power is hardly ever a fraction itself
+            (_, Number::Float(_), _) | (_, _, Number::Float(_)) | (Pow, _, Number::Rational(_)) => {
+                Some(Number::Float(float(op, left.to_f64(), right.to_f64())))
+            }
+            _ => left
+                .to_rational()
+                .zip(right.to_rational())
+                .and_then(|(l, r)| fraction(op, l, r))
+                .map(Number::from),
+        };
+        result.ok_or_else(|| EvalError::Overflow { op, path: path() })
+    }
+
+    /// Apply `op` to two integers.
+    fn apply_integers(
+        self,
+        op: Operation,
+        left: i64,
+        right: i64,
+        path: impl Fn() -> Path,
+    ) -> Result<Number, EvalError> {
+        let overflow = || EvalError::Overflow { op, path: path() };
+
+        match op {
+            Operation::Shl | Operation::Shr if !(0..64).contains(&right) => {
+                return Err(EvalError::InvalidShift { amount: right, path: path() });
+            }
+            // rather than truncating, a division that does not come out
+            // even gives the exact fraction
+            Operation::Div if left.checked_rem(right).is_some_and(|rem| rem != 0) => {
+                return Rational::new(left, right).map(Number::from).ok_or_else(overflow);
+            }
+            Operation::Pow if right < 0 => {
+                if left == 0 {
+                    return Err(EvalError::DivisionByZero { path: path() });
+                }
+                return Rational::from(left).checked_pow(right).map(Number::from).ok_or_else(overflow);
+            }
+            _ => {}
+        }
+
+        let result = match op {
+            Operation::Add => {
+                self.arithmetic(left, right, i64::checked_add, i64::wrapping_add, i64::saturating_add)
+            }
+            Operation::Sub => {
+                self.arithmetic(left, right, i64::checked_sub, i64::wrapping_sub, i64::saturating_sub)
+            }
+            Operation::Mul => {
+                self.arithmetic(left, right, i64::checked_mul, i64::wrapping_mul, i64::saturating_mul)
+            }
+            Operation::Div => {
+                self.arithmetic(left, right, i64::checked_div, i64::wrapping_div, i64::saturating_div)
+            }
+            // only i64::MIN % -1 can overflow, and the true answer is 0
+            Operation::Rem => self.arithmetic(left, right, i64::checked_rem, i64::wrapping_rem, |l, r| {
+                l.checked_rem(r).unwrap_or(0)
+            }),
+            Operation::Pow => self.arithmetic(left, right, checked_pow, wrapping_pow, saturating_pow),
+            Operation::Shl => self.arithmetic(left, right, checked_shl, |l, r| l << r, saturating_shl),
+            Operation::Shr => Some(left >> right),
+            Operation::BitAnd => Some(left & right),
+            Operation::BitOr => Some(left | right),
+            Operation::BitXor => Some(left ^ right),
+            _ => unreachable!("{op:?} is handled by apply"),
+        };
+
+        result.map(Number::Int).ok_or_else(overflow)
+    }
+
+    /// Pick the flavour of an arithmetic operation that matches the mode.
+    fn arithmetic(
+        self,
+        left: i64,
+        right: i64,
+        checked: fn(i64, i64) -> Option<i64>,
+        wrapping: fn(i64, i64) -> i64,
+        saturating: fn(i64, i64) -> i64,
+    ) -> Option<i64> {
+        match self {
+            ArithmeticMode::Checked => checked(left, right),
+            ArithmeticMode::Wrapping => Some(wrapping(left, right)),
+            ArithmeticMode::Saturating => Some(saturating(left, right)),
+        }
+    }
+}
+
+/// Arithmetic on floats, where every result is fine as far as overflow is
+/// concerned.
+fn float(op: Operation, left: f64, right: f64) -> f64 {
+    match op {
+        Operation::Add => left + right,
+        Operation::Sub => left - right,
+        Operation::Mul => left * right,
+        Operation::Div => left / right,
+        Operation::Rem => left % right,
+        Operation::Pow => left.powf(right),
+        _ => unreachable!("{op:?} is handled by apply"),
+    }
+}
+
+/// Exact arithmetic on fractions. Only integer exponents get this far.
+fn fraction(op: Operation, left: Rational, right: Rational) -> Option<Rational> {
+    match op {
+        Operation::Add => left.checked_add(right),
+        Operation::Sub => left.checked_sub(right),
+        Operation::Mul => left.checked_mul(right),
+        Operation::Div => left.checked_div(right),
+        Operation::Rem => left.checked_rem(right),
+        Operation::Pow => left.checked_pow(right.numer()),
+        _ => unreachable!("{op:?} is handled by apply"),
+    }
+}
+
+/// `base` to the power of `exp` (which is not negative) by repeated
+/// squaring, using `mul` for every multiplication. The base is only
+/// squared when the result still needs it, so this only fails if the
+/// answer itself is out of range.
+fn power(mut base: i64, mut exp: i64, mul: impl Fn(i64, i64) -> Option<i64>) -> Option<i64> {
+    let mut result = 1;
+    while exp > 0 {
+        if exp & 1 == 1 {
+            result = mul(result, base)?;
+        }
+        exp >>= 1;
+        if exp > 0 {
+            base = mul(base, base)?;
+        }
+    }
+    Some(result)
+}
+
+fn checked_pow(base: i64, exp: i64) -> Option<i64> {
+    power(base, exp, i64::checked_mul)
+}
+
+fn wrapping_pow(base: i64, exp: i64) -> i64 {
+    power(base, exp, |a, b| Some(a.wrapping_mul(b))).unwrap()
+}
+
+// saturating keeps the sign right, and once a value has saturated
+// multiplying it by anything but zero keeps it there
+fn saturating_pow(base: i64, exp: i64) -> i64 {
+    power(base, exp, |a, b| Some(a.saturating_mul(b))).unwrap()
+}
+
+/// A left shift overflows when shifting back does not give the original
+/// value, that is when bits (or the sign) were lost.
+fn checked_shl(value: i64, amount: i64) -> Option<i64> {
+    let shifted = value << amount;
+    (shifted >> amount == value).then_some(shifted)
+}
+
+fn saturating_shl(value: i64, amount: i64) -> i64 {
+    checked_shl(value, amount).unwrap_or(if value < 0 { i64::MIN } else { i64::MAX })
+}
+
+/// Evaluate an expression without free variables, using checked
+/// arithmetic.
+pub fn eval(e: &Expression) -> Result<Number, EvalError> {
+    Evaluator::new().eval(e)
 }
 
 #[test]
 fn test_value() {
-    assert_eq!(eval(Expression::Value(19)), Ok(19));
+    assert_eq!(eval(&Expression::Value(Number::Int(19))), Ok(Number::Int(19)));
 }
 
 #[test]
 fn test_sum() {
     assert_eq!(
-        eval(Expression::Op {
+        eval(&Expression::Op {
             op: Operation::Add,
-            left: Box::new(Expression::Value(10)),
-            right: Box::new(Expression::Value(20)),
+            left: Box::new(Expression::Value(Number::Int(10))),
+            right: Box::new(Expression::Value(Number::Int(20))),
         }),
-        Ok(30)
+        Ok(Number::Int(30))
     );
 }
 
@@ -68,36 +1086,509 @@ fn test_sum() {
 fn test_recursion() {
     let term1 = Expression::Op {
         op: Operation::Mul,
-        left: Box::new(Expression::Value(10)),
-        right: Box::new(Expression::Value(9)),
+        left: Box::new(Expression::Value(Number::Int(10))),
+        right: Box::new(Expression::Value(Number::Int(9))),
     };
     let term2 = Expression::Op {
         op: Operation::Mul,
         left: Box::new(Expression::Op {
             op: Operation::Sub,
-            left: Box::new(Expression::Value(3)),
-            right: Box::new(Expression::Value(4)),
+            left: Box::new(Expression::Value(Number::Int(3))),
+            right: Box::new(Expression::Value(Number::Int(4))),
         }),
-        right: Box::new(Expression::Value(5)),
+        right: Box::new(Expression::Value(Number::Int(5))),
     };
     assert_eq!(
-        eval(Expression::Op {
+        eval(&Expression::Op {
             op: Operation::Add,
             left: Box::new(term1),
             right: Box::new(term2),
         }),
-        Ok(85)
+        Ok(Number::Int(85))
     );
 }
 
 #[test]
 fn test_error() {
     assert_eq!(
-        eval(Expression::Op {
+        eval(&Expression::Op {
             op: Operation::Div,
-            left: Box::new(Expression::Value(99)),
-            right: Box::new(Expression::Value(0)),
+            left: Box::new(Expression::Value(Number::Int(99))),
+            right: Box::new(Expression::Value(Number::Int(0))),
         }),
-        Err(String::from("division by zero"))
+        Err(EvalError::DivisionByZero { path: Path::default() })
+    );
+}
+
+#[test]
+fn test_parse_and_eval() {
+    assert_eq!(eval(&parser::parse("10 * 9 + (3 - 4) * 5").unwrap()), Ok(Number::Int(85)));
+    assert_eq!(
+        eval(&parser::parse("99 / 0").unwrap()),
+        Err(EvalError::DivisionByZero { path: Path::default() })
+    );
+}
+
+#[test]
+fn test_nested_error() {
+    // the failing division is the right child of the left child of the root
+    let error = eval(&parser::parse("(1 + 2 / (3 - 3)) * 4").unwrap()).unwrap_err();
+    assert_eq!(
+        error,
+        EvalError::DivisionByZero { path: Path(vec![Branch::Left, Branch::Right]) }
+    );
+    assert_eq!(error.to_string(), "division by zero at left.right");
+}
+
+#[cfg(test)]
+fn eval_op(mode: ArithmeticMode, op: Operation, left: i64, right: i64) -> Result<Number, EvalError> {
+    Evaluator::new().mode(mode).eval(&Expression::Op {
+        op,
+        left: Box::new(Expression::Value(Number::Int(left))),
+        right: Box::new(Expression::Value(Number::Int(right))),
+    })
+}
+
+#[test]
+fn test_arithmetic_modes_at_boundaries() {
+    use ArithmeticMode::*;
+    use Operation::*;
+
+    let overflow = |op| Err(EvalError::Overflow { op, path: Path::default() });
+
+    // (op, left, right, checked, wrapping, saturating)
+    let cases = [
+        (Add, i64::MAX, 1, overflow(Add), Ok(i64::MIN), Ok(i64::MAX)),
+        (Add, i64::MIN, -1, overflow(Add), Ok(i64::MAX), Ok(i64::MIN)),
+        (Add, i64::MAX, i64::MIN, Ok(-1), Ok(-1), Ok(-1)),
+        (Sub, i64::MIN, 1, overflow(Sub), Ok(i64::MAX), Ok(i64::MIN)),
+        (Sub, i64::MAX, -1, overflow(Sub), Ok(i64::MIN), Ok(i64::MAX)),
+        (Sub, 0, i64::MAX, Ok(-i64::MAX), Ok(-i64::MAX), Ok(-i64::MAX)),
+        (Mul, i64::MAX, 2, overflow(Mul), Ok(-2), Ok(i64::MAX)),
+        (Mul, i64::MIN, -1, overflow(Mul), Ok(i64::MIN), Ok(i64::MAX)),
+        (Mul, i64::MIN, 2, overflow(Mul), Ok(0), Ok(i64::MIN)),
+        (Mul, i64::MAX, -1, Ok(-i64::MAX), Ok(-i64::MAX), Ok(-i64::MAX)),
+        (Div, i64::MIN, -1, overflow(Div), Ok(i64::MIN), Ok(i64::MAX)),
+        (Div, i64::MIN, 1, Ok(i64::MIN), Ok(i64::MIN), Ok(i64::MIN)),
+        (Div, i64::MAX, -1, Ok(-i64::MAX), Ok(-i64::MAX), Ok(-i64::MAX)),
+    ];
+
+    let int = |expected: Result<i64, EvalError>| expected.map(Number::Int);
+    for (op, left, right, checked, wrapping, saturating) in cases {
+        assert_eq!(eval_op(Checked, op, left, right), int(checked), "{left} {op:?} {right}");
+        assert_eq!(eval_op(Wrapping, op, left, right), int(wrapping), "{left} {op:?} {right}");
+        assert_eq!(eval_op(Saturating, op, left, right), int(saturating), "{left} {op:?} {right}");
+    }
+}
+
+#[test]
+fn test_division_by_zero_in_every_mode() {
+    for mode in [ArithmeticMode::Checked, ArithmeticMode::Wrapping, ArithmeticMode::Saturating] {
+        assert_eq!(
+            eval_op(mode, Operation::Div, i64::MIN, 0),
+            Err(EvalError::DivisionByZero { path: Path::default() })
+        );
+    }
+}
+
+#[test]
+fn test_nested_overflow() {
+    let error = eval(&parser::parse("1 + 9223372036854775807 * 2").unwrap()).unwrap_err();
+    assert_eq!(error, EvalError::Overflow { op: Operation::Mul, path: Path(vec![Branch::Right]) });
+    assert_eq!(error.to_string(), "* overflowed at right");
+}
+
+#[test]
+fn test_variables() {
+    let formula = parser::parse("rate * hours + bonus").unwrap();
+    let alice = Environment::new().with("rate", 20).with("hours", 40).with("bonus", 100);
+    let bob = Environment::new().with("rate", 25).with("hours", 10).with("bonus", 0);
+    let evaluator = Evaluator::new();
+    assert_eq!(evaluator.eval_in(&formula, &alice), Ok(Number::Int(900)));
+    assert_eq!(evaluator.eval_in(&formula, &bob), Ok(Number::Int(250)));
+}
+
+#[test]
+fn test_unknown_variable() {
+    let formula = parser::parse("rate * hours + bonus").unwrap();
+    let env = Environment::new().with("rate", 20).with("bonus", 100);
+    let error = Evaluator::new().eval_in(&formula, &env).unwrap_err();
+    assert_eq!(
+        error,
+        EvalError::UnknownVariable {
+            name: "hours".to_string(),
+            path: Path(vec![Branch::Left, Branch::Right])
+        }
+    );
+    assert_eq!(error.to_string(), "unknown variable \"hours\" at left.right");
+}
+
+#[test]
+fn test_let_shadows_environment() {
+    let e = parser::parse("let x = x + 1 in let x = x * 10 in x + y").unwrap();
+    let env = Environment::new().with("x", 4).with("y", 2);
+    assert_eq!(Evaluator::new().eval_in(&e, &env), Ok(Number::Int(52)));
+    // the environment itself is left untouched
+    assert_eq!(env.get("x"), Some(Number::Int(4)));
+}
+
+#[test]
+fn test_let_error_path() {
+    let e = parser::parse("let x = 1 in let y = x / 0 in y").unwrap();
+    assert_eq!(
+        eval(&e),
+        Err(EvalError::DivisionByZero { path: Path(vec![Branch::Body, Branch::Value]) })
+    );
+}
+
+#[test]
+fn test_environment_scopes() {
+    let mut env = Environment::new().with("x", 1).with("y", 2);
+    env.push_scope();
+    env.bind("x", 10);
+    assert_eq!(env.get("x"), Some(Number::Int(10)));
+    assert_eq!(env.get("y"), Some(Number::Int(2)));
+    env.pop_scope();
+    assert_eq!(env.get("x"), Some(Number::Int(1)));
+    // the outermost scope stays put
+    env.pop_scope();
+    assert_eq!(env.get("y"), Some(Number::Int(2)));
+}
+
+#[test]
+fn test_extra_operators() {
+    let cases = [
+        ("17 % 5", 2),
+        ("-17 % 5", -2),
+        ("2 ** 10", 1024),
+        ("2 ** 3 ** 2", 512),
+        ("-2 ** 2", -4),
+        ("(-2) ** 3", -8),
+        ("7 ** 0", 1),
+        ("12 & 10", 8),
+        ("12 | 10", 14),
+        ("12 ^ 10", 6),
+        ("1 << 62", 1 << 62),
+        ("-16 >> 2", -4),
+        ("1 + 2 << 3", 24),
+        ("3 < 4", 1),
+        ("4 < 4", 0),
+        ("4 <= 4", 1),
+        ("5 > 4", 1),
+        ("4 >= 5", 0),
+        ("2 + 2 == 4", 1),
+        ("2 + 2 != 4", 0),
+        ("1 < 2 && 2 < 3", 1),
+        ("1 > 2 || 2 > 3", 0),
+        ("5 && 7", 1),
+        ("0 || -3", 1),
+        ("1 | 2 == 3", 1),
+    ];
+    for (input, expected) in cases {
+        assert_eq!(eval(&parser::parse(input).unwrap()), Ok(Number::Int(expected)), "{input}");
+    }
+}
+
+#[test]
+fn test_short_circuit() {
+    // the right hand side would fail, but is never evaluated
+    assert_eq!(eval(&parser::parse("0 && 1 / 0").unwrap()), Ok(Number::Int(0)));
+    assert_eq!(eval(&parser::parse("2 || missing").unwrap()), Ok(Number::Int(1)));
+    assert_eq!(
+        eval(&parser::parse("1 && 1 / 0").unwrap()),
+        Err(EvalError::DivisionByZero { path: Path(vec![Branch::Right]) })
+    );
+}
+
+#[test]
+fn test_if() {
+    let rule = parser::parse("if hours > 40 then 40 * rate + (hours - 40) * rate * 3 / 2 else hours * rate")
+        .unwrap();
+    let evaluator = Evaluator::new();
+    let env = |hours| Environment::new().with("rate", 20).with("hours", hours);
+    assert_eq!(evaluator.eval_in(&rule, &env(30)), Ok(Number::Int(600)));
+    assert_eq!(evaluator.eval_in(&rule, &env(50)), Ok(Number::Int(1100)));
+
+    // only the chosen branch is evaluated
+    assert_eq!(eval(&parser::parse("if 0 then 1 / 0 else 2").unwrap()), Ok(Number::Int(2)));
+    assert_eq!(
+        eval(&parser::parse("if 1 then 1 / 0 else 2").unwrap()),
+        Err(EvalError::DivisionByZero { path: Path(vec![Branch::Then]) })
+    );
+}
+
+#[test]
+fn test_extra_operator_errors() {
+    let error = |input| eval(&parser::parse(input).unwrap()).unwrap_err();
+    assert_eq!(error("5 % 0"), EvalError::DivisionByZero { path: Path::default() });
+    assert_eq!(error("0 ** -1"), EvalError::DivisionByZero { path: Path::default() });
+    assert_eq!(
+        error("2.5 & 1"),
+        EvalError::NotAnInteger { op: Operation::BitAnd, path: Path::default() }
+    );
+    assert_eq!(error("1 << 1 / 2").to_string(), "<< needs integers at root");
+    assert_eq!(error("1 << 64"), EvalError::InvalidShift { amount: 64, path: Path::default() });
+    assert_eq!(error("1 >> -1"), EvalError::InvalidShift { amount: -1, path: Path::default() });
+    assert_eq!(error("1 >> -1").to_string(), "cannot shift by -1 bits at root");
+}
+
+#[test]
+fn test_extra_operators_at_boundaries() {
+    use ArithmeticMode::*;
+    use Operation::*;
+
+    let overflow = |op| Err(EvalError::Overflow { op, path: Path::default() });
+
+    // (op, left, right, checked, wrapping, saturating)
+    let cases = [
+        (Rem, i64::MIN, -1, overflow(Rem), Ok(0), Ok(0)),
+        (Rem, i64::MIN, i64::MAX, Ok(-1), Ok(-1), Ok(-1)),
+        (Pow, 2, 63, overflow(Pow), Ok(i64::MIN), Ok(i64::MAX)),
+        (Pow, -2, 63, Ok(i64::MIN), Ok(i64::MIN), Ok(i64::MIN)),
+        (Pow, -2, 64, overflow(Pow), Ok(0), Ok(i64::MAX)),
+        (Pow, -3, 41, overflow(Pow), Ok(3i64.wrapping_pow(41).wrapping_neg()), Ok(i64::MIN)),
+        (Pow, 3, 39, Ok(3i64.pow(39)), Ok(3i64.pow(39)), Ok(3i64.pow(39))),
+        (Pow, -1, i64::MAX, Ok(-1), Ok(-1), Ok(-1)),
+        (Pow, 0, i64::MAX, Ok(0), Ok(0), Ok(0)),
+        (Shl, 1, 63, overflow(Shl), Ok(i64::MIN), Ok(i64::MAX)),
+        (Shl, -1, 63, Ok(i64::MIN), Ok(i64::MIN), Ok(i64::MIN)),
+        (Shl, i64::MIN, 1, overflow(Shl), Ok(0), Ok(i64::MIN)),
+        (Shr, i64::MIN, 63, Ok(-1), Ok(-1), Ok(-1)),
+        (BitAnd, i64::MIN, -1, Ok(i64::MIN), Ok(i64::MIN), Ok(i64::MIN)),
+        (BitOr, i64::MIN, i64::MAX, Ok(-1), Ok(-1), Ok(-1)),
+        (BitXor, i64::MAX, -1, Ok(i64::MIN), Ok(i64::MIN), Ok(i64::MIN)),
+        (Lt, i64::MIN, i64::MAX, Ok(1), Ok(1), Ok(1)),
+        (Le, i64::MAX, i64::MAX, Ok(1), Ok(1), Ok(1)),
+        (Gt, i64::MIN, i64::MAX, Ok(0), Ok(0), Ok(0)),
+        (Ge, i64::MIN, i64::MIN, Ok(1), Ok(1), Ok(1)),
+        (Eq, i64::MIN, i64::MAX, Ok(0), Ok(0), Ok(0)),
+        (Ne, i64::MIN, i64::MAX, Ok(1), Ok(1), Ok(1)),
+        (And, i64::MIN, i64::MAX, Ok(1), Ok(1), Ok(1)),
+        (Or, i64::MIN, 0, Ok(1), Ok(1), Ok(1)),
+    ];
+
+    let int = |expected: Result<i64, EvalError>| expected.map(Number::Int);
+    for (op, left, right, checked, wrapping, saturating) in cases {
+        assert_eq!(eval_op(Checked, op, left, right), int(checked), "{left} {op:?} {right}");
+        assert_eq!(eval_op(Wrapping, op, left, right), int(wrapping), "{left} {op:?} {right}");
+        assert_eq!(eval_op(Saturating, op, left, right), int(saturating), "{left} {op:?} {right}");
+    }
+}
+
+#[test]
+fn test_numbers() {
+    let fraction = |numer, denom| Ok(Number::Rational(Rational::new(numer, denom).unwrap()));
+    let cases = [
+        ("7 / 2", fraction(7, 2)),
+        ("6 / 3", Ok(Number::Int(2))),
+        ("7 / 2 * 2", Ok(Number::Int(7))),
+        ("1 / 3 + 1 / 6", fraction(1, 2)),
+        ("-7 / 2 % (4 / 3)", fraction(-5, 6)),
+        ("2 ** -2", fraction(1, 4)),
+        ("(2 / 3) ** -2", fraction(9, 4)),
+        ("7 / 2 + 0.5", Ok(Number::Float(4.0))),
+        ("4 ** (1 / 2)", Ok(Number::Float(2.0))),
+        ("7.5 % 2", Ok(Number::Float(1.5))),
+        ("1.5e3 - 1", Ok(Number::Float(1499.0))),
+        ("1 / 2 == 0.5", Ok(Number::Int(1))),
+        ("1 / 3 < 0.34", Ok(Number::Int(1))),
+        ("2 / 4 != 1 / 2", Ok(Number::Int(0))),
+        ("0.0 || 1 / 2", Ok(Number::Int(1))),
+        ("if 0.0 then 1 else 2", Ok(Number::Int(2))),
+    ];
+    for (input, expected) in cases {
+        assert_eq!(eval(&parser::parse(input).unwrap()), expected, "{input}");
+    }
+}
+
+#[test]
+fn test_number_errors() {
+    let error = |input| eval(&parser::parse(input).unwrap()).unwrap_err();
+    assert_eq!(error("1.5 / 0"), EvalError::DivisionByZero { path: Path::default() });
+    assert_eq!(error("1 / 2 % 0.0"), EvalError::DivisionByZero { path: Path::default() });
+    assert_eq!(
+        error("1 / 2 | 0"),
+        EvalError::NotAnInteger { op: Operation::BitOr, path: Path::default() }
+    );
+    assert_eq!(
+        error("1 / 9223372036854775807 / 2"),
+        EvalError::Overflow { op: Operation::Div, path: Path::default() }
     );
+    // the fraction's numerator does not fit, whatever the mode
+    let e = parser::parse("-9223372036854775808 / -3").unwrap();
+    for mode in [ArithmeticMode::Checked, ArithmeticMode::Wrapping, ArithmeticMode::Saturating] {
+        assert_eq!(
+            Evaluator::new().mode(mode).eval(&e),
+            Err(EvalError::Overflow { op: Operation::Div, path: Path::default() })
+        );
+    }
+}
+
+#[test]
+fn test_exact_pricing() {
+    let total = parser::parse("price * quantity * (100 - discount) / 100").unwrap();
+    let env = Environment::new()
+        .with("price", Rational::new(1999, 100).unwrap())
+        .with("quantity", 3)
+        .with("discount", 15);
+    // 19.99 * 3 * 0.85 = 50.9745, with nothing lost to rounding
+    assert_eq!(
+        Evaluator::new().eval_in(&total, &env),
+        Ok(Number::Rational(Rational::new(509_745, 10_000).unwrap()))
+    );
+}
+
+#[test]
+fn test_builtin_functions() {
+    let value = |input| eval(&parser::parse(input).unwrap());
+    let five_halves = Number::Rational(Rational::new(5, 2).unwrap());
+    assert_eq!(value("abs(-3) + max(1, 7 / 2, 2) - min(4)"), Ok(five_halves));
+    assert_eq!(value("clamp(12, 0, 10) + clamp(-2, 0, 10)"), Ok(Number::Int(10)));
+    assert_eq!(value("max(0.5, 1)"), Ok(Number::Int(1)));
+}
+
+#[test]
+fn test_user_functions() {
+    let value = |input| eval(&parser::parse(input).unwrap());
+    assert_eq!(value("let square(x) = x * x in square(3) + square(4)"), Ok(Number::Int(25)));
+    assert_eq!(
+        value("let fact(n) = if n <= 1 then 1 else n * fact(n - 1) in fact(10)"),
+        Ok(Number::Int(3_628_800))
+    );
+    // functions see the variables around their definition, not their call
+    assert_eq!(value("let k = 3 in let add(x) = x + k in let k = 100 in add(k)"), Ok(Number::Int(103)));
+    assert_eq!(
+        value("let adder(a) = (let add(b) = a + b in add(10)) in adder(1) + adder(2)"),
+        Ok(Number::Int(23))
+    );
+    // a definition shadows a built-in of the same name
+    assert_eq!(value("let abs(x) = 42 in abs(-1)"), Ok(Number::Int(42)));
+    assert_eq!(value("let f(x) = x in let x = 5 in f(x) + x"), Ok(Number::Int(10)));
+}
+
+#[test]
+fn test_function_errors() {
+    let error = |input| eval(&parser::parse(input).unwrap()).unwrap_err();
+    let e = error("1 + nothing(2)");
+    let path = Path(vec![Branch::Right]);
+    assert_eq!(e, EvalError::UnknownFunction { name: "nothing".to_string(), path });
+    assert_eq!(e.to_string(), "unknown function \"nothing\" at right");
+
+    let e = error("let f(x, y) = x in f(1)");
+    let expected = Arity::Exactly(2);
+    let path = Path(vec![Branch::Rest]);
+    assert_eq!(e, EvalError::WrongArity { name: "f".to_string(), expected, found: 1, path });
+    assert_eq!(e.to_string(), "f takes 2 arguments but was given 1 at rest");
+    assert_eq!(error("max()").to_string(), "max takes at least 1 argument but was given 0 at root");
+    assert_eq!(
+        error("clamp(1, 3, 2)").to_string(),
+        "clamp failed: the lower bound is above the upper bound at root"
+    );
+
+    // errors inside a function point through the call into its body
+    let e = error("let f(x) = 1 / x in f(1) + f(0)");
+    let path = Path(vec![Branch::Rest, Branch::Right, Branch::Call]);
+    assert_eq!(e, EvalError::DivisionByZero { path });
+    assert_eq!(error("abs(2, 1 / 0)").path(), &Path(vec![Branch::Arg(1)]));
+}
+
+#[test]
+fn test_call_depth() {
+    let e = parser::parse("let down(n) = if n == 0 then 0 else down(n - 1) in down(50)").unwrap();
+    assert_eq!(Evaluator::new().eval(&e), Ok(Number::Int(0)));
+    let error = Evaluator::new().max_call_depth(50).eval(&e).unwrap_err();
+    assert!(matches!(error, EvalError::CallTooDeep { .. }), "{error}");
+    // rest, then call.otherwise for each of the calls that did happen
+    assert_eq!(error.path().0.len(), 1 + 50 * 2);
+
+    // runaway recursion is an error rather than a stack overflow
+    let e = parser::parse("let loop(n) = loop(n + 1) in loop(0)").unwrap();
+    assert!(matches!(eval(&e), Err(EvalError::CallTooDeep { .. })));
+}
+
+#[test]
+fn test_registered_functions() {
+    let evaluator = Evaluator::new().function("hypot", 2, |args: &[Number]| {
+        Ok(Number::Float(args[0].to_f64().hypot(args[1].to_f64())))
+    });
+    let e = parser::parse("hypot(3, 4) + abs(-1)").unwrap();
+    assert_eq!(evaluator.eval(&e), Ok(Number::Float(6.0)));
+
+    // without the built-ins only the registered functions are there
+    let mut functions = Functions::new();
+    functions.register("answer", 0, |_: &[Number]| Ok(Number::Int(42)));
+    let evaluator = Evaluator::new().functions(functions);
+    assert_eq!(evaluator.eval(&parser::parse("answer()").unwrap()), Ok(Number::Int(42)));
+    assert!(matches!(
+        evaluator.eval(&parser::parse("abs(1)").unwrap()),
+        Err(EvalError::UnknownFunction { .. })
+    ));
+}
+
+#[test]
+fn test_deep_tree() {
+    // ((((1 + 1) + 1) + 1) ...), a million levels deep
+    const DEPTH: i64 = 1_000_000;
+    let mut e = Expression::Value(Number::Int(1));
+    for _ in 0..DEPTH {
+        e = Expression::Op {
+            op: Operation::Add,
+            left: Box::new(e),
+            right: Box::new(Expression::Value(Number::Int(1))),
+        };
+    }
+    assert_eq!(eval(&e), Ok(Number::Int(DEPTH + 1)));
+    // neither must cloning, comparing or dropping it
+    let copy = e.clone();
+    assert!(copy == e);
+    let Expression::Op { left, .. } = &e else { unreachable!() };
+    assert!(**left != e);
+    drop(e);
+    drop(copy);
+}
+
+#[test]
+fn test_deep_tree_of_every_node() {
+    // if x > 0 then 1 && (let x = x - 1 in ...) else -1, so this time the
+    // tree is deep through the right hand sides and every other node kind
+    const DEPTH: usize = 200_000;
+    let tree = |innermost: &str| {
+        let mut e = Expression::Variable(innermost.to_string());
+        for _ in 0..DEPTH {
+            let rebound = Expression::Let {
+                name: "x".to_string(),
+                value: Box::new(parser::parse("x - 1").unwrap()),
+                body: Box::new(e),
+            };
+            e = Expression::If {
+                cond: Box::new(parser::parse("x > 0").unwrap()),
+                then: Box::new(Expression::Op {
+                    op: Operation::And,
+                    left: Box::new(Expression::Value(Number::Int(1))),
+                    right: Box::new(rebound),
+                }),
+                otherwise: Box::new(Expression::Value(Number::Int(-1))),
+            };
+        }
+        e
+    };
+    let e = tree("x");
+    let env = |x| Environment::new().with("x", x);
+    assert_eq!(Evaluator::new().eval_in(&e, &env(DEPTH as i64)), Ok(Number::Int(0)));
+    assert_eq!(Evaluator::new().eval_in(&e, &env(DEPTH as i64 + 1)), Ok(Number::Int(1)));
+    assert!(e.clone() == e);
+    // a difference right at the bottom
+    assert!(tree("y") != e);
+
+    // the error path is as deep as the tree
+    let mut tree = parser::parse("1 / 0").unwrap();
+    for _ in 0..DEPTH {
+        tree = Expression::Let {
+            name: "y".to_string(),
+            value: Box::new(Expression::Value(Number::Int(0))),
+            body: Box::new(tree),
+        };
+    }
+    let error = eval(&tree).unwrap_err();
+    assert_eq!(error.path().0.len(), DEPTH);
 }
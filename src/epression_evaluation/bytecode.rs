@@ -0,0 +1,598 @@
+use super::{Arity, Branch, Environment, EvalError, Evaluator, Expression, Number, Operation, Path};
+
+/// A single instruction for the stack machine. Most instructions pop their
+/// operands off the value stack and push their result back onto it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Instruction {
+    /// Push a constant.
+    Push(Number),
+    /// Push the value bound to the `n`th local of the function being run,
+    /// counting from its first parameter. Outside of any function, count
+    /// from the outermost `Let` that is currently in scope.
+    LoadLocal(usize),
+    /// Push a local of a function further out: go `hops` functions out,
+    /// following where each function was defined rather than where it was
+    /// called from, and take the local there like `LoadLocal` does.
+    LoadOuter(usize, usize),
+    /// Push the value of the `n`th entry of `Program::globals`, looked up
+    /// in the environment.
+    LoadGlobal(usize),
+    /// Pop a value and bind it as the innermost local.
+    Bind,
+    /// Forget the innermost local.
+    Unbind,
+    /// Pop the right and then the left operand and push the result.
+    Apply(Operation),
+    /// Look at the left operand of `&&` or `||` on top of the stack. If it
+    /// already decides the result, replace it with that result and jump to
+    /// the target, otherwise carry on with the right operand.
+    ShortCircuit(Operation, usize),
+    /// Pop a value and jump to the target if it is zero.
+    JumpIfFalse(usize),
+    /// Jump to the target.
+    Jump(usize),
+    /// Pop the given number of arguments and call a function with them.
+    Call(Callee, usize),
+    /// Leave the function being run, with its result on the stack.
+    Return,
+}
+
+/// What a `Call` instruction calls.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Callee {
+    /// A function the expression defines, whose code starts at `entry`.
+    /// It was defined `hops` functions further out than the call.
+    Defined { name: String, entry: usize, params: usize, hops: usize },
+    /// A function registered with the evaluator, looked up by name.
+    Registered(String),
+}
+
+/// An `Expression` compiled into a flat list of instructions.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    pub code: Vec<Instruction>,
+    /// Names of the variables that are not bound by a `Let` in the
+    /// expression and have to come from the environment.
+    pub globals: Vec<String>,
+    /// For every instruction, the node of the expression it came from (as
+    /// an index into `nodes`), so that errors are reported exactly like
+    /// `eval` does. `None` is the root, or the body of the function the
+    /// instruction belongs to.
+    origins: Vec<Option<usize>>,
+    /// Every node of the expression below the root, as the node above it
+    /// and the branch taken from there. Storing whole paths for each
+    /// instruction would take quadratic space on deep trees.
+    nodes: Vec<(Option<usize>, Branch)>,
+}
+
+impl Program {
+    /// The path to the node the instruction at `pc` came from, from the
+    /// root or from the body of the function it is in.
+    fn path(&self, pc: usize) -> Vec<Branch> {
+        let mut branches = Vec::new();
+        let mut node = self.origins[pc];
+        while let Some(index) = node {
+            let (parent, branch) = self.nodes[index];
+            branches.push(branch);
+            node = parent;
+        }
+        branches.reverse();
+        branches
+    }
+
+    /// The full path to the instruction at `pc`, through every call that
+    /// is in progress, the way `eval` reports it.
+    fn trace(&self, frames: &[Frame], pc: usize) -> Path {
+        let mut branches = Vec::new();
+        // the outermost frame is not a call
+        for frame in &frames[1..] {
+            branches.extend(self.path(frame.call));
+            branches.push(Branch::Call);
+        }
+        branches.extend(self.path(pc));
+        Path(branches)
+    }
+}
+
+/// A piece of work for the compiler, which keeps its own stack for the
+/// same reason the evaluator does.
+enum Step<'e> {
+    /// Compile the child of the current node reached through `Branch`.
+    Node(Branch, &'e Expression),
+    /// A child is done, step back up to its parent.
+    Leave,
+    /// Emit an instruction that belongs to the current node.
+    Emit(Instruction),
+    /// Emit a jump whose target is not known yet, and remember where it is.
+    EmitForward(Instruction),
+    /// Point the most recently remembered jump at the next instruction.
+    Land,
+    /// Between the branches of an `If`: jump over the else branch, and
+    /// make the condition's jump land here.
+    Else,
+    /// A `Let` body starts, its name is now in scope.
+    Scope(&'e str),
+    /// A `Let` body ends.
+    Unscope,
+    /// A function is defined here, its body comes next.
+    Define(&'e str, &'e [String]),
+    /// Compile the body of the function just defined.
+    Body(&'e Expression),
+    /// The body of a function is done.
+    Exit,
+    /// The rest of a `Function` is done, the function goes out of scope.
+    Undefine,
+}
+
+/// What is in scope inside one function, or outside of all of them.
+struct Scope<'e> {
+    /// The parameters and the names bound by the `Let`s we are inside of,
+    /// innermost last. The position of a name here is its slot in the
+    /// function's part of the VM's local stack.
+    locals: Vec<&'e str>,
+    /// The functions defined around us, with where their code starts and
+    /// how many parameters they take.
+    functions: Vec<(&'e str, usize, usize)>,
+    /// The node the function was defined at, to carry on from when its
+    /// body is done.
+    definition: Option<usize>,
+}
+
+struct Compiler<'e> {
+    program: Program,
+    /// The node being compiled, `None` for the root or the root of a
+    /// function body.
+    current: Option<usize>,
+    /// One scope for every function we are inside of, innermost last.
+    scopes: Vec<Scope<'e>>,
+    /// Jumps waiting for their target.
+    forward: Vec<usize>,
+}
+
+impl<'e> Compiler<'e> {
+    fn emit(&mut self, instruction: Instruction) {
+        self.program.code.push(instruction);
+        self.program.origins.push(self.current);
+    }
+
+    fn global(&mut self, name: &str) -> usize {
+        match self.program.globals.iter().position(|global| global == name) {
+            Some(index) => index,
+            None => {
+                self.program.globals.push(name.to_string());
+                self.program.globals.len() - 1
+            }
+        }
+    }
+
+    /// How to load the variable `name` from where we are.
+    fn variable(&mut self, name: &str) -> Instruction {
+        for (hops, scope) in self.scopes.iter().rev().enumerate() {
+            if let Some(slot) = scope.locals.iter().rposition(|local| *local == name) {
+                return match hops {
+                    0 => Instruction::LoadLocal(slot),
+                    _ => Instruction::LoadOuter(hops, slot),
+                };
+            }
+        }
+        Instruction::LoadGlobal(self.global(name))
+    }
+
+    /// What a call to `name` from where we are calls.
+    fn callee(&self, name: &str) -> Callee {
+        for (hops, scope) in self.scopes.iter().rev().enumerate() {
+            if let Some(&(_, entry, params)) = scope.functions.iter().rev().find(|(f, ..)| *f == name) {
+                return Callee::Defined { name: name.to_string(), entry, params, hops };
+            }
+        }
+        Callee::Registered(name.to_string())
+    }
+
+    fn scope(&mut self) -> &mut Scope<'e> {
+        self.scopes.last_mut().expect("outside of every scope")
+    }
+
+    /// Point the jump at `at` to the next instruction to be emitted.
+    fn land(&mut self, at: usize) {
+        let here = self.program.code.len();
+        match &mut self.program.code[at] {
+            Instruction::ShortCircuit(_, target)
+            | Instruction::JumpIfFalse(target)
+            | Instruction::Jump(target) => *target = here,
+            other => unreachable!("{other:?} is not a jump"),
+        }
+    }
+
+    /// Compile the node at the current path. Steps run in the reverse order
+    /// they are pushed in.
+    fn node(&mut self, e: &'e Expression, steps: &mut Vec<Step<'e>>) {
+        match e {
+            Expression::Op { op, left, right } => {
+                if let Operation::And | Operation::Or = op {
+                    steps.push(Step::Land);
+                    steps.push(Step::Emit(Instruction::Apply(*op)));
+                    steps.push(Step::Node(Branch::Right, right));
+                    steps.push(Step::EmitForward(Instruction::ShortCircuit(*op, 0)));
+                } else {
+                    steps.push(Step::Emit(Instruction::Apply(*op)));
+                    steps.push(Step::Node(Branch::Right, right));
+                }
+                steps.push(Step::Node(Branch::Left, left));
+            }
+            Expression::Value(val) => self.emit(Instruction::Push(*val)),
+            Expression::Variable(name) => {
+                let instruction = self.variable(name);
+                self.emit(instruction);
+            }
+            Expression::Let { name, value, body } => {
+                steps.push(Step::Emit(Instruction::Unbind));
+                steps.push(Step::Unscope);
+                steps.push(Step::Node(Branch::Body, body));
+                steps.push(Step::Scope(name));
+                steps.push(Step::Emit(Instruction::Bind));
+                steps.push(Step::Node(Branch::Value, value));
+            }
+            Expression::If { cond, then, otherwise } => {
+                steps.push(Step::Land);
+                steps.push(Step::Node(Branch::Else, otherwise));
+                steps.push(Step::Else);
+                steps.push(Step::Node(Branch::Then, then));
+                steps.push(Step::EmitForward(Instruction::JumpIfFalse(0)));
+                steps.push(Step::Node(Branch::Cond, cond));
+            }
+            Expression::Call { name, args } => {
+                steps.push(Step::Emit(Instruction::Call(self.callee(name), args.len())));
+                for (i, arg) in args.iter().enumerate().rev() {
+                    steps.push(Step::Node(Branch::Arg(i), arg));
+                }
+            }
+            Expression::Function { name, params, body, rest } => {
+                // the body goes right here, with a jump around it
+                steps.push(Step::Undefine);
+                steps.push(Step::Node(Branch::Rest, rest));
+                steps.push(Step::Land);
+                steps.push(Step::Exit);
+                steps.push(Step::Emit(Instruction::Return));
+                steps.push(Step::Body(body));
+                steps.push(Step::Define(name, params));
+                steps.push(Step::EmitForward(Instruction::Jump(0)));
+            }
+        }
+    }
+
+    fn step(&mut self, step: Step<'e>, steps: &mut Vec<Step<'e>>) {
+        match step {
+            Step::Node(branch, e) => {
+                self.program.nodes.push((self.current, branch));
+                self.current = Some(self.program.nodes.len() - 1);
+                steps.push(Step::Leave);
+                self.node(e, steps);
+            }
+            Step::Leave => {
+                let current = self.current.expect("left the root");
+                self.current = self.program.nodes[current].0;
+            }
+            Step::Emit(instruction) => self.emit(instruction),
+            Step::EmitForward(instruction) => {
+                self.forward.push(self.program.code.len());
+                self.emit(instruction);
+            }
+            Step::Land => {
+                let at = self.forward.pop().unwrap();
+                self.land(at);
+            }
+            Step::Else => {
+                let condition = self.forward.pop().unwrap();
+                self.forward.push(self.program.code.len());
+                self.emit(Instruction::Jump(0));
+                self.land(condition);
+            }
+            Step::Scope(name) => self.scope().locals.push(name),
+            Step::Unscope => {
+                self.scope().locals.pop();
+            }
+            Step::Define(name, params) => {
+                let entry = self.program.code.len();
+                self.scope().functions.push((name, entry, params.len()));
+                self.scopes.push(Scope {
+                    locals: params.iter().map(String::as_str).collect(),
+                    functions: Vec::new(),
+                    definition: self.current,
+                });
+                self.current = None;
+            }
+            Step::Body(body) => self.node(body, steps),
+            Step::Exit => {
+                let scope = self.scopes.pop().expect("exited the outermost scope");
+                self.current = scope.definition;
+            }
+            Step::Undefine => {
+                self.scope().functions.pop();
+            }
+        }
+    }
+}
+
+/// Compile an expression to a `Program` that `Evaluator::run` can execute
+/// over and over again, against different environments.
+pub fn compile(e: &Expression) -> Program {
+    let mut compiler = Compiler {
+        program: Program {
+            code: Vec::new(),
+            globals: Vec::new(),
+            origins: Vec::new(),
+            nodes: Vec::new(),
+        },
+        current: None,
+        scopes: vec![Scope { locals: Vec::new(), functions: Vec::new(), definition: None }],
+        forward: Vec::new(),
+    };
+
+    let mut steps = Vec::new();
+    compiler.node(e, &mut steps);
+    while let Some(step) = steps.pop() {
+        compiler.step(step, &mut steps);
+    }
+
+    compiler.program
+}
+
+/// A call in progress on the VM.
+struct Frame {
+    /// Where the function's locals start on the local stack.
+    base: usize,
+    /// The frame of the function the called one was defined in.
+    link: usize,
+    /// The `Call` instruction, to return after and to report errors at.
+    call: usize,
+}
+
+impl Evaluator {
+    /// Run a compiled program, looking its globals up in `env`. Gives the
+    /// same result, or the same error, as `eval_in` does on the expression
+    /// the program was compiled from.
+    pub fn run(&self, program: &Program, env: &Environment) -> Result<Number, EvalError> {
+        // look every global up once, but only complain about a missing one
+        // if it is actually used
+        let globals: Vec<Option<Number>> = program.globals.iter().map(|name| env.get(name)).collect();
+        let mut locals = Vec::new();
+        let mut values = Vec::new();
+        // the outermost frame stands for the expression itself
+        let mut frames = vec![Frame { base: 0, link: 0, call: 0 }];
+        let mut pc = 0;
+
+        while let Some(instruction) = program.code.get(pc) {
+            pc += 1;
+            let frame = frames.last().unwrap();
+            match instruction {
+                Instruction::Push(value) => values.push(*value),
+                Instruction::LoadLocal(slot) => values.push(locals[frame.base + slot]),
+                Instruction::LoadOuter(hops, slot) => {
+                    let mut outer = frames.len() - 1;
+                    for _ in 0..*hops {
+                        outer = frames[outer].link;
+                    }
+                    values.push(locals[frames[outer].base + slot]);
+                }
+                Instruction::LoadGlobal(index) => match globals[*index] {
+                    Some(value) => values.push(value),
+                    None => {
+                        return Err(EvalError::UnknownVariable {
+                            name: program.globals[*index].clone(),
+                            path: program.trace(&frames, pc - 1),
+                        });
+                    }
+                },
+                Instruction::Bind => locals.push(values.pop().unwrap()),
+                Instruction::Unbind => {
+                    locals.pop();
+                }
+                Instruction::Apply(op) => {
+                    let right = values.pop().unwrap();
+                    let left = values.pop().unwrap();
+                    // the path is only worked out if something went wrong
+                    let result = self.mode.apply(*op, left, right, &[]).map_err(|mut error| {
+                        *error.path_mut() = program.trace(&frames, pc - 1);
+                        error
+                    })?;
+                    values.push(result);
+                }
+                Instruction::ShortCircuit(op, target) => {
+                    let left = values.last_mut().unwrap();
+                    match (op, !left.is_zero()) {
+                        (Operation::And, false) => *left = Number::Int(0),
+                        (Operation::Or, true) => *left = Number::Int(1),
+                        _ => continue,
+                    }
+                    pc = *target;
+                }
+                Instruction::JumpIfFalse(target) => {
+                    if values.pop().unwrap().is_zero() {
+                        pc = *target;
+                    }
+                }
+                Instruction::Jump(target) => pc = *target,
+                Instruction::Call(Callee::Defined { name, entry, params, hops }, count) => {
+                    if params != count {
+                        return Err(EvalError::WrongArity {
+                            name: name.clone(),
+                            expected: Arity::Exactly(*params),
+                            found: *count,
+                            path: program.trace(&frames, pc - 1),
+                        });
+                    }
+                    if frames.len() > self.max_call_depth {
+                        return Err(EvalError::CallTooDeep { path: program.trace(&frames, pc - 1) });
+                    }
+                    let mut link = frames.len() - 1;
+                    for _ in 0..*hops {
+                        link = frames[link].link;
+                    }
+                    let base = locals.len();
+                    locals.extend(values.drain(values.len() - count..));
+                    frames.push(Frame { base, link, call: pc - 1 });
+                    pc = *entry;
+                }
+                Instruction::Call(Callee::Registered(name), count) => {
+                    let args = values.split_off(values.len() - count);
+                    let result = self.call_native(name, &args, &[]).map_err(|mut error| {
+                        *error.path_mut() = program.trace(&frames, pc - 1);
+                        error
+                    })?;
+                    values.push(result);
+                }
+                Instruction::Return => {
+                    let frame = frames.pop().expect("returned from the outermost frame");
+                    locals.truncate(frame.base);
+                    pc = frame.call + 1;
+                }
+            }
+        }
+
+        Ok(values.pop().expect("program left no value on the stack"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::epression_evaluation::random::Rng;
+    use crate::epression_evaluation::{parser, ArithmeticMode};
+
+    /// Check that the VM agrees with `eval_in` on `input`, in every mode.
+    fn agrees(input: &str, env: &Environment) {
+        let e = parser::parse(input).unwrap();
+        let program = compile(&e);
+        for mode in [ArithmeticMode::Checked, ArithmeticMode::Wrapping, ArithmeticMode::Saturating] {
+            let evaluator = Evaluator::new().mode(mode);
+            assert_eq!(evaluator.run(&program, env), evaluator.eval_in(&e, env), "{input} in {mode:?}");
+        }
+    }
+
+    #[test]
+    fn matches_eval() {
+        let env = Environment::new().with("rate", 20).with("hours", 45).with("bonus", 100).with("big", i64::MAX);
+        let inputs = [
+            "19",
+            "10 * 9 + (3 - 4) * 5",
+            "rate * hours + bonus",
+            "if hours > 40 then 40 * rate + (hours - 40) * rate * 3 / 2 else hours * rate",
+            "let x = rate in let y = x * 2 in let x = y + 1 in x + y + hours",
+            "let rate = 1 in rate + bonus",
+            "(let x = 1 in x) + (let y = 2 in y)",
+            "0 && 1 / 0",
+            "1 && 1 / 0",
+            "2 || missing",
+            "0 || missing",
+            "1 < 2 && (2 < 3 || missing) && 3",
+            "if 0 then 1 / 0 else if 1 then 2 ** 10 else 3",
+            "big + 1",
+            "-big - 2",
+            "big * big % 7",
+            "2 ** 63 + (1 << 63)",
+            "7 ** -1",
+            "0 ** -1",
+            "7 / 2 + 1.5",
+            "(rate + 1) / 3 * hours",
+            "2 ** 0.5 < 1.5",
+            "7 / 2 & 1",
+            "1 << 64",
+            "rate * hours + unknown",
+            "let x = 1 in let y = x / 0 in y",
+            "(1 + 2 / (3 - 3)) * 4",
+            "max(rate, hours, bonus) - min(3, abs(-7))",
+            "clamp(hours, 0, 40) * rate + max(hours - 40, 0) * rate * 3 / 2",
+            "let double(x) = x * 2 in double(double(rate))",
+            "let fact(n) = if n <= 1 then 1 else n * fact(n - 1) in fact(20) + fact(21)",
+            "let k = 3 in let add(x) = x + k in let k = 100 in add(k)",
+            "let outer(a) = (let inner(b) = a * b + hours in inner(a + 1)) in outer(2) + outer(3)",
+            "let f() = 1 in let g() = f() + 1 in let f() = 10 in g() + f()",
+            "let f(x) = 1 / x in f(1) + f(0)",
+            "let loop(n) = loop(n + 1) in loop(0)",
+            "let f(x, y) = x in f(1)",
+            "clamp(1, 2)",
+            "clamp(1, 3, 2)",
+            "nothing(1 / 0)",
+            "abs(1 / 0)",
+        ];
+        for input in inputs {
+            agrees(input, &env);
+        }
+    }
+
+    #[test]
+    fn reused_across_environments() {
+        let program = compile(&parser::parse("rate * hours + bonus").unwrap());
+        assert_eq!(program.globals, vec!["rate", "hours", "bonus"]);
+
+        let evaluator = Evaluator::new();
+        for hours in 0..50 {
+            let env = Environment::new().with("rate", 20).with("hours", hours).with("bonus", 7);
+            assert_eq!(evaluator.run(&program, &env), Ok(Number::Int(20 * hours + 7)));
+        }
+    }
+
+    #[test]
+    fn instructions() {
+        let program = compile(&parser::parse("let x = 2 in if x then x && y else 3").unwrap());
+        assert_eq!(
+            program.code,
+            vec![
+                Instruction::Push(Number::Int(2)),
+                Instruction::Bind,
+                Instruction::LoadLocal(0),
+                Instruction::JumpIfFalse(9),
+                Instruction::LoadLocal(0),
+                Instruction::ShortCircuit(Operation::And, 8),
+                Instruction::LoadGlobal(0),
+                Instruction::Apply(Operation::And),
+                Instruction::Jump(10),
+                Instruction::Push(Number::Int(3)),
+                Instruction::Unbind,
+            ]
+        );
+    }
+
+    #[test]
+    fn function_instructions() {
+        let program = compile(&parser::parse("let f(a) = a + x in f(1)").unwrap());
+        let callee = Callee::Defined { name: "f".to_string(), entry: 1, params: 1, hops: 0 };
+        assert_eq!(
+            program.code,
+            vec![
+                Instruction::Jump(5),
+                Instruction::LoadLocal(0),
+                Instruction::LoadGlobal(0),
+                Instruction::Apply(Operation::Add),
+                Instruction::Return,
+                Instruction::Push(Number::Int(1)),
+                Instruction::Call(callee, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn agrees_on_random_expressions() {
+        let mut rng = Rng::new(10);
+        for _ in 0..5_000 {
+            let e = rng.expression(5);
+            let env = rng.environment();
+            let evaluator = Evaluator::new();
+            assert_eq!(evaluator.run(&compile(&e), &env), evaluator.eval_in(&e, &env), "{e}");
+        }
+    }
+
+    #[test]
+    fn deep_tree() {
+        const DEPTH: i64 = 1_000_000;
+        let mut e = Expression::Value(Number::Int(1));
+        for _ in 0..DEPTH {
+            e = Expression::Op {
+                op: Operation::Add,
+                left: Box::new(e),
+                right: Box::new(Expression::Value(Number::Int(1))),
+            };
+        }
+        assert_eq!(Evaluator::new().run(&compile(&e), &Environment::new()), Ok(Number::Int(DEPTH + 1)));
+    }
+}
@@ -0,0 +1,463 @@
+use std::fmt;
+
+use super::optimizer::Optimizer;
+use super::{Branch, Expression, Number, Operation, Path};
+
+/// Why an expression has no derivative that `derive` can give.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeriveError {
+    /// An operation that is not smooth, such as `&` or `<`, on operands
+    /// that depend on the variable.
+    NotDifferentiable { op: Operation, path: Path },
+    /// A call whose result depends on the variable, to a function whose
+    /// derivative is not known: anything but the built-in `ln` and `exp`.
+    UnknownDerivative { name: String, path: Path },
+}
+
+impl DeriveError {
+    pub fn path(&self) -> &Path {
+        match self {
+            DeriveError::NotDifferentiable { path, .. } => path,
+            DeriveError::UnknownDerivative { path, .. } => path,
+        }
+    }
+}
+
+impl fmt::Display for DeriveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeriveError::NotDifferentiable { op, path } => write!(f, "{op} has no derivative at {path}"),
+            DeriveError::UnknownDerivative { name, path } => {
+                write!(f, "the derivative of {name} is not known at {path}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for DeriveError {}
+
+/// The derivative of `e` with respect to the variable `var`, simplified.
+///
+/// Variables bound by a `Let` count as functions of `var` if their value
+/// does, so the chain rule applies through them. An `If` is treated as
+/// piecewise, each branch being differentiated on its own. Calls are only
+/// allowed to depend on `var` if they call the built-in `ln` or `exp`.
+///
+/// The result is meant to be evaluated where `e` succeeds. Terms that are
+/// multiplied by zero are left out, so it may succeed where `e` fails.
+/// Like the optimizer this recurses once per level of the tree.
+pub fn derive(e: &Expression, var: &str) -> Result<Expression, DeriveError> {
+    let mut deriver = Deriver { var, scope: Vec::new(), path: Vec::new() };
+    let derivative = deriver.derive(e)?;
+    Ok(Optimizer::new().optimize(&derivative))
+}
+
+/// What a name bound around the node being differentiated stands for.
+enum Local<'e> {
+    /// A `Let`, with the name its value's derivative is bound to, or
+    /// `None` if that derivative is zero.
+    Value(&'e str, Option<String>),
+    /// A function, and whether its result may depend on the variable.
+    Function(&'e str, bool),
+}
+
+struct Deriver<'e> {
+    var: &'e str,
+    /// Innermost last.
+    scope: Vec<Local<'e>>,
+    path: Vec<Branch>,
+}
+
+fn zero() -> Expression {
+    Expression::Value(Number::Int(0))
+}
+
+fn is_zero(e: &Expression) -> bool {
+    matches!(e, Expression::Value(Number::Int(0)))
+}
+
+fn binary(op: Operation, left: Expression, right: Expression) -> Expression {
+    Expression::Op { op, left: Box::new(left), right: Box::new(right) }
+}
+
+// The derivatives are built with these rather than `binary` so that terms that
+// are certainly zero disappear right away, which is how `Deriver` tells
+// that something does not depend on the variable.
+
+fn add(left: Expression, right: Expression) -> Expression {
+    match (is_zero(&left), is_zero(&right)) {
+        (true, _) => right,
+        (_, true) => left,
+        _ => binary(Operation::Add, left, right),
+    }
+}
+
+fn sub(left: Expression, right: Expression) -> Expression {
+    if is_zero(&right) {
+        return left;
+    }
+    binary(Operation::Sub, left, right)
+}
+
+fn mul(left: Expression, right: Expression) -> Expression {
+    match (&left, &right) {
+        (l, r) if is_zero(l) || is_zero(r) => zero(),
+        (Expression::Value(Number::Int(1)), _) => right,
+        (_, Expression::Value(Number::Int(1))) => left,
+        _ => binary(Operation::Mul, left, right),
+    }
+}
+
+fn div(left: Expression, right: Expression) -> Expression {
+    if is_zero(&left) {
+        return zero();
+    }
+    binary(Operation::Div, left, right)
+}
+
+fn pow(base: Expression, exp: Expression) -> Expression {
+    binary(Operation::Pow, base, exp)
+}
+
+fn call(name: &str, arg: Expression) -> Expression {
+    Expression::Call { name: name.to_string(), args: vec![arg] }
+}
+
+/// Whether `name` occurs anywhere in `e`, as whatever kind of name.
+fn mentions(e: &Expression, name: &str) -> bool {
+    match e {
+        Expression::Value(_) => false,
+        Expression::Variable(var) => var == name,
+        Expression::Op { left, right, .. } => mentions(left, name) || mentions(right, name),
+        Expression::Let { name: bound, value, body } => {
+            bound == name || mentions(value, name) || mentions(body, name)
+        }
+        Expression::If { cond, then, otherwise } => {
+            mentions(cond, name) || mentions(then, name) || mentions(otherwise, name)
+        }
+        Expression::Call { name: called, args } => {
+            called == name || args.iter().any(|arg| mentions(arg, name))
+        }
+        Expression::Function { name: defined, params, body, rest } => {
+            defined == name
+                || params.iter().any(|param| param == name)
+                || mentions(body, name)
+                || mentions(rest, name)
+        }
+    }
+}
+
+impl<'e> Deriver<'e> {
+    fn error_path(&self) -> Path {
+        Path(self.path.clone())
+    }
+
+    /// Differentiate the child of the current node reached through
+    /// `branch`.
+    fn child(&mut self, branch: Branch, e: &'e Expression) -> Result<Expression, DeriveError> {
+        self.path.push(branch);
+        let derivative = self.derive(e)?;
+        self.path.pop();
+        Ok(derivative)
+    }
+
+    /// The derivative of the variable `name`, as seen from here.
+    fn variable(&self, name: &str) -> Expression {
+        let local = self.scope.iter().rev().find_map(|local| match local {
+            Local::Value(bound, derivative) if *bound == name => Some(derivative),
+            _ => None,
+        });
+        match local {
+            Some(Some(derivative)) => Expression::Variable(derivative.clone()),
+            Some(None) => zero(),
+            None if name == self.var => Expression::Value(Number::Int(1)),
+            None => zero(),
+        }
+    }
+
+    /// A name for the derivative of the variable `name` that none of
+    /// `within` uses, and that is not already in use for another one.
+    fn fresh(&self, name: &str, within: [&Expression; 3]) -> String {
+        let taken = |fresh: &String| {
+            fresh == self.var
+                || within.iter().any(|e| mentions(e, fresh))
+                || self.scope.iter().any(|local| matches!(local, Local::Value(_, Some(d)) if d == fresh))
+        };
+        let mut fresh = format!("d_{name}");
+        while taken(&fresh) {
+            fresh.push('_');
+        }
+        fresh
+    }
+
+    /// Whether the function `name`, as seen from here, is one that `e`
+    /// defines, and if so whether it depends on the variable.
+    fn function(&self, name: &str) -> Option<bool> {
+        self.scope.iter().rev().find_map(|local| match local {
+            Local::Function(defined, varies) if *defined == name => Some(*varies),
+            _ => None,
+        })
+    }
+
+    /// Whether `e` may depend on the variable, through the names it uses
+    /// from around it. `values` and `functions` are the names bound inside
+    /// of `e` itself on the way down.
+    fn varies(&self, e: &Expression, values: &mut Vec<String>, functions: &mut Vec<String>) -> bool {
+        match e {
+            Expression::Value(_) => false,
+            Expression::Variable(name) => !values.contains(name) && !is_zero(&self.variable(name)),
+            Expression::Op { left, right, .. } => {
+                self.varies(left, values, functions) || self.varies(right, values, functions)
+            }
+            Expression::Let { name, value, body } => {
+                if self.varies(value, values, functions) {
+                    return true;
+                }
+                values.push(name.clone());
+                let varies = self.varies(body, values, functions);
+                values.pop();
+                varies
+            }
+            Expression::If { cond, then, otherwise } => {
+                self.varies(cond, values, functions)
+                    || self.varies(then, values, functions)
+                    || self.varies(otherwise, values, functions)
+            }
+            Expression::Call { name, args } => {
+                let defined_outside = !functions.contains(name) && self.function(name) == Some(true);
+                defined_outside || args.iter().any(|arg| self.varies(arg, values, functions))
+            }
+            Expression::Function { name, params, body, rest } => {
+                // the body sees the function itself, and its parameters
+                functions.push(name.clone());
+                let outer = values.len();
+                values.extend(params.iter().cloned());
+                let varies = self.varies(body, values, functions) || {
+                    values.truncate(outer);
+                    self.varies(rest, values, functions)
+                };
+                values.truncate(outer);
+                functions.pop();
+                varies
+            }
+        }
+    }
+
+    fn derive(&mut self, e: &'e Expression) -> Result<Expression, DeriveError> {
+        match e {
+            Expression::Value(_) => Ok(zero()),
+            Expression::Variable(name) => Ok(self.variable(name)),
+            Expression::Op { op, left, right } => self.operation(*op, left, right),
+            Expression::Let { name, value, body } => {
+                let d_value = self.child(Branch::Value, value)?;
+                if is_zero(&d_value) {
+                    self.scope.push(Local::Value(name, None));
+                    let d_body = self.child(Branch::Body, body);
+                    self.scope.pop();
+                    let d_body = d_body?;
+                    if is_zero(&d_body) {
+                        return Ok(zero());
+                    }
+                    let body = Box::new(d_body);
+                    return Ok(Expression::Let { name: name.clone(), value: value.clone(), body });
+                }
+
+                // bind the value's derivative under a name of its own, one
+                // that nothing else uses, outside of the `Let` so that the
+                // name being bound does not change what it means
+                let fresh = self.fresh(name, [value, body, &d_value]);
+                self.scope.push(Local::Value(name, Some(fresh.clone())));
+                let d_body = self.child(Branch::Body, body);
+                self.scope.pop();
+                let d_body = d_body?;
+                if is_zero(&d_body) {
+                    return Ok(zero());
+                }
+                let (value, d_body) = (value.clone(), Box::new(d_body));
+                let body = Box::new(Expression::Let { name: name.clone(), value, body: d_body });
+                Ok(Expression::Let { name: fresh, value: Box::new(d_value), body })
+            }
+            Expression::If { cond, then, otherwise } => {
+                let d_then = self.child(Branch::Then, then)?;
+                let d_otherwise = self.child(Branch::Else, otherwise)?;
+                if is_zero(&d_then) && is_zero(&d_otherwise) {
+                    return Ok(zero());
+                }
+                let (then, otherwise) = (Box::new(d_then), Box::new(d_otherwise));
+                Ok(Expression::If { cond: cond.clone(), then, otherwise })
+            }
+            Expression::Call { name, args } => {
+                let mut d_args = Vec::new();
+                for (i, arg) in args.iter().enumerate() {
+                    d_args.push(self.child(Branch::Arg(i), arg)?);
+                }
+                let varies = d_args.iter().any(|d_arg| !is_zero(d_arg));
+                match self.function(name) {
+                    Some(false) if !varies => return Ok(zero()),
+                    None if !varies => return Ok(zero()),
+                    // the chain rule, for the two built-ins it knows
+                    None if args.len() == 1 && name == "ln" => {
+                        return Ok(div(d_args.remove(0), args[0].clone()));
+                    }
+                    None if args.len() == 1 && name == "exp" => {
+                        return Ok(mul(e.clone(), d_args.remove(0)));
+                    }
+                    _ => {}
+                }
+                Err(DeriveError::UnknownDerivative { name: name.clone(), path: self.error_path() })
+            }
+            Expression::Function { name, params, body, rest } => {
+                // the body itself is only differentiated through calls,
+                // which needs to know whether it depends on the variable
+                let mut values = params.clone();
+                let varies = self.varies(body, &mut values, &mut vec![name.clone()]);
+                self.scope.push(Local::Function(name, varies));
+                let d_rest = self.child(Branch::Rest, rest);
+                self.scope.pop();
+                let d_rest = d_rest?;
+                if is_zero(&d_rest) {
+                    return Ok(zero());
+                }
+                Ok(Expression::Function {
+                    name: name.clone(),
+                    params: params.clone(),
+                    body: body.clone(),
+                    rest: Box::new(d_rest),
+                })
+            }
+        }
+    }
+
+    fn operation(
+        &mut self,
+        op: Operation,
+        left: &'e Expression,
+        right: &'e Expression,
+    ) -> Result<Expression, DeriveError> {
+        let d_left = self.child(Branch::Left, left)?;
+        let d_right = self.child(Branch::Right, right)?;
+        let (u, v) = (|| left.clone(), || right.clone());
+        Ok(match op {
+            Operation::Add => add(d_left, d_right),
+            Operation::Sub => sub(d_left, d_right),
+            // (uv)' = u'v + uv'
+            Operation::Mul => add(mul(d_left, v()), mul(u(), d_right)),
+            // (u/v)' = (u'v - uv') / v²
+            Operation::Div if is_zero(&d_right) => div(d_left, v()),
+            Operation::Div => {
+                let numerator = sub(mul(d_left, v()), mul(u(), d_right));
+                div(numerator, pow(v(), Expression::Value(Number::Int(2))))
+            }
+            // (u^v)' = v u^(v - 1) u' for a constant exponent
+            Operation::Pow if is_zero(&d_right) => {
+                let one_less = binary(Operation::Sub, v(), Expression::Value(Number::Int(1)));
+                mul(mul(v(), pow(u(), one_less)), d_left)
+            }
+            // and u^v (v' ln u + v u' / u) otherwise
+            Operation::Pow => {
+                if self.function("ln").is_some() {
+                    // the `ln` the derivative calls would be that one
+                    let name = "ln".to_string();
+                    return Err(DeriveError::UnknownDerivative { name, path: self.error_path() });
+                }
+                let log = mul(d_right, call("ln", u()));
+                let power = div(mul(v(), d_left), u());
+                mul(pow(u(), v()), add(log, power))
+            }
+            // the rest are constant, except where they jump
+            _ if is_zero(&d_left) && is_zero(&d_right) => zero(),
+            _ => return Err(DeriveError::NotDifferentiable { op, path: self.error_path() }),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::epression_evaluation::{parser, Environment, Evaluator, Rational};
+
+    fn derived(input: &str, var: &str) -> Expression {
+        derive(&parser::parse(input).unwrap(), var).unwrap()
+    }
+
+    /// The derivative of `input` at `x`.
+    fn slope(input: &str, x: Number) -> Number {
+        let env = Environment::new().with("x", x).with("y", 10);
+        Evaluator::new().eval_in(&derived(input, "x"), &env).unwrap()
+    }
+
+    fn close(a: Number, b: f64) -> bool {
+        (a.to_f64() - b).abs() < 1e-9 * b.abs().max(1.0)
+    }
+
+    #[test]
+    fn rules() {
+        assert_eq!(derived("x ** 3 + 2 * x + 7", "x").to_string(), "x ** 2 * 3 + 2");
+        assert_eq!(derived("x * y", "x").to_string(), "y");
+        assert_eq!(derived("x * y", "y").to_string(), "x");
+        assert_eq!(derived("x - y", "y").to_string(), "-1");
+        assert_eq!(derived("y ** 2", "x").to_string(), "0");
+        assert_eq!(derived("x / (x + 1)", "x").to_string(), "(x + 1 - x) / (x + 1) ** 2");
+        assert_eq!(derived("x / 4", "x").to_string(), "1/4");
+    }
+
+    #[test]
+    fn values() {
+        let int = |n: i64| Number::Int(n);
+        // the power rule keeps exact fractions exact
+        assert_eq!(slope("x ** -1", int(2)), Number::Rational(Rational::new(-1, 4).unwrap()));
+        assert_eq!(slope("(x * x - 1) / (x + 1)", int(5)), int(1));
+        assert_eq!(slope("let a = x * x in a * a", int(2)), int(32));
+        assert_eq!(slope("let x = x * 3 in x * x", int(1)), int(18));
+        assert_eq!(slope("if x < 0 then -x else x * y", int(-3)), int(-1));
+        assert_eq!(slope("if x < 0 then -x else x * y", int(3)), int(10));
+        assert_eq!(slope("let sq(t) = t * t in sq(3) * x", int(1)), int(9));
+        assert!(close(slope("2 ** x", int(0)), 2f64.ln()));
+        assert!(close(slope("x ** x", int(2)), 4.0 * (2f64.ln() + 1.0)));
+        assert!(close(slope("ln(x * x)", int(4)), 0.5));
+        assert!(close(slope("exp(2 * x)", int(0)), 2.0));
+    }
+
+    #[test]
+    fn matches_finite_differences() {
+        let inputs = [
+            "x ** 4 - 3 * x ** 2 + x",
+            "(x + 1) * (x - 2) / (x * x + 3)",
+            "let u = x * y + 1 in u ** 3 / x",
+            "exp(x / 4) * ln(x + 10)",
+            "1.5 ** x - x ** 0.5",
+        ];
+        let h = 1e-6;
+        for input in inputs {
+            let e = parser::parse(input).unwrap();
+            for x in [0.5, 1.0, 2.5] {
+                let at = |x: f64| {
+                    let env = Environment::new().with("x", x).with("y", 0.25);
+                    Evaluator::new().eval_in(&e, &env).unwrap().to_f64()
+                };
+                let estimate = (at(x + h) - at(x - h)) / (2.0 * h);
+                let env = Environment::new().with("x", x).with("y", 0.25);
+                let exact = Evaluator::new().eval_in(&derived(input, "x"), &env).unwrap().to_f64();
+                let tolerance = 1e-5 * exact.abs().max(1.0);
+                assert!((exact - estimate).abs() < tolerance, "{input} at {x}: {exact} vs {estimate}");
+            }
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let error = |input| derive(&parser::parse(input).unwrap(), "x").unwrap_err();
+        let e = error("1 + (x & 3)");
+        let path = Path(vec![Branch::Right]);
+        assert_eq!(e, DeriveError::NotDifferentiable { op: Operation::BitAnd, path });
+        assert_eq!(e.to_string(), "& has no derivative at right");
+        assert_eq!(error("max(x, 1)").to_string(), "the derivative of max is not known at root");
+        let unknown = |name| format!("the derivative of {name} is not known at rest");
+        assert_eq!(error("let f(t) = t * x in f(2)").to_string(), unknown("f"));
+        assert_eq!(error("let ln(t) = t in 2 ** x").to_string(), unknown("ln"));
+
+        // all of these are fine as long as they do not depend on x
+        assert_eq!(derived("(y & 3) * x + max(y, 1) + (y < 2)", "x").to_string(), "y & 3");
+        assert_eq!(derived("let f(x) = x * 2 in f(3) * x", "x").to_string(), "let f(x) = x * 2 in f(3)");
+    }
+}
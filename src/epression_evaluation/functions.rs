@@ -0,0 +1,178 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+
+use super::number::{Number, Rational};
+
+/// How many arguments a function takes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Arity {
+    Exactly(usize),
+    AtLeast(usize),
+}
+
+impl Arity {
+    pub fn accepts(&self, count: usize) -> bool {
+        match self {
+            Arity::Exactly(n) => count == *n,
+            Arity::AtLeast(n) => count >= *n,
+        }
+    }
+}
+
+impl From<usize> for Arity {
+    fn from(count: usize) -> Self {
+        Arity::Exactly(count)
+    }
+}
+
+impl fmt::Display for Arity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (prefix, n) = match self {
+            Arity::Exactly(n) => ("", n),
+            Arity::AtLeast(n) => ("at least ", n),
+        };
+        let plural = if *n == 1 { "" } else { "s" };
+        write!(f, "{prefix}{n} argument{plural}")
+    }
+}
+
+/// A function implemented in Rust. It gets as many arguments as its arity
+/// allows, and can fail with a message for the user.
+pub type Native = Box<dyn Fn(&[Number]) -> Result<Number, String>>;
+
+/// The functions that expressions can call besides the ones they define
+/// themselves, by name.
+pub struct Functions {
+    natives: HashMap<String, (Arity, Native)>,
+}
+
+impl Functions {
+    /// A registry without any functions in it, not even the built-in ones.
+    pub fn new() -> Self {
+        Functions { natives: HashMap::new() }
+    }
+
+    /// The built-in functions: `abs(x)`, `min(a, ...)`, `max(a, ...)`,
+    /// `clamp(x, lo, hi)`, and the natural logarithm `ln(x)` and its
+    /// inverse `exp(x)`, which always give floats.
+    pub fn builtins() -> Self {
+        let mut functions = Functions::new();
+        functions.register("abs", 1, |args| abs(args[0]).ok_or_else(|| "overflow".to_string()));
+        functions.register("min", Arity::AtLeast(1), |args| Ok(extreme(args, Ordering::Less)));
+        functions.register("max", Arity::AtLeast(1), |args| Ok(extreme(args, Ordering::Greater)));
+        functions.register("clamp", 3, |args| {
+            let (x, lo, hi) = (args[0], args[1], args[2]);
+            if lo.compare(hi) == Some(Ordering::Greater) {
+                return Err("the lower bound is above the upper bound".to_string());
+            }
+            Ok(extreme(&[extreme(&[x, hi], Ordering::Less), lo], Ordering::Greater))
+        });
+        functions.register("ln", 1, |args| Ok(Number::Float(args[0].to_f64().ln())));
+        functions.register("exp", 1, |args| Ok(Number::Float(args[0].to_f64().exp())));
+        functions
+    }
+
+    /// Make `f` callable as `name`, replacing any function of that name.
+    /// Calls with an argument count that `arity` does not accept fail
+    /// before `f` is called.
+    pub fn register(
+        &mut self,
+        name: impl Into<String>,
+        arity: impl Into<Arity>,
+        f: impl Fn(&[Number]) -> Result<Number, String> + 'static,
+    ) {
+        self.natives.insert(name.into(), (arity.into(), Box::new(f)));
+    }
+
+    pub fn get(&self, name: &str) -> Option<&(Arity, Native)> {
+        self.natives.get(name)
+    }
+}
+
+impl Default for Functions {
+    fn default() -> Self {
+        Self::builtins()
+    }
+}
+
+impl fmt::Debug for Functions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut names: Vec<_> = self.natives.keys().collect();
+        names.sort();
+        f.debug_struct("Functions").field("natives", &names).finish()
+    }
+}
+
+fn abs(x: Number) -> Option<Number> {
+    match x {
+        Number::Int(val) => val.checked_abs().map(Number::Int),
+        Number::Float(val) => Some(Number::Float(val.abs())),
+        Number::Rational(val) => Some(Rational::new(val.numer().checked_abs()?, val.denom())?.into()),
+    }
+}
+
+/// The argument furthest in the direction of `wanted`, the first one if
+/// there is a tie. `NaN` if any argument is `NaN`.
+fn extreme(args: &[Number], wanted: Ordering) -> Number {
+    let mut best = args[0];
+    for &arg in &args[1..] {
+        match arg.compare(best) {
+            None => return Number::Float(f64::NAN),
+            Some(order) if order == wanted => best = arg,
+            Some(_) => {}
+        }
+    }
+    // a lone NaN is not compared with anything
+    if best.compare(best).is_none() {
+        return Number::Float(f64::NAN);
+    }
+    best
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn call(name: &str, args: &[i64]) -> Result<Number, String> {
+        let args: Vec<Number> = args.iter().map(|&arg| Number::Int(arg)).collect();
+        call_with(name, &args)
+    }
+
+    fn call_with(name: &str, args: &[Number]) -> Result<Number, String> {
+        let functions = Functions::builtins();
+        let (arity, f) = functions.get(name).unwrap();
+        assert!(arity.accepts(args.len()));
+        f(args)
+    }
+
+    #[test]
+    fn builtins() {
+        let half = Number::Rational(Rational::new(1, 2).unwrap());
+        assert_eq!(call("abs", &[-3]), Ok(Number::Int(3)));
+        assert_eq!(call_with("abs", &[Number::Rational(Rational::new(-1, 2).unwrap())]), Ok(half));
+        assert_eq!(call("abs", &[i64::MIN]), Err("overflow".to_string()));
+        assert_eq!(call_with("max", &[Number::Int(1), half, Number::Float(0.75)]), Ok(Number::Int(1)));
+        assert_eq!(call_with("min", &[Number::Int(1), half, Number::Float(0.75)]), Ok(half));
+        assert_eq!(call("max", &[7]), Ok(Number::Int(7)));
+        let nan = call_with("max", &[Number::Int(7), Number::Float(f64::NAN)]);
+        assert!(matches!(nan, Ok(Number::Float(x)) if x.is_nan()));
+        assert_eq!(call("clamp", &[12, 0, 10]), Ok(Number::Int(10)));
+        assert_eq!(call("clamp", &[-2, 0, 10]), Ok(Number::Int(0)));
+        assert_eq!(call_with("clamp", &[half, Number::Int(0), Number::Int(10)]), Ok(half));
+        assert!(call("clamp", &[1, 10, 0]).is_err());
+        assert_eq!(call("ln", &[1]), Ok(Number::Float(0.0)));
+        assert_eq!(call("exp", &[0]), Ok(Number::Float(1.0)));
+        assert!(matches!(call("ln", &[-1]), Ok(Number::Float(x)) if x.is_nan()));
+    }
+
+    #[test]
+    fn arity() {
+        assert!(Arity::Exactly(2).accepts(2));
+        assert!(!Arity::Exactly(2).accepts(3));
+        assert!(Arity::AtLeast(1).accepts(3));
+        assert!(!Arity::AtLeast(1).accepts(0));
+        assert_eq!(Arity::Exactly(1).to_string(), "1 argument");
+        assert_eq!(Arity::AtLeast(2).to_string(), "at least 2 arguments");
+    }
+}
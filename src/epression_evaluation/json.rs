@@ -0,0 +1,982 @@
+// A JSON form of expression trees for storing formulas in files, written
+// by hand as the project has no dependencies.
+//
+// A document is `{"version": 1, "expression": node}`, where a node is an
+// object whose "kind" says what the other fields are:
+//
+//   {"kind": "int", "value": 42}
+//   {"kind": "float", "value": 0.5}         "inf", "-inf" or "nan" too
+//   {"kind": "rational", "numer": 7, "denom": 2}
+//   {"kind": "variable", "name": "x"}
+//   {"kind": "op", "op": "add", "left": node, "right": node}
+//   {"kind": "let", "name": "x", "value": node, "body": node}
+//   {"kind": "if", "cond": node, "then": node, "otherwise": node}
+//   {"kind": "call", "name": "max", "args": [node, ...]}
+//   {"kind": "function", "name": "f", "params": ["x", ...], "body": node, "rest": node}
+//
+// Operations are named by `op_name`, not by their Rust names, so renaming
+// a variant does not change the format. Any change that older builds
+// could misread must bump `SCHEMA_VERSION`.
+
+use std::fmt;
+
+use super::{Expression, Number, Operation, Rational};
+
+/// The version of the schema this build writes. Documents with a higher
+/// version are rejected rather than half understood.
+pub const SCHEMA_VERSION: u64 = 1;
+
+/// Why a document could not be read.
+#[derive(Debug, PartialEq)]
+pub enum JsonError {
+    /// The input is not well-formed JSON.
+    Syntax { message: String, offset: usize },
+    /// The input is JSON, but not an expression in this schema. `at` says
+    /// where in the document, as in `expression.left.args[1]`.
+    Schema { message: String, at: String },
+    /// The document was written with a newer schema than this build knows.
+    UnsupportedVersion(u64),
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JsonError::Syntax { message, offset } => write!(f, "{message} at offset {offset}"),
+            JsonError::Schema { message, at } => write!(f, "{message} at {at}"),
+            JsonError::UnsupportedVersion(version) => write!(
+                f,
+                "schema version {version} is newer than the supported version {SCHEMA_VERSION}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for JsonError {}
+
+/// The stable name of an operation in documents.
+fn op_name(op: Operation) -> &'static str {
+    match op {
+        Operation::Add => "add",
+        Operation::Sub => "sub",
+        Operation::Mul => "mul",
+        Operation::Div => "div",
+        Operation::Rem => "rem",
+        Operation::Pow => "pow",
+        Operation::BitAnd => "bit_and",
+        Operation::BitOr => "bit_or",
+        Operation::BitXor => "bit_xor",
+        Operation::Shl => "shl",
+        Operation::Shr => "shr",
+        Operation::Lt => "lt",
+        Operation::Le => "le",
+        Operation::Gt => "gt",
+        Operation::Ge => "ge",
+        Operation::Eq => "eq",
+        Operation::Ne => "ne",
+        Operation::And => "and",
+        Operation::Or => "or",
+    }
+}
+
+const OPERATIONS: [Operation; 19] = [
+    Operation::Add,
+    Operation::Sub,
+    Operation::Mul,
+    Operation::Div,
+    Operation::Rem,
+    Operation::Pow,
+    Operation::BitAnd,
+    Operation::BitOr,
+    Operation::BitXor,
+    Operation::Shl,
+    Operation::Shr,
+    Operation::Lt,
+    Operation::Le,
+    Operation::Gt,
+    Operation::Ge,
+    Operation::Eq,
+    Operation::Ne,
+    Operation::And,
+    Operation::Or,
+];
+
+impl Expression {
+    /// This tree as a JSON document, on a single line. The same tree always
+    /// gives the same text, and `from_json` reads it back into an equal
+    /// tree.
+    pub fn to_json(&self) -> String {
+        let mut out = format!("{{\"version\":{SCHEMA_VERSION},\"expression\":");
+        encode(self, &mut out);
+        out.push('}');
+        out
+    }
+
+    /// Read a document written by `to_json`, by this build or an older one.
+    /// Whitespace and the order of fields do not matter, but unknown fields
+    /// are errors.
+    pub fn from_json(input: &str) -> Result<Expression, JsonError> {
+        let mut reader = Reader { input, pos: 0 };
+        let document = reader.document()?;
+        reader.skip_whitespace();
+        if reader.pos < input.len() {
+            return Err(reader.error("trailing characters after the document"));
+        }
+
+        let schema = |message| JsonError::Schema { message, at: "document".to_string() };
+        let mut fields = Fields::new(document).map_err(schema)?;
+        let version = fields.int("version").map_err(schema)?;
+        match u64::try_from(version) {
+            Ok(version) if version > SCHEMA_VERSION => return Err(JsonError::UnsupportedVersion(version)),
+            Ok(version) if version > 0 => {}
+            _ => return Err(schema(format!("invalid version {version}"))),
+        }
+        let e = fields.take("expression").map_err(schema)?;
+        fields.finish().map_err(schema)?;
+        decode(e)
+    }
+}
+
+/// Part of a document that is still to be written.
+enum Emit<'e> {
+    Node(&'e Expression),
+    Text(&'static str),
+}
+
+// Encoding, reading and decoding keep their own stacks instead of
+// recursing once per level, like `Evaluator` does, so a tree of any depth
+// makes the round trip.
+
+fn encode(e: &Expression, out: &mut String) {
+    let mut pending = vec![Emit::Node(e)];
+    while let Some(emit) = pending.pop() {
+        let e = match emit {
+            Emit::Node(e) => e,
+            Emit::Text(text) => {
+                out.push_str(text);
+                continue;
+            }
+        };
+        // the start of the node is written straight away, and what follows
+        // it is pushed last part first
+        match e {
+            Expression::Value(Number::Int(val)) => {
+                out.push_str(&format!("{{\"kind\":\"int\",\"value\":{val}}}"));
+            }
+            Expression::Value(Number::Float(val)) => {
+                let value = match val {
+                    val if val.is_nan() => "\"nan\"".to_string(),
+                    val if val.is_infinite() && *val > 0.0 => "\"inf\"".to_string(),
+                    val if val.is_infinite() => "\"-inf\"".to_string(),
+                    // `Debug` gives the shortest text that reads back as the
+                    // same float, and it is valid JSON for finite floats
+                    val => format!("{val:?}"),
+                };
+                out.push_str(&format!("{{\"kind\":\"float\",\"value\":{value}}}"));
+            }
+            Expression::Value(Number::Rational(val)) => out.push_str(&format!(
+                "{{\"kind\":\"rational\",\"numer\":{},\"denom\":{}}}",
+                val.numer(),
+                val.denom()
+            )),
+            Expression::Variable(name) => {
+                out.push_str("{\"kind\":\"variable\",\"name\":");
+                string(name, out);
+                out.push('}');
+            }
+            Expression::Op { op, left, right } => {
+                out.push_str(&format!("{{\"kind\":\"op\",\"op\":\"{}\",\"left\":", op_name(*op)));
+                pending.extend([
+                    Emit::Text("}"),
+                    Emit::Node(right),
+                    Emit::Text(",\"right\":"),
+                    Emit::Node(left),
+                ]);
+            }
+            Expression::Let { name, value, body } => {
+                out.push_str("{\"kind\":\"let\",\"name\":");
+                string(name, out);
+                out.push_str(",\"value\":");
+                pending.extend([
+                    Emit::Text("}"),
+                    Emit::Node(body),
+                    Emit::Text(",\"body\":"),
+                    Emit::Node(value),
+                ]);
+            }
+            Expression::If { cond, then, otherwise } => {
+                out.push_str("{\"kind\":\"if\",\"cond\":");
+                pending.extend([
+                    Emit::Text("}"),
+                    Emit::Node(otherwise),
+                    Emit::Text(",\"otherwise\":"),
+                    Emit::Node(then),
+                    Emit::Text(",\"then\":"),
+                    Emit::Node(cond),
+                ]);
+            }
+            Expression::Call { name, args } => {
+                out.push_str("{\"kind\":\"call\",\"name\":");
+                string(name, out);
+                out.push_str(",\"args\":[");
+                pending.push(Emit::Text("]}"));
+                for (i, arg) in args.iter().enumerate().rev() {
+                    pending.push(Emit::Node(arg));
+                    if i > 0 {
+                        pending.push(Emit::Text(","));
+                    }
+                }
+            }
+            Expression::Function { name, params, body, rest } => {
+                out.push_str("{\"kind\":\"function\",\"name\":");
+                string(name, out);
+                out.push_str(",\"params\":[");
+                for (i, param) in params.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    string(param, out);
+                }
+                out.push_str("],\"body\":");
+                pending.extend([
+                    Emit::Text("}"),
+                    Emit::Node(rest),
+                    Emit::Text(",\"rest\":"),
+                    Emit::Node(body),
+                ]);
+            }
+        }
+    }
+}
+
+/// Write `s` as a JSON string literal.
+fn string(s: &str, out: &mut String) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// A parsed JSON value. Numbers keep their text, so that integers are read
+/// exactly rather than through an `f64`.
+#[derive(Debug)]
+enum Json {
+    Null,
+    Bool(bool),
+    Number(String),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// What kind of value this is, for error messages.
+    fn describe(&self) -> &'static str {
+        match self {
+            Json::Null => "null",
+            Json::Bool(true) => "true",
+            Json::Bool(false) => "false",
+            Json::Number(_) => "a number",
+            Json::String(_) => "a string",
+            Json::Array(_) => "an array",
+            Json::Object(_) => "an object",
+        }
+    }
+
+    /// Move the values in this array or object out into `into`.
+    fn take_children(&mut self, into: &mut Vec<Json>) {
+        match self {
+            Json::Array(items) => into.append(items),
+            Json::Object(fields) => into.extend(fields.drain(..).map(|(_, value)| value)),
+            _ => {}
+        }
+    }
+}
+
+// as with `Expression`, the drop glue would recurse once per level, and a
+// document that fails to decode may be nested arbitrarily deep
+impl Drop for Json {
+    fn drop(&mut self) {
+        let mut pending = Vec::new();
+        self.take_children(&mut pending);
+        while let Some(mut json) = pending.pop() {
+            json.take_children(&mut pending);
+        }
+    }
+}
+
+/// An array or object that has been opened but not closed yet while
+/// reading, with what is in it so far.
+enum Open {
+    Array(Vec<Json>),
+    /// Along with the key of the value being read.
+    Object(Vec<(String, Json)>, String),
+}
+
+struct Reader<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn error(&self, message: impl Into<String>) -> JsonError {
+        JsonError::Syntax { message: message.into(), offset: self.pos }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.input.as_bytes().get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), JsonError> {
+        self.skip_whitespace();
+        if self.peek() != Some(byte) {
+            return Err(self.unexpected());
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn unexpected(&self) -> JsonError {
+        match self.input[self.pos..].chars().next() {
+            Some(c) => self.error(format!("unexpected character {c:?}")),
+            None => self.error("unexpected end of input"),
+        }
+    }
+
+    fn document(&mut self) -> Result<Json, JsonError> {
+        let mut open = Vec::new();
+        loop {
+            self.skip_whitespace();
+            let mut value = match self.peek() {
+                Some(b'{') => {
+                    self.pos += 1;
+                    self.skip_whitespace();
+                    if self.peek() != Some(b'}') {
+                        open.push(Open::Object(Vec::new(), self.key()?));
+                        continue;
+                    }
+                    self.pos += 1;
+                    Json::Object(Vec::new())
+                }
+                Some(b'[') => {
+                    self.pos += 1;
+                    self.skip_whitespace();
+                    if self.peek() != Some(b']') {
+                        open.push(Open::Array(Vec::new()));
+                        continue;
+                    }
+                    self.pos += 1;
+                    Json::Array(Vec::new())
+                }
+                Some(b'"') => Json::String(self.string()?),
+                Some(b'-' | b'0'..=b'9') => self.number()?,
+                _ => self.word()?,
+            };
+
+            // the value goes into the innermost open array or object, which
+            // may be complete with it, and so on outwards
+            loop {
+                let Some(container) = open.last_mut() else {
+                    return Ok(value);
+                };
+                match container {
+                    Open::Array(items) => items.push(value),
+                    Open::Object(fields, key) => fields.push((std::mem::take(key), value)),
+                }
+                self.skip_whitespace();
+                match (self.peek(), container) {
+                    (Some(b','), Open::Array(_)) => {
+                        self.pos += 1;
+                        break;
+                    }
+                    (Some(b','), Open::Object(_, key)) => {
+                        self.pos += 1;
+                        *key = self.key()?;
+                        break;
+                    }
+                    (Some(b']'), Open::Array(_)) | (Some(b'}'), Open::Object(..)) => {
+                        self.pos += 1;
+                        value = match open.pop() {
+                            Some(Open::Array(items)) => Json::Array(items),
+                            Some(Open::Object(fields, _)) => Json::Object(fields),
+                            None => unreachable!("there was an open container"),
+                        };
+                    }
+                    _ => return Err(self.unexpected()),
+                }
+            }
+        }
+    }
+
+    /// The key of an object field, and the colon after it.
+    fn key(&mut self) -> Result<String, JsonError> {
+        self.skip_whitespace();
+        if self.peek() != Some(b'"') {
+            return Err(self.unexpected());
+        }
+        let key = self.string()?;
+        self.expect(b':')?;
+        Ok(key)
+    }
+
+    /// `null`, `true` or `false`.
+    fn word(&mut self) -> Result<Json, JsonError> {
+        let words = [("null", Json::Null), ("true", Json::Bool(true)), ("false", Json::Bool(false))];
+        for (word, value) in words {
+            if self.input[self.pos..].starts_with(word) {
+                self.pos += word.len();
+                return Ok(value);
+            }
+        }
+        Err(self.unexpected())
+    }
+
+    /// A number, checked against the JSON grammar: no leading zeros, no
+    /// leading `+`, and digits on both sides of a `.`.
+    fn number(&mut self) -> Result<Json, JsonError> {
+        let start = self.pos;
+        let digits = |reader: &mut Self| {
+            let from = reader.pos;
+            while let Some(b'0'..=b'9') = reader.peek() {
+                reader.pos += 1;
+            }
+            reader.pos - from
+        };
+        if self.peek() == Some(b'-') {
+            self.pos += 1;
+        }
+        let integer_start = self.pos;
+        match digits(self) {
+            0 => return Err(self.unexpected()),
+            n if n > 1 && self.input.as_bytes()[integer_start] == b'0' => {
+                let message = "leading zero in a number".to_string();
+                return Err(JsonError::Syntax { message, offset: start });
+            }
+            _ => {}
+        }
+        if self.peek() == Some(b'.') {
+            self.pos += 1;
+            if digits(self) == 0 {
+                return Err(self.unexpected());
+            }
+        }
+        if let Some(b'e' | b'E') = self.peek() {
+            self.pos += 1;
+            if let Some(b'+' | b'-') = self.peek() {
+                self.pos += 1;
+            }
+            if digits(self) == 0 {
+                return Err(self.unexpected());
+            }
+        }
+        Ok(Json::Number(self.input[start..self.pos].to_string()))
+    }
+
+    /// A string literal, starting at its opening quote.
+    fn string(&mut self) -> Result<String, JsonError> {
+        self.pos += 1;
+        let mut s = String::new();
+        loop {
+            let Some(c) = self.input[self.pos..].chars().next() else {
+                return Err(self.error("unterminated string"));
+            };
+            match c {
+                '"' => {
+                    self.pos += 1;
+                    return Ok(s);
+                }
+                '\\' => {
+                    self.pos += 1;
+                    let escaped = match self.peek() {
+                        Some(b'"') => '"',
+                        Some(b'\\') => '\\',
+                        Some(b'/') => '/',
+                        Some(b'b') => '\u{8}',
+                        Some(b'f') => '\u{c}',
+                        Some(b'n') => '\n',
+                        Some(b'r') => '\r',
+                        Some(b't') => '\t',
+                        Some(b'u') => {
+                            self.pos += 1;
+                            s.push(self.unicode_escape()?);
+                            continue;
+                        }
+                        _ => return Err(self.error("invalid escape")),
+                    };
+                    self.pos += 1;
+                    s.push(escaped);
+                }
+                c if (c as u32) < 0x20 => return Err(self.error("control character in a string")),
+                c => {
+                    self.pos += c.len_utf8();
+                    s.push(c);
+                }
+            }
+        }
+    }
+
+    /// The character of a `\u` escape, just after the `u`. Characters
+    /// outside the basic plane are written as two escapes, a surrogate
+    /// pair.
+    fn unicode_escape(&mut self) -> Result<char, JsonError> {
+        let high = self.hex4()?;
+        let code = match high {
+            0xD800..=0xDBFF => {
+                if !self.input[self.pos..].starts_with("\\u") {
+                    return Err(self.error("unpaired surrogate"));
+                }
+                self.pos += 2;
+                let low = self.hex4()?;
+                if !(0xDC00..=0xDFFF).contains(&low) {
+                    return Err(self.error("unpaired surrogate"));
+                }
+                0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00)
+            }
+            0xDC00..=0xDFFF => return Err(self.error("unpaired surrogate")),
+            code => code,
+        };
+        Ok(char::from_u32(code).expect("surrogates are handled above"))
+    }
+
+    fn hex4(&mut self) -> Result<u32, JsonError> {
+        let hex = self.input.get(self.pos..self.pos + 4);
+        let Some(hex) = hex.filter(|hex| hex.bytes().all(|b| b.is_ascii_hexdigit())) else {
+            return Err(self.error("invalid \\u escape"));
+        };
+        self.pos += 4;
+        Ok(u32::from_str_radix(hex, 16).expect("checked to be hex digits"))
+    }
+}
+
+/// The fields of an object being turned into a node, taken out one by one
+/// so that whatever is left over at the end is known to be unexpected.
+/// Errors are only the message, the caller knows where the object is.
+struct Fields {
+    fields: Vec<(String, Json)>,
+}
+
+impl Fields {
+    fn new(mut json: Json) -> Result<Self, String> {
+        let Json::Object(fields) = &mut json else {
+            return Err(format!("expected an object, found {}", json.describe()));
+        };
+        let fields = std::mem::take(fields);
+        for (i, (key, _)) in fields.iter().enumerate() {
+            if fields[..i].iter().any(|(other, _)| other == key) {
+                return Err(format!("duplicate field {key:?}"));
+            }
+        }
+        Ok(Fields { fields })
+    }
+
+    /// The field `key` holds the wrong kind of value.
+    fn mismatch(key: &str, wanted: &str, found: &Json) -> String {
+        format!("expected {key:?} to be {wanted}, found {}", found.describe())
+    }
+
+    fn take(&mut self, key: &str) -> Result<Json, String> {
+        match self.fields.iter().position(|(k, _)| k == key) {
+            Some(i) => Ok(self.fields.remove(i).1),
+            None => Err(format!("missing field {key:?}")),
+        }
+    }
+
+    /// Fail if there are fields that were never asked for.
+    fn finish(self) -> Result<(), String> {
+        match self.fields.first() {
+            Some((key, _)) => Err(format!("unknown field {key:?}")),
+            None => Ok(()),
+        }
+    }
+
+    fn string(&mut self, key: &str) -> Result<String, String> {
+        match &mut self.take(key)? {
+            Json::String(s) => Ok(std::mem::take(s)),
+            other => Err(Self::mismatch(key, "a string", other)),
+        }
+    }
+
+    fn int(&mut self, key: &str) -> Result<i64, String> {
+        match &self.take(key)? {
+            Json::Number(text) => text.parse().map_err(|_| format!("{key:?} is not an integer: {text}")),
+            other => Err(Self::mismatch(key, "a number", other)),
+        }
+    }
+
+    fn float(&mut self, key: &str) -> Result<f64, String> {
+        match &self.take(key)? {
+            // every well-formed JSON number parses, if need be to infinity
+            Json::Number(text) => Ok(text.parse().expect("JSON numbers are valid floats")),
+            Json::String(text) => match text.as_str() {
+                "inf" => Ok(f64::INFINITY),
+                "-inf" => Ok(f64::NEG_INFINITY),
+                "nan" => Ok(f64::NAN),
+                _ => Err(format!("{key:?} is not a float: {text:?}")),
+            },
+            other => Err(Self::mismatch(key, "a number", other)),
+        }
+    }
+
+    fn array(&mut self, key: &str) -> Result<Vec<Json>, String> {
+        match &mut self.take(key)? {
+            Json::Array(items) => Ok(std::mem::take(items)),
+            other => Err(Self::mismatch(key, "an array", other)),
+        }
+    }
+
+    /// The nodes in the fields `keys`, in that order.
+    fn nodes(&mut self, keys: &[&'static str]) -> Result<Vec<(Step, Json)>, String> {
+        keys.iter().map(|&key| Ok((Step::Field(key), self.take(key)?))).collect()
+    }
+}
+
+/// How a node is reached from its parent.
+enum Step {
+    Field(&'static str),
+    Arg(usize),
+}
+
+/// Where the nodes being decoded are in the document: each is the index of
+/// its parent's place and the step from there, the first being the root.
+/// The path is only spelled out for an error, doing it for every node would
+/// take time and memory quadratic in the depth.
+struct Places(Vec<(usize, Step)>);
+
+impl Places {
+    fn describe(&self, mut place: usize) -> String {
+        let mut steps = Vec::new();
+        while place > 0 {
+            let (parent, step) = &self.0[place];
+            steps.push(step);
+            place = *parent;
+        }
+        let mut at = "expression".to_string();
+        for step in steps.into_iter().rev() {
+            match step {
+                Step::Field(key) => at.push_str(&format!(".{key}")),
+                Step::Arg(i) => at.push_str(&format!(".args[{i}]")),
+            }
+        }
+        at
+    }
+}
+
+/// A node whose children are being decoded, short of its children.
+enum Shape {
+    Op(Operation),
+    Let(String),
+    If,
+    Call(String, usize),
+    Function(String, Vec<String>),
+}
+
+impl Shape {
+    /// The node, with the last of `built` as its children, which are taken
+    /// off it.
+    fn build(self, built: &mut Vec<Expression>) -> Expression {
+        let count = match &self {
+            Shape::If => 3,
+            Shape::Call(_, args) => *args,
+            _ => 2,
+        };
+        let mut children = built.split_off(built.len() - count).into_iter();
+        if let Shape::Call(name, _) = self {
+            return Expression::Call { name, args: children.collect() };
+        }
+        let mut next = || Box::new(children.next().unwrap());
+        match self {
+            Shape::Op(op) => Expression::Op { op, left: next(), right: next() },
+            Shape::Let(name) => Expression::Let { name, value: next(), body: next() },
+            Shape::If => Expression::If { cond: next(), then: next(), otherwise: next() },
+            Shape::Function(name, params) => {
+                Expression::Function { name, params, body: next(), rest: next() }
+            }
+            Shape::Call(..) => unreachable!(),
+        }
+    }
+}
+
+/// What one object of the document decodes to.
+enum Node {
+    Leaf(Expression),
+    /// A node with children still to decode, and where they are.
+    Branch(Shape, Vec<(Step, Json)>),
+}
+
+fn node(json: Json) -> Result<Node, String> {
+    let mut fields = Fields::new(json)?;
+    let kind = fields.string("kind")?;
+    let node = match kind.as_str() {
+        "int" => Node::Leaf(Expression::Value(Number::Int(fields.int("value")?))),
+        "float" => Node::Leaf(Expression::Value(Number::Float(fields.float("value")?))),
+        "rational" => {
+            let (numer, denom) = (fields.int("numer")?, fields.int("denom")?);
+            match Rational::new(numer, denom) {
+                Some(val) => Node::Leaf(Expression::Value(val.into())),
+                None => return Err(format!("invalid fraction {numer}/{denom}")),
+            }
+        }
+        "variable" => Node::Leaf(Expression::Variable(fields.string("name")?)),
+        "op" => {
+            let name = fields.string("op")?;
+            let Some(op) = OPERATIONS.into_iter().find(|op| op_name(*op) == name) else {
+                return Err(format!("unknown operation {name:?}"));
+            };
+            Node::Branch(Shape::Op(op), fields.nodes(&["left", "right"])?)
+        }
+        "let" => {
+            let name = fields.string("name")?;
+            Node::Branch(Shape::Let(name), fields.nodes(&["value", "body"])?)
+        }
+        "if" => Node::Branch(Shape::If, fields.nodes(&["cond", "then", "otherwise"])?),
+        "call" => {
+            let name = fields.string("name")?;
+            let args = fields.array("args")?;
+            let shape = Shape::Call(name, args.len());
+            let args = args.into_iter().enumerate().map(|(i, arg)| (Step::Arg(i), arg));
+            Node::Branch(shape, args.collect())
+        }
+        "function" => {
+            let name = fields.string("name")?;
+            let mut params = Vec::new();
+            for mut param in fields.array("params")? {
+                match &mut param {
+                    Json::String(param) => params.push(std::mem::take(param)),
+                    other => return Err(Fields::mismatch("params", "an array of names", other)),
+                }
+            }
+            Node::Branch(Shape::Function(name, params), fields.nodes(&["body", "rest"])?)
+        }
+        _ => return Err(format!("unknown kind {kind:?}")),
+    };
+    fields.finish()?;
+    Ok(node)
+}
+
+/// Decode the node at the root of a document.
+fn decode(json: Json) -> Result<Expression, JsonError> {
+    enum Task {
+        /// Decode the node at a place, leaving it on top of `built`.
+        Decode(Json, usize),
+        /// Its children are the last ones built.
+        Build(Shape),
+    }
+
+    let mut places = Places(vec![(0, Step::Field("expression"))]);
+    let mut tasks = vec![Task::Decode(json, 0)];
+    let mut built = Vec::new();
+    while let Some(task) = tasks.pop() {
+        match task {
+            Task::Decode(json, place) => {
+                let schema = |message| JsonError::Schema { message, at: places.describe(place) };
+                match node(json).map_err(schema)? {
+                    Node::Leaf(e) => built.push(e),
+                    Node::Branch(shape, children) => {
+                        tasks.push(Task::Build(shape));
+                        // in reverse, so that the first child is decoded first
+                        for (step, child) in children.into_iter().rev() {
+                            places.0.push((place, step));
+                            tasks.push(Task::Decode(child, places.0.len() - 1));
+                        }
+                    }
+                }
+            }
+            Task::Build(shape) => {
+                let e = shape.build(&mut built);
+                built.push(e);
+            }
+        }
+    }
+    Ok(built.pop().expect("decoding left no node"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::epression_evaluation::parser;
+    use crate::epression_evaluation::random::Rng;
+
+    fn schema_error(input: &str) -> String {
+        match Expression::from_json(input) {
+            Err(error @ JsonError::Schema { .. }) => error.to_string(),
+            other => panic!("expected a schema error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn stable_format() {
+        let e = parser::parse("let f(x) = max(x, 0.5) in if a < 7 / 2 then f(a) else -1").unwrap();
+        let json = concat!(
+            r#"{"version":1,"expression":{"kind":"function","name":"f","params":["x"],"#,
+            r#""body":{"kind":"call","name":"max","args":[{"kind":"variable","name":"x"},"#,
+            r#"{"kind":"float","value":0.5}]},"rest":{"kind":"if","#,
+            r#""cond":{"kind":"op","op":"lt","left":{"kind":"variable","name":"a"},"#,
+            r#""right":{"kind":"op","op":"div","#,
+            r#""left":{"kind":"int","value":7},"right":{"kind":"int","value":2}}},"#,
+            r#""then":{"kind":"call","name":"f","args":[{"kind":"variable","name":"a"}]},"#,
+            r#""otherwise":{"kind":"int","value":-1}}}}"#,
+        );
+        assert_eq!(e.to_json(), json);
+        assert_eq!(Expression::from_json(json), Ok(e));
+    }
+
+    #[test]
+    fn values() {
+        let values = [
+            Number::Int(i64::MIN),
+            Number::Float(-0.0),
+            Number::Float(f64::MAX),
+            Number::Float(f64::INFINITY),
+            Number::Float(f64::NEG_INFINITY),
+            Number::Rational(Rational::new(-7, 2).unwrap()),
+        ];
+        for val in values {
+            let json = Expression::Value(val).to_json();
+            assert_eq!(Expression::from_json(&json), Ok(Expression::Value(val)), "{json}");
+        }
+        let json = Expression::Value(Number::Float(f64::NAN)).to_json();
+        assert!(json.contains(r#""value":"nan""#));
+        let decoded = Expression::from_json(&json);
+        assert!(matches!(decoded, Ok(Expression::Value(Number::Float(x))) if x.is_nan()));
+    }
+
+    #[test]
+    fn any_layout() {
+        let json = r#"
+            { "expression": { "right": {"value": 10, "kind": "int"}, "op": "mul",
+                              "left": {"name": "café \"😀\"", "kind": "variable"},
+                              "kind": "op" },
+              "version": 1 }
+        "#;
+        let e = Expression::from_json(json).unwrap();
+        let Expression::Op { left, .. } = &e else { panic!("{e:?}") };
+        assert_eq!(**left, Expression::Variable("café \"😀\"".to_string()));
+        assert_eq!(Expression::from_json(&e.to_json()), Ok(e));
+    }
+
+    #[test]
+    fn syntax_errors() {
+        let error = |input| match Expression::from_json(input) {
+            Err(JsonError::Syntax { message, offset }) => (message, offset),
+            other => panic!("expected a syntax error for {input}, got {other:?}"),
+        };
+        assert_eq!(error(""), ("unexpected end of input".to_string(), 0));
+        assert_eq!(error(r#"{"version": 1,}"#), ("unexpected character '}'".to_string(), 14));
+        assert_eq!(error(r#"{"version": 01}"#), ("leading zero in a number".to_string(), 12));
+        assert_eq!(error(r#"{"a": "\q"}"#), ("invalid escape".to_string(), 8));
+        assert_eq!(error(r#"{"a": "\ud800"}"#), ("unpaired surrogate".to_string(), 13));
+        assert_eq!(error("{} {}"), ("trailing characters after the document".to_string(), 3));
+    }
+
+    #[test]
+    fn schema_errors() {
+        let document = |node: &str| format!(r#"{{"version":1,"expression":{node}}}"#);
+        assert_eq!(schema_error("[]"), "expected an object, found an array at document");
+        assert_eq!(schema_error(r#"{"expression":{}}"#), "missing field \"version\" at document");
+        assert_eq!(schema_error(&document("{}")), "missing field \"kind\" at expression");
+        assert_eq!(
+            schema_error(&document(r#"{"kind":"int","value":1.5}"#)),
+            "\"value\" is not an integer: 1.5 at expression"
+        );
+        assert_eq!(
+            schema_error(&document(r#"{"kind":"variable","name":true}"#)),
+            "expected \"name\" to be a string, found true at expression"
+        );
+        assert_eq!(
+            schema_error(&document(r#"{"kind":"int","value":1,"extra":true}"#)),
+            "unknown field \"extra\" at expression"
+        );
+        assert_eq!(
+            schema_error(&document(r#"{"kind":"call","name":"f","args":[{"kind":"nope"}]}"#)),
+            "unknown kind \"nope\" at expression.args[0]"
+        );
+        assert_eq!(
+            schema_error(&document(concat!(
+                r#"{"kind":"op","op":"add","left":{"kind":"int","value":1},"#,
+                r#""right":{"kind":"op","op":"plus"}}"#,
+            ))),
+            "unknown operation \"plus\" at expression.right"
+        );
+        assert_eq!(
+            schema_error(&document(r#"{"kind":"rational","numer":1,"denom":0}"#)),
+            "invalid fraction 1/0 at expression"
+        );
+        assert_eq!(
+            schema_error(r#"{"version":1,"version":1,"expression":{}}"#),
+            "duplicate field \"version\" at document"
+        );
+    }
+
+    #[test]
+    fn versions() {
+        let newer = r#"{"version":2,"expression":{"kind":"int","value":1}}"#;
+        assert_eq!(Expression::from_json(newer), Err(JsonError::UnsupportedVersion(2)));
+        assert_eq!(
+            Expression::from_json(newer).unwrap_err().to_string(),
+            "schema version 2 is newer than the supported version 1"
+        );
+        assert_eq!(schema_error(r#"{"version":0,"expression":{}}"#), "invalid version 0 at document");
+    }
+
+    #[test]
+    fn deep_documents() {
+        // nested far deeper than the stack could take if anything recursed
+        const DEPTH: usize = 1_000_000;
+        let brackets = format!("{}1{}", "[".repeat(DEPTH), "]".repeat(DEPTH));
+        let document = format!(r#"{{"version":1,"expression":{brackets}}}"#);
+        assert_eq!(schema_error(&document), "expected an object, found an array at expression");
+        let error = Expression::from_json(&format!("{brackets} x")).unwrap_err();
+        assert!(matches!(error, JsonError::Syntax { .. }), "{error}");
+        let unclosed = Expression::from_json(&"[{\"a\":".repeat(DEPTH)).unwrap_err();
+        assert_eq!(unclosed.to_string(), format!("unexpected end of input at offset {}", 6 * DEPTH));
+
+        // a deep tree makes the round trip
+        let (int, x) = (|val| Box::new(Expression::Value(Number::Int(val))), || "x".to_string());
+        let mut e = *int(1);
+        for i in 0..100_000 {
+            e = match i % 3 {
+                0 => Expression::Op { op: Operation::Add, left: Box::new(e), right: int(1) },
+                1 => {
+                    Expression::Call { name: "abs".to_string(), args: vec![Expression::Variable(x()), e] }
+                }
+                _ => Expression::Let { name: x(), value: int(2), body: Box::new(e) },
+            };
+        }
+        let json = e.to_json();
+        let decoded = Expression::from_json(&json).unwrap();
+        assert!(decoded == e);
+        assert_eq!(decoded.to_json(), json);
+
+        // and errors deep inside say where they are
+        let broken = json.replacen(r#""value":1}"#, r#""value":true}"#, 1);
+        let error = schema_error(&broken);
+        let at = format!("expression{}", ".left.body.args[1]".repeat(33_333));
+        assert_eq!(error, format!("expected \"value\" to be a number, found true at {at}.left"));
+    }
+
+    #[test]
+    fn round_trips() {
+        let mut rng = Rng::new(11);
+        for _ in 0..5_000 {
+            let e = rng.expression(5);
+            let json = e.to_json();
+            let decoded = Expression::from_json(&json).unwrap();
+            assert_eq!(decoded.to_json(), json);
+            assert_eq!(decoded, e, "{json}");
+        }
+    }
+}
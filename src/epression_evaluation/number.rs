@@ -0,0 +1,285 @@
+use std::cmp::Ordering;
+use std::fmt;
+
+/// An exact fraction, always kept in lowest terms with a positive
+/// denominator. The arithmetic works on `i128`s internally, so it only
+/// fails when the reduced result itself does not fit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rational {
+    numer: i64,
+    denom: i64,
+}
+
+fn gcd(mut a: u128, mut b: u128) -> u128 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+impl Rational {
+    /// `numer / denom` in lowest terms, or `None` if `denom` is zero or the
+    /// result does not fit in `i64`s.
+    pub fn new(numer: i64, denom: i64) -> Option<Self> {
+        Self::reduce(numer.into(), denom.into())
+    }
+
+    fn reduce(numer: i128, denom: i128) -> Option<Self> {
+        if denom == 0 {
+            return None;
+        }
+        // the gcd is at least 1 here, as the denominator is not zero
+        let divisor = gcd(numer.unsigned_abs(), denom.unsigned_abs()) as i128;
+        let (mut numer, mut denom) = (numer / divisor, denom / divisor);
+        if denom < 0 {
+            (numer, denom) = (-numer, -denom);
+        }
+        Some(Rational { numer: numer.try_into().ok()?, denom: denom.try_into().ok()? })
+    }
+
+    pub fn numer(&self) -> i64 {
+        self.numer
+    }
+
+    pub fn denom(&self) -> i64 {
+        self.denom
+    }
+
+    pub fn checked_add(self, other: Self) -> Option<Self> {
+        let (a, b) = (self.wide(), other.wide());
+        Self::reduce(a.0 * b.1 + b.0 * a.1, a.1 * b.1)
+    }
+
+    pub fn checked_sub(self, other: Self) -> Option<Self> {
+        let (a, b) = (self.wide(), other.wide());
+        Self::reduce(a.0 * b.1 - b.0 * a.1, a.1 * b.1)
+    }
+
+    pub fn checked_mul(self, other: Self) -> Option<Self> {
+        let (a, b) = (self.wide(), other.wide());
+        Self::reduce(a.0 * b.0, a.1 * b.1)
+    }
+
+    /// `None` when dividing by zero as well as on overflow.
+    pub fn checked_div(self, other: Self) -> Option<Self> {
+        let (a, b) = (self.wide(), other.wide());
+        Self::reduce(a.0 * b.1, a.1 * b.0)
+    }
+
+    /// The remainder of truncating division, with the sign of `self`.
+    pub fn checked_rem(self, other: Self) -> Option<Self> {
+        // over a common denominator this is the remainder of the numerators
+        let (a, b) = (self.wide(), other.wide());
+        let divisor = b.0 * a.1;
+        if divisor == 0 {
+            return None;
+        }
+        Self::reduce((a.0 * b.1) % divisor, a.1 * b.1)
+    }
+
+    /// `self` to an integer power. A negative exponent takes the
+    /// reciprocal, which fails for zero.
+    pub fn checked_pow(self, exp: i64) -> Option<Self> {
+        let mut base = if exp < 0 { Rational { numer: 1, denom: 1 }.checked_div(self)? } else { self };
+        let mut exp = exp.unsigned_abs();
+        let mut result = Rational { numer: 1, denom: 1 };
+        while exp > 0 {
+            if exp & 1 == 1 {
+                result = result.checked_mul(base)?;
+            }
+            exp >>= 1;
+            if exp > 0 {
+                base = base.checked_mul(base)?;
+            }
+        }
+        Some(result)
+    }
+
+    pub fn to_f64(self) -> f64 {
+        self.numer as f64 / self.denom as f64
+    }
+
+    fn wide(self) -> (i128, i128) {
+        (self.numer.into(), self.denom.into())
+    }
+}
+
+impl From<i64> for Rational {
+    fn from(value: i64) -> Self {
+        Rational { numer: value, denom: 1 }
+    }
+}
+
+impl Ord for Rational {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // the denominators are positive, so cross multiplying keeps the order
+        let (a, b) = (self.wide(), other.wide());
+        (a.0 * b.1).cmp(&(b.0 * a.1))
+    }
+}
+
+impl PartialOrd for Rational {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for Rational {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.numer, self.denom)
+    }
+}
+
+/// A number that expressions compute with.
+///
+/// When an operation mixes kinds of number, the operands are promoted to
+/// the more general kind first: `Int` to `Rational` to `Float`. A
+/// `Rational` result that turns out to be whole becomes an `Int` again, so
+/// `7 / 2` is the exact `7/2`, and `7 / 2 * 2` is `7`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Number {
+    Int(i64),
+    Float(f64),
+    /// A fraction that is not a whole number.
+    Rational(Rational),
+}
+
+impl Number {
+    /// Whether this is zero, which is what counts as false.
+    pub fn is_zero(&self) -> bool {
+        match self {
+            Number::Int(val) => *val == 0,
+            Number::Float(val) => *val == 0.0,
+            Number::Rational(_) => false,
+        }
+    }
+
+    pub fn to_f64(self) -> f64 {
+        match self {
+            Number::Int(val) => val as f64,
+            Number::Float(val) => val,
+            Number::Rational(val) => val.to_f64(),
+        }
+    }
+
+    /// The exact value, unless this is a `Float`.
+    pub fn to_rational(self) -> Option<Rational> {
+        match self {
+            Number::Int(val) => Some(val.into()),
+            Number::Float(_) => None,
+            Number::Rational(val) => Some(val),
+        }
+    }
+
+    /// Compare two numbers of any kind by their value. Only `NaN` is not
+    /// comparable.
+    pub fn compare(self, other: Number) -> Option<Ordering> {
+        match (self, other) {
+            (Number::Int(a), Number::Int(b)) => Some(a.cmp(&b)),
+            (Number::Float(_), _) | (_, Number::Float(_)) => self.to_f64().partial_cmp(&other.to_f64()),
+            _ => Some(self.to_rational()?.cmp(&other.to_rational()?)),
+        }
+    }
+
+    /// Whether this is negative, as far as printing it is concerned.
+    pub fn is_sign_negative(&self) -> bool {
+        match self {
+            Number::Int(val) => *val < 0,
+            Number::Float(val) => val.is_sign_negative(),
+            Number::Rational(val) => val.numer < 0,
+        }
+    }
+}
+
+impl From<i64> for Number {
+    fn from(value: i64) -> Self {
+        Number::Int(value)
+    }
+}
+
+impl From<f64> for Number {
+    fn from(value: f64) -> Self {
+        Number::Float(value)
+    }
+}
+
+impl From<Rational> for Number {
+    fn from(value: Rational) -> Self {
+        if value.denom == 1 {
+            Number::Int(value.numer)
+        } else {
+            Number::Rational(value)
+        }
+    }
+}
+
+/// Integers print as usual, floats always with a `.` or an exponent so
+/// they read back as floats, and rationals as `numerator/denominator`.
+impl fmt::Display for Number {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Number::Int(val) => write!(f, "{val}"),
+            Number::Float(val) => write!(f, "{val:?}"),
+            Number::Rational(val) => write!(f, "{val}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rational(numer: i64, denom: i64) -> Rational {
+        Rational::new(numer, denom).unwrap()
+    }
+
+    #[test]
+    fn lowest_terms() {
+        assert_eq!(rational(6, -4), rational(-3, 2));
+        assert_eq!(rational(-6, -4).numer(), 3);
+        assert_eq!(rational(0, -7), Rational::from(0));
+        assert_eq!(Rational::new(1, 0), None);
+        // -i64::MIN does not fit
+        assert_eq!(Rational::new(i64::MIN, -3), None);
+        assert_eq!(Rational::new(i64::MIN, -2), Some(Rational::from(1 << 62)));
+    }
+
+    #[test]
+    fn arithmetic() {
+        let half = rational(1, 2);
+        let third = rational(1, 3);
+        assert_eq!(half.checked_add(third), Some(rational(5, 6)));
+        assert_eq!(half.checked_sub(third), Some(rational(1, 6)));
+        assert_eq!(half.checked_mul(third), Some(rational(1, 6)));
+        assert_eq!(half.checked_div(third), Some(rational(3, 2)));
+        assert_eq!(rational(7, 2).checked_rem(rational(4, 3)), Some(rational(5, 6)));
+        assert_eq!(rational(-7, 2).checked_rem(rational(4, 3)), Some(rational(-5, 6)));
+        assert_eq!(rational(2, 3).checked_pow(3), Some(rational(8, 27)));
+        assert_eq!(rational(2, 3).checked_pow(-2), Some(rational(9, 4)));
+        assert_eq!(Rational::from(0).checked_pow(-1), None);
+        assert_eq!(half.checked_div(Rational::from(0)), None);
+
+        // intermediate results may be huge as long as the answer is not
+        let big = rational(i64::MAX, i64::MAX - 1);
+        assert_eq!(big.checked_mul(big.checked_pow(-1).unwrap()), Some(Rational::from(1)));
+        assert_eq!(big.checked_mul(big), None);
+    }
+
+    #[test]
+    fn ordering() {
+        assert!(rational(1, 3) < rational(1, 2));
+        assert!(rational(-1, 2) < rational(-1, 3));
+        assert_eq!(Number::Int(1).compare(Number::Float(0.5)), Some(Ordering::Greater));
+        assert_eq!(Number::Int(1).compare(Number::Rational(rational(3, 2))), Some(Ordering::Less));
+        assert_eq!(Number::Float(f64::NAN).compare(Number::Int(1)), None);
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(Number::Int(-3).to_string(), "-3");
+        assert_eq!(Number::Float(2.0).to_string(), "2.0");
+        assert_eq!(Number::Float(1e300).to_string(), "1e300");
+        assert_eq!(Number::from(rational(-7, 2)).to_string(), "-7/2");
+        assert_eq!(Number::from(rational(8, 2)), Number::Int(4));
+    }
+}
@@ -0,0 +1,383 @@
+use super::{ArithmeticMode, Expression, Number, Operation};
+
+/// Rewrites expressions into simpler ones that evaluate to the same value:
+/// constant subtrees are folded, identities such as `x + 0` or `x * 1` are
+/// removed, and the operands of commutative operations are put in a
+/// canonical order (constants last).
+///
+/// Anything that would fail when evaluated is left in place, so the
+/// optimized tree fails with the same error (although the path to it may
+/// differ, the tree having changed shape). Folding depends on the
+/// arithmetic mode, so optimize with the mode you are going to evaluate
+/// with.
+#[derive(Debug, Default)]
+pub struct Optimizer {
+    mode: ArithmeticMode,
+}
+
+/// The variables bound by the `Let`s around the node being simplified,
+/// innermost last, with their value if it is a constant.
+type Scope<'e> = Vec<(&'e str, Option<Number>)>;
+
+/// What is known about an expression without evaluating it.
+#[derive(Clone, Copy)]
+struct Facts {
+    /// Evaluating it certainly succeeds.
+    infallible: bool,
+    /// If it succeeds, the result is a `Number::Int`.
+    integer: bool,
+}
+
+impl Facts {
+    fn of(val: &Number) -> Self {
+        Facts { infallible: true, integer: matches!(val, Number::Int(_)) }
+    }
+}
+
+impl Optimizer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the arithmetic mode the optimized expression will be evaluated
+    /// with.
+    pub fn mode(mut self, mode: ArithmeticMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Return a simplified copy of `e`. Unlike `eval` this recurses once
+    /// per level of the tree.
+    pub fn optimize(&self, e: &Expression) -> Expression {
+        self.simplify(e, &mut Vec::new()).0
+    }
+
+    /// Simplify `e`, and work out what is known about the result on the
+    /// way back up, so that no subtree is looked at twice. Variables only
+    /// count as infallible if a `Let` around them binds them, anything else
+    /// may be missing from the environment, and nothing is known about the
+    /// kind of number they hold.
+    fn simplify<'e>(&self, e: &'e Expression, scope: &mut Scope<'e>) -> (Expression, Facts) {
+        match e {
+            Expression::Value(val) => (Expression::Value(*val), Facts::of(val)),
+            Expression::Variable(name) => {
+                match scope.iter().rev().find(|(bound, _)| bound == name) {
+                    Some((_, Some(constant))) => (Expression::Value(*constant), Facts::of(constant)),
+                    bound => {
+                        let facts = Facts { infallible: bound.is_some(), integer: false };
+                        (Expression::Variable(name.clone()), facts)
+                    }
+                }
+            }
+            Expression::Let { name, value, body } => {
+                let (value, value_facts) = self.simplify(value, scope);
+                let constant = match value {
+                    Expression::Value(val) => Some(val),
+                    _ => None,
+                };
+
+                scope.push((name, constant));
+                let (body, facts) = self.simplify(body, scope);
+                scope.pop();
+
+                if constant.is_some() {
+                    // every use of the name has been replaced by its value
+                    return (body, facts);
+                }
+                let facts = Facts { infallible: value_facts.infallible && facts.infallible, ..facts };
+                let (value, body) = (Box::new(value), Box::new(body));
+                (Expression::Let { name: name.clone(), value, body }, facts)
+            }
+            Expression::If { cond, then, otherwise } => match self.simplify(cond, scope) {
+                // the other branch would never have been evaluated
+                (Expression::Value(val), _) if val.is_zero() => self.simplify(otherwise, scope),
+                (Expression::Value(_), _) => self.simplify(then, scope),
+                (cond, cond_facts) => {
+                    let (then, then_facts) = self.simplify(then, scope);
+                    let (otherwise, otherwise_facts) = self.simplify(otherwise, scope);
+                    let facts = Facts {
+                        infallible: cond_facts.infallible
+                            && then_facts.infallible
+                            && otherwise_facts.infallible,
+                        integer: then_facts.integer && otherwise_facts.integer,
+                    };
+                    let e = Expression::If {
+                        cond: Box::new(cond),
+                        then: Box::new(then),
+                        otherwise: Box::new(otherwise),
+                    };
+                    (e, facts)
+                }
+            },
+            // calls are never folded, the functions belong to the
+            // evaluator, and any call may fail, if only because of its
+            // arguments
+            Expression::Call { name, args } => {
+                let args = args.iter().map(|arg| self.simplify(arg, scope).0).collect();
+                let facts = Facts { infallible: false, integer: false };
+                (Expression::Call { name: name.clone(), args }, facts)
+            }
+            Expression::Function { name, params, body, rest } => {
+                // the parameters shadow whatever is bound around the
+                // definition
+                let outer = scope.len();
+                scope.extend(params.iter().map(|param| (param.as_str(), None)));
+                let (body, _) = self.simplify(body, scope);
+                scope.truncate(outer);
+                // defining a function cannot fail
+                let (rest, facts) = self.simplify(rest, scope);
+                let e = Expression::Function {
+                    name: name.clone(),
+                    params: params.clone(),
+                    body: Box::new(body),
+                    rest: Box::new(rest),
+                };
+                (e, facts)
+            }
+            Expression::Op { op, left, right } => {
+                let left = self.simplify(left, scope);
+                // neither would the right hand side here
+                let short_circuit = match (op, &left.0) {
+                    (Operation::And, Expression::Value(val)) if val.is_zero() => Some(Number::Int(0)),
+                    (Operation::Or, Expression::Value(val)) if !val.is_zero() => Some(Number::Int(1)),
+                    _ => None,
+                };
+                if let Some(val) = short_circuit {
+                    return (Expression::Value(val), Facts::of(&val));
+                }
+                let right = self.simplify(right, scope);
+                self.combine(*op, left, right)
+            }
+        }
+    }
+
+    /// Build `left op right` from already simplified operands, and what is
+    /// known about them.
+    fn combine(
+        &self,
+        op: Operation,
+        (left, l): (Expression, Facts),
+        (right, r): (Expression, Facts),
+    ) -> (Expression, Facts) {
+        use Expression::Value;
+        use Number::Int;
+        use Operation::*;
+
+        if let (Value(lv), Value(rv)) = (&left, &right) {
+            // a failure is left for `eval` to report
+            if let Ok(val) = self.mode.apply(op, *lv, *rv, &[]) {
+                return (Value(val), Facts::of(&val));
+            }
+        }
+
+        let zero = || (Value(Int(0)), Facts { infallible: true, integer: true });
+        match (op, &left, &right) {
+            // these hold for every kind of number
+            (Add | Sub, _, Value(Int(0))) | (Mul | Div | Pow, _, Value(Int(1))) => return (left, l),
+            (Add, Value(Int(0)), _) | (Mul, Value(Int(1)), _) => return (right, r),
+            // these do not, a float or fraction has to fail
+            (BitOr | BitXor | Shl | Shr, _, Value(Int(0))) if l.integer => return (left, l),
+            (BitOr | BitXor, Value(Int(0)), _) if r.integer => return (right, r),
+            // dropping an operand is only fine if it could not have failed,
+            // and `0.5 * 0` is the float `0.0`
+            (Mul | BitAnd, _, Value(Int(0))) if l.infallible && l.integer => return zero(),
+            (Mul | BitAnd, Value(Int(0)), _) if r.infallible && r.integer => return zero(),
+            (Pow, _, Value(Int(0))) if l.infallible && l.integer => {
+                return (Value(Int(1)), Facts { infallible: true, integer: true });
+            }
+            _ => {}
+        }
+
+        // swapping the operands is only fine if that cannot change which
+        // of them fails first
+        let commutative = matches!(op, Add | Mul | BitAnd | BitOr | BitXor | Eq | Ne);
+        let ((left, l), (right, r)) =
+            if commutative && order(&left) > order(&right) && (l.infallible || r.infallible) {
+                ((right, r), (left, l))
+            } else {
+                ((left, l), (right, r))
+            };
+        let facts = self.op_facts(op, l, r, &right);
+        (Expression::Op { op, left: Box::new(left), right: Box::new(right) }, facts)
+    }
+
+    /// What is known about `left op right`, given what is known about the
+    /// operands.
+    fn op_facts(&self, op: Operation, l: Facts, r: Facts, right: &Expression) -> Facts {
+        let wraps = self.mode != ArithmeticMode::Checked;
+        let constant = match *right {
+            Expression::Value(Number::Int(val)) => Some(val),
+            _ => None,
+        };
+        // whether the result is an integer, and whether `op` itself
+        // certainly succeeds on operands that did
+        let (integer, op_is_safe) = match op {
+            Operation::Add | Operation::Sub | Operation::Mul => {
+                let integer = l.integer && r.integer;
+                (integer, integer && wraps)
+            }
+            // a division that does not come out even is a
+            // fraction, which cannot hold i64::MIN / -3 either
+            Operation::Div => (false, l.integer && constant.is_some_and(|r| r > 0 || (r == -1 && wraps))),
+            // only i64::MIN % -1 overflows
+            Operation::Rem => {
+                let safe = l.integer && constant.is_some_and(|r| r != 0 && (r != -1 || wraps));
+                (l.integer && r.integer, safe)
+            }
+            Operation::Pow => {
+                let integer = l.integer && constant.is_some_and(|r| r >= 0);
+                (integer, integer && wraps)
+            }
+            Operation::Shl | Operation::Shr => {
+                let in_range = constant.is_some_and(|r| (0..64).contains(&r));
+                (true, l.integer && in_range && (op == Operation::Shr || wraps))
+            }
+            Operation::BitAnd | Operation::BitOr | Operation::BitXor => (true, l.integer && r.integer),
+            // comparisons and the logical operators never fail
+            _ => (true, true),
+        };
+        Facts { infallible: op_is_safe && l.infallible && r.infallible, integer }
+    }
+}
+
+/// Where an operand goes in a commutative operation: compound expressions
+/// first, then variables by name, then constants.
+fn order(e: &Expression) -> (u8, &str) {
+    match e {
+        Expression::Value(_) => (2, ""),
+        Expression::Variable(name) => (1, name),
+        _ => (0, ""),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::epression_evaluation::number::Rational;
+    use crate::epression_evaluation::random::Rng;
+    use crate::epression_evaluation::{parser, Environment, EvalError, Evaluator, Path};
+
+    fn optimized(input: &str) -> Expression {
+        Optimizer::new().optimize(&parser::parse(input).unwrap())
+    }
+
+    fn parsed(input: &str) -> Expression {
+        parser::parse(input).unwrap()
+    }
+
+    #[test]
+    fn folds_constants() {
+        assert_eq!(optimized("10 * 9 + (3 - 4) * 5"), Expression::Value(Number::Int(85)));
+        assert_eq!(optimized("2 * 3 + x"), parsed("x + 6"));
+        assert_eq!(optimized("let a = 2 in a * y + a"), parsed("y * 2 + 2"));
+        assert_eq!(optimized("if 3 > 2 then x else missing"), parsed("x"));
+        assert_eq!(optimized("0 && missing"), Expression::Value(Number::Int(0)));
+        assert_eq!(optimized("1 || missing"), Expression::Value(Number::Int(1)));
+        assert_eq!(optimized("0.5 || missing"), Expression::Value(Number::Int(1)));
+        // inexact divisions fold into exact fractions
+        let half = Expression::Value(Number::Rational(Rational::new(3, 2).unwrap()));
+        assert_eq!(
+            optimized("(1 + 2) / 2 * x"),
+            Expression::Op { op: Operation::Mul, left: Box::new(parsed("x")), right: Box::new(half) }
+        );
+        assert_eq!(optimized("1 / 2 + 0.25"), Expression::Value(Number::Float(0.75)));
+    }
+
+    #[test]
+    fn removes_identities() {
+        assert_eq!(optimized("x + 0"), parsed("x"));
+        assert_eq!(optimized("0 + x"), parsed("x"));
+        assert_eq!(optimized("(x - 0) * 1 / 1"), parsed("x"));
+        assert_eq!(optimized("1 * x ** 1"), parsed("x"));
+        assert_eq!(optimized("(x & 3) << (2 - 2) | 0"), parsed("x & 3"));
+        // `x` might be a float or fraction, which `|` and `<<` reject
+        assert_eq!(optimized("x | 0"), parsed("x | 0"));
+        assert_eq!(optimized("x << 0"), parsed("x << 0"));
+    }
+
+    #[test]
+    fn multiplying_by_zero() {
+        // `x` might not be bound, so it has to stay
+        assert_eq!(optimized("x * 0"), parsed("x * 0"));
+        // but here it is, and `q < 1` cannot fail
+        assert_eq!(optimized("let q = x in (q < 1) * 0"), parsed("let q = x in 0"));
+        // `q` might be a fraction, and then `q & 1` fails
+        assert_eq!(optimized("let q = x in (q & 1) * 0"), parsed("let q = x in (q & 1) * 0"));
+        // `0.5 * 0` is `0.0` rather than `0`
+        assert_eq!(optimized("let q = x in q * 0"), parsed("let q = x in q * 0"));
+        // `(q < 1) + 1` can only overflow in checked mode
+        assert_eq!(
+            optimized("let q = x in 0 * ((q < 1) + 1)"),
+            parsed("let q = x in ((q < 1) + 1) * 0")
+        );
+        let wrapping = Optimizer::new().mode(ArithmeticMode::Wrapping);
+        assert_eq!(
+            wrapping.optimize(&parsed("let q = x in 0 * ((q < 1) + 1)")),
+            parsed("let q = x in 0")
+        );
+    }
+
+    #[test]
+    fn keeps_errors() {
+        assert_eq!(optimized("1 / 0"), parsed("1 / 0"));
+        assert_eq!(optimized("x + 9223372036854775807 + 1"), parsed("x + 9223372036854775807 + 1"));
+        assert_eq!(optimized("(1 / 0) * 0"), parsed("1 / 0 * 0"));
+        assert_eq!(optimized("0 ** -1 + 1"), parsed("0 ** -1 + 1"));
+        assert_eq!(optimized("2.5 & 1"), parsed("2.5 & 1"));
+    }
+
+    #[test]
+    fn functions() {
+        assert_eq!(optimized("max(1 + 2, x * 1)"), parsed("max(3, x)"));
+        assert_eq!(
+            optimized("let a = 2 in let f(x) = x * a + 0 in f(a)"),
+            parsed("let f(x) = x * 2 in f(2)")
+        );
+        // the parameter is not the constant of the same name
+        assert_eq!(optimized("let x = 2 in let f(x) = x in f(x)"), parsed("let f(x) = x in f(2)"));
+        // a call might fail, so it cannot be dropped
+        assert_eq!(optimized("let q = x in f(q) * 0"), parsed("let q = x in f(q) * 0"));
+    }
+
+    #[test]
+    fn normalizes_commutative_operands() {
+        assert_eq!(optimized("3 + x"), parsed("x + 3"));
+        assert_eq!(
+            optimized("let a = y in let b = x in b * a"),
+            parsed("let a = y in let b = x in a * b")
+        );
+        assert_eq!(optimized("let a = y in x == (a < 1)"), parsed("let a = y in (a < 1) == x"));
+        // both sides may fail, so the order they are evaluated in matters
+        assert_eq!(optimized("y * x"), parsed("y * x"));
+        assert_eq!(optimized("y / 2 + x"), parsed("y / 2 + x"));
+    }
+
+    /// Strip the path from an error, which may differ, and make `NaN`s
+    /// compare equal by turning them into `None`.
+    fn comparable(result: Result<Number, EvalError>) -> Result<Option<Number>, EvalError> {
+        result
+            .map(|val| Some(val).filter(|val| !matches!(val, Number::Float(f) if f.is_nan())))
+            .map_err(|mut error| {
+                *error.path_mut() = Path::default();
+                error
+            })
+    }
+
+    #[test]
+    fn agrees_with_eval() {
+        let mut rng = Rng::new(8);
+        for _ in 0..5_000 {
+            let e = rng.expression(5);
+            let env: Environment = rng.environment();
+            for mode in [ArithmeticMode::Checked, ArithmeticMode::Wrapping, ArithmeticMode::Saturating] {
+                let evaluator = Evaluator::new().mode(mode);
+                let optimized = Optimizer::new().mode(mode).optimize(&e);
+                assert_eq!(
+                    comparable(evaluator.eval_in(&optimized, &env)),
+                    comparable(evaluator.eval_in(&e, &env)),
+                    "{e:?} in {mode:?} became {optimized:?} with {env:?}"
+                );
+            }
+        }
+    }
+}
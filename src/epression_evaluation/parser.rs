@@ -0,0 +1,773 @@
+use std::fmt;
+
+use super::{Expression, Number, Operation};
+
+/// The kinds of token the parser understands.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TokenKind {
+    Number,
+    Identifier,
+    Let,
+    In,
+    If,
+    Then,
+    Else,
+    Equals,
+    Plus,
+    Minus,
+    Star,
+    StarStar,
+    Slash,
+    Percent,
+    Amp,
+    AmpAmp,
+    Pipe,
+    PipePipe,
+    Caret,
+    Shl,
+    Shr,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    EqEq,
+    NotEq,
+    LParen,
+    RParen,
+    Comma,
+    End,
+}
+
+/// A token, remembering where in the input it started so errors can
+/// point back at it.
+#[derive(Debug, Clone, Copy)]
+struct Token<'a> {
+    kind: TokenKind,
+    text: &'a str,
+    offset: usize,
+}
+
+/// Why parsing failed.
+#[derive(Debug, PartialEq)]
+pub enum ParseErrorKind {
+    /// A character that cannot start any token.
+    UnexpectedChar(char),
+    /// A token that is not allowed at this point in the expression.
+    UnexpectedToken(String),
+    /// The input ended where an operand or `)` was expected.
+    UnexpectedEnd,
+    /// An integer literal that does not fit in an `i64`, or a float
+    /// literal so large it would be infinite.
+    NumberTooLarge(String),
+    /// Parentheses, unary minuses, `**` and the like nested more than
+    /// `MAX_NESTING` deep.
+    TooDeep,
+}
+
+/// A parse failure, along with the byte offset of the offending token.
+#[derive(Debug, PartialEq)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    pub offset: usize,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.kind {
+            ParseErrorKind::UnexpectedChar(c) => write!(f, "unexpected character {c:?}")?,
+            ParseErrorKind::UnexpectedToken(t) => write!(f, "unexpected token {t:?}")?,
+            ParseErrorKind::UnexpectedEnd => write!(f, "unexpected end of input")?,
+            ParseErrorKind::NumberTooLarge(n) => write!(f, "number {n} is out of range")?,
+            ParseErrorKind::TooDeep => write!(f, "expression nested too deeply")?,
+        }
+        write!(f, " at offset {}", self.offset)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Split the input into tokens, skipping whitespace. The returned list
+/// always ends with a `TokenKind::End` token.
+fn tokenize(input: &str) -> Result<Vec<Token<'_>>, ParseError> {
+    let mut tokens = Vec::new();
+    let mut chars = input.char_indices().peekable();
+
+    while let Some((offset, c)) = chars.next() {
+        // operators made of two characters win over their one character
+        // prefixes, so `<<` is not read as two `<`
+        let next = chars.peek().map(|&(_, next)| next);
+        let double = match (c, next) {
+            ('*', Some('*')) => Some(TokenKind::StarStar),
+            ('&', Some('&')) => Some(TokenKind::AmpAmp),
+            ('|', Some('|')) => Some(TokenKind::PipePipe),
+            ('<', Some('<')) => Some(TokenKind::Shl),
+            ('>', Some('>')) => Some(TokenKind::Shr),
+            ('<', Some('=')) => Some(TokenKind::Le),
+            ('>', Some('=')) => Some(TokenKind::Ge),
+            ('=', Some('=')) => Some(TokenKind::EqEq),
+            ('!', Some('=')) => Some(TokenKind::NotEq),
+            _ => None,
+        };
+        if let Some(kind) = double {
+            chars.next();
+            tokens.push(Token { kind, text: &input[offset..offset + 2], offset });
+            continue;
+        }
+
+        let kind = match c {
+            c if c.is_whitespace() => continue,
+            '+' => TokenKind::Plus,
+            '-' => TokenKind::Minus,
+            '*' => TokenKind::Star,
+            '/' => TokenKind::Slash,
+            '%' => TokenKind::Percent,
+            '&' => TokenKind::Amp,
+            '|' => TokenKind::Pipe,
+            '^' => TokenKind::Caret,
+            '<' => TokenKind::Lt,
+            '>' => TokenKind::Gt,
+            '=' => TokenKind::Equals,
+            '(' => TokenKind::LParen,
+            ')' => TokenKind::RParen,
+            ',' => TokenKind::Comma,
+            '0'..='9' => {
+                // the literal itself is converted later so that a too-large
+                // number is reported by the parser
+                let end = number_end(input, offset);
+                while chars.next_if(|&(next, _)| next < end).is_some() {}
+                tokens.push(Token { kind: TokenKind::Number, text: &input[offset..end], offset });
+                continue;
+            }
+            'a'..='z' | 'A'..='Z' | '_' => {
+                let mut end = offset + 1;
+                while let Some(&(next, c)) = chars.peek() {
+                    if !(c.is_ascii_alphanumeric() || c == '_') {
+                        break;
+                    }
+                    end = next + 1;
+                    chars.next();
+                }
+                let text = &input[offset..end];
+                let kind = match text {
+                    "let" => TokenKind::Let,
+                    "in" => TokenKind::In,
+                    "if" => TokenKind::If,
+                    "then" => TokenKind::Then,
+                    "else" => TokenKind::Else,
+                    _ => TokenKind::Identifier,
+                };
+                tokens.push(Token { kind, text, offset });
+                continue;
+            }
+            other => {
+                return Err(ParseError { kind: ParseErrorKind::UnexpectedChar(other), offset });
+            }
+        };
+        tokens.push(Token { kind, text: &input[offset..offset + c.len_utf8()], offset });
+    }
+
+    tokens.push(Token { kind: TokenKind::End, text: "", offset: input.len() });
+    Ok(tokens)
+}
+
+/// Where the number literal starting at `start` ends: digits, then
+/// optionally a fraction such as `.25` and an exponent such as `e-3`.
+fn number_end(input: &str, start: usize) -> usize {
+    let bytes = input.as_bytes();
+    let digits = |from: usize| from + bytes[from..].iter().take_while(|b| b.is_ascii_digit()).count();
+    let mut end = digits(start);
+    if bytes.get(end) == Some(&b'.') && bytes.get(end + 1).is_some_and(u8::is_ascii_digit) {
+        end = digits(end + 1);
+    }
+    if matches!(bytes.get(end), Some(b'e' | b'E')) {
+        let sign = usize::from(matches!(bytes.get(end + 1), Some(b'+' | b'-')));
+        if bytes.get(end + 1 + sign).is_some_and(u8::is_ascii_digit) {
+            end = digits(end + 1 + sign);
+        }
+    }
+    end
+}
+
+/// The binary operator a token stands for. `**` is not in here because it
+/// is right associative and binds tighter than a unary minus, see
+/// `Parser::power`.
+fn binary_op(kind: TokenKind) -> Option<Operation> {
+    match kind {
+        TokenKind::PipePipe => Some(Operation::Or),
+        TokenKind::AmpAmp => Some(Operation::And),
+        TokenKind::Pipe => Some(Operation::BitOr),
+        TokenKind::Caret => Some(Operation::BitXor),
+        TokenKind::Amp => Some(Operation::BitAnd),
+        TokenKind::EqEq => Some(Operation::Eq),
+        TokenKind::NotEq => Some(Operation::Ne),
+        TokenKind::Lt => Some(Operation::Lt),
+        TokenKind::Le => Some(Operation::Le),
+        TokenKind::Gt => Some(Operation::Gt),
+        TokenKind::Ge => Some(Operation::Ge),
+        TokenKind::Shl => Some(Operation::Shl),
+        TokenKind::Shr => Some(Operation::Shr),
+        TokenKind::Plus => Some(Operation::Add),
+        TokenKind::Minus => Some(Operation::Sub),
+        TokenKind::Star => Some(Operation::Mul),
+        TokenKind::Slash => Some(Operation::Div),
+        TokenKind::Percent => Some(Operation::Rem),
+        _ => None,
+    }
+}
+
+/// How tightly a unary minus binds, between `*` and `**`.
+pub(super) const UNARY_POWER: u8 = 11;
+
+/// How tightly an operator binds its operands, higher numbers binding
+/// tighter. The order follows C.
+pub(super) fn binding_power(op: Operation) -> u8 {
+    match op {
+        Operation::Or => 1,
+        Operation::And => 2,
+        Operation::BitOr => 3,
+        Operation::BitXor => 4,
+        Operation::BitAnd => 5,
+        Operation::Eq | Operation::Ne => 6,
+        Operation::Lt | Operation::Le | Operation::Gt | Operation::Ge => 7,
+        Operation::Shl | Operation::Shr => 8,
+        Operation::Add | Operation::Sub => 9,
+        Operation::Mul | Operation::Div | Operation::Rem => 10,
+        Operation::Pow => 12,
+    }
+}
+
+/// How deeply an expression may nest. The parser recurses once per level,
+/// so this keeps it from running out of stack on hostile input.
+pub const MAX_NESTING: usize = 128;
+
+struct Parser<'a> {
+    tokens: Vec<Token<'a>>,
+    pos: usize,
+    /// How many operands are being parsed inside one another.
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Token<'a> {
+        self.tokens[self.pos]
+    }
+
+    fn advance(&mut self) -> Token<'a> {
+        let token = self.tokens[self.pos];
+        // never walk past the End token
+        if token.kind != TokenKind::End {
+            self.pos += 1;
+        }
+        token
+    }
+
+    /// Build the error for a token that we did not expect to see here.
+    fn unexpected(token: Token) -> ParseError {
+        let kind = match token.kind {
+            TokenKind::End => ParseErrorKind::UnexpectedEnd,
+            _ => ParseErrorKind::UnexpectedToken(token.text.to_string()),
+        };
+        ParseError { kind, offset: token.offset }
+    }
+
+    /// Consume the next token, which has to be of the given kind.
+    fn expect(&mut self, kind: TokenKind) -> Result<Token<'a>, ParseError> {
+        let token = self.advance();
+        if token.kind != kind {
+            return Err(Self::unexpected(token));
+        }
+        Ok(token)
+    }
+
+    /// Precedence climbing: parse operands joined by operators that bind
+    /// at least as tightly as `min_power`. All of these operators are left
+    /// associative, so the right hand side only accepts tighter ones.
+    fn expression(&mut self, min_power: u8) -> Result<Expression, ParseError> {
+        let mut left = self.unary()?;
+
+        while let Some(op) = binary_op(self.peek().kind) {
+            let power = binding_power(op);
+            if power < min_power {
+                break;
+            }
+            self.advance();
+            let right = self.expression(power + 1)?;
+            left = Expression::Op { op, left: Box::new(left), right: Box::new(right) };
+        }
+
+        Ok(left)
+    }
+
+    /// Every way of nesting one expression in another comes through here,
+    /// so this is where the depth is kept.
+    fn unary(&mut self) -> Result<Expression, ParseError> {
+        if self.depth == MAX_NESTING {
+            return Err(ParseError { kind: ParseErrorKind::TooDeep, offset: self.peek().offset });
+        }
+        self.depth += 1;
+        let operand = self.negation();
+        self.depth -= 1;
+        operand
+    }
+
+    /// A unary minus is turned into `0 - operand`, except directly in front
+    /// of a literal where it becomes part of the value (that way
+    /// `-9223372036854775808` still fits). Like in maths, `-2 ** 2` is
+    /// `-(2 ** 2)`, so a literal followed by `**` is not folded.
+    fn negation(&mut self) -> Result<Expression, ParseError> {
+        if self.peek().kind != TokenKind::Minus {
+            return self.power();
+        }
+        self.advance();
+
+        let next = self.peek();
+        let after = self.tokens[(self.pos + 1).min(self.tokens.len() - 1)];
+        if next.kind == TokenKind::Number && after.kind != TokenKind::StarStar {
+            self.advance();
+            return number(&format!("-{}", next.text), next.offset);
+        }
+
+        let operand = self.unary()?;
+        Ok(Expression::Op {
+            op: Operation::Sub,
+            left: Box::new(Expression::Value(Number::Int(0))),
+            right: Box::new(operand),
+        })
+    }
+
+    /// `**` is right associative, and its exponent may itself be negated:
+    /// `2 ** -1 ** 2` is `2 ** (-(1 ** 2))`.
+    fn power(&mut self) -> Result<Expression, ParseError> {
+        let base = self.primary()?;
+        if self.peek().kind != TokenKind::StarStar {
+            return Ok(base);
+        }
+        self.advance();
+        let exponent = self.unary()?;
+        Ok(Expression::Op { op: Operation::Pow, left: Box::new(base), right: Box::new(exponent) })
+    }
+
+    fn primary(&mut self) -> Result<Expression, ParseError> {
+        let token = self.advance();
+        match token.kind {
+            TokenKind::Number => number(token.text, token.offset),
+            // a name followed by `(` is a call
+            TokenKind::Identifier if self.peek().kind == TokenKind::LParen => {
+                self.advance();
+                let args = self.list(|parser| parser.expression(0))?;
+                Ok(Expression::Call { name: token.text.to_string(), args })
+            }
+            TokenKind::Identifier => Ok(Expression::Variable(token.text.to_string())),
+            TokenKind::LParen => {
+                let inner = self.expression(0)?;
+                self.expect(TokenKind::RParen)?;
+                Ok(inner)
+            }
+            TokenKind::Let => {
+                // let <name> = <value> in <body>, where the body reaches as
+                // far to the right as it can
+                let name = self.expect(TokenKind::Identifier)?.text.to_string();
+                if self.peek().kind == TokenKind::LParen {
+                    return self.function(name);
+                }
+                self.expect(TokenKind::Equals)?;
+                let value = self.expression(0)?;
+                self.expect(TokenKind::In)?;
+                let body = self.expression(0)?;
+                Ok(Expression::Let { name, value: Box::new(value), body: Box::new(body) })
+            }
+            TokenKind::If => {
+                // if <cond> then <then> else <otherwise>, where again the
+                // else branch reaches as far to the right as it can
+                let cond = self.expression(0)?;
+                self.expect(TokenKind::Then)?;
+                let then = self.expression(0)?;
+                self.expect(TokenKind::Else)?;
+                let otherwise = self.expression(0)?;
+                Ok(Expression::If {
+                    cond: Box::new(cond),
+                    then: Box::new(then),
+                    otherwise: Box::new(otherwise),
+                })
+            }
+            _ => Err(Self::unexpected(token)),
+        }
+    }
+
+    /// The rest of `let <name>(<params>) = <body> in <rest>`, after the
+    /// name.
+    fn function(&mut self, name: String) -> Result<Expression, ParseError> {
+        self.advance();
+        let params = self.list(|parser| Ok(parser.expect(TokenKind::Identifier)?.text.to_string()))?;
+        self.expect(TokenKind::Equals)?;
+        let body = self.expression(0)?;
+        self.expect(TokenKind::In)?;
+        let rest = self.expression(0)?;
+        Ok(Expression::Function { name, params, body: Box::new(body), rest: Box::new(rest) })
+    }
+
+    /// A comma separated list of `item`s up to a closing parenthesis, after
+    /// the opening one. The list may be empty.
+    fn list<T>(
+        &mut self,
+        mut item: impl FnMut(&mut Self) -> Result<T, ParseError>,
+    ) -> Result<Vec<T>, ParseError> {
+        let mut items = Vec::new();
+        if self.peek().kind == TokenKind::RParen {
+            self.advance();
+            return Ok(items);
+        }
+        loop {
+            items.push(item(self)?);
+            let token = self.advance();
+            match token.kind {
+                TokenKind::Comma => continue,
+                TokenKind::RParen => return Ok(items),
+                _ => return Err(Self::unexpected(token)),
+            }
+        }
+    }
+}
+
+/// A literal with a fraction or an exponent is a float, anything else an
+/// integer.
+fn number(text: &str, offset: usize) -> Result<Expression, ParseError> {
+    let number = if text.contains(['.', 'e', 'E']) {
+        text.parse().ok().filter(|val: &f64| val.is_finite()).map(Number::Float)
+    } else {
+        text.parse().ok().map(Number::Int)
+    };
+    number
+        .map(Expression::Value)
+        .ok_or_else(|| ParseError { kind: ParseErrorKind::NumberTooLarge(text.to_string()), offset })
+}
+
+/// Parse an infix expression such as `10 * 9 + (3 - 4) * 5`.
+///
+/// `*` and `/` bind tighter than `+` and `-`, all four are left
+/// associative, and whitespace is ignored. Names such as `rate` are
+/// variables, and `let x = 4 in x * x` binds one. Numbers with a fraction
+/// or an exponent, such as `2.5` or `1e-3`, are floats.
+///
+/// The remaining operators follow C's precedence (`||` loosest, then `&&`,
+/// `|`, `^`, `&`, `==`/`!=`, the comparisons, the shifts), with `%` next to
+/// `*` and a right associative `**` binding tightest of all. Conditionals
+/// are written `if cond then a else b`.
+///
+/// `max(a, b)` calls a function, and `let f(x, y) = x * y in f(2, 3)`
+/// defines one.
+pub fn parse(input: &str) -> Result<Expression, ParseError> {
+    let mut parser = Parser { tokens: tokenize(input)?, pos: 0, depth: 0 };
+    let expression = parser.expression(0)?;
+
+    // the whole input has to be used up, `1 2` is not an expression
+    let rest = parser.advance();
+    if rest.kind != TokenKind::End {
+        return Err(Parser::unexpected(rest));
+    }
+    Ok(expression)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn op(op: Operation, left: Expression, right: Expression) -> Expression {
+        Expression::Op { op, left: Box::new(left), right: Box::new(right) }
+    }
+
+    fn value(v: i64) -> Expression {
+        Expression::Value(Number::Int(v))
+    }
+
+    fn float(v: f64) -> Expression {
+        Expression::Value(Number::Float(v))
+    }
+
+    #[test]
+    fn literal() {
+        assert_eq!(parse("19"), Ok(value(19)));
+        assert_eq!(parse("  19 \n"), Ok(value(19)));
+    }
+
+    #[test]
+    fn float_literals() {
+        assert_eq!(parse("2.5"), Ok(float(2.5)));
+        assert_eq!(parse("-0.125"), Ok(float(-0.125)));
+        assert_eq!(parse("1e3"), Ok(float(1000.0)));
+        assert_eq!(parse("2.5E-1"), Ok(float(0.25)));
+        assert_eq!(parse("1.5*2"), Ok(op(Operation::Mul, float(1.5), value(2))));
+        // neither of these is part of the literal
+        assert_eq!(
+            parse("2e"),
+            Err(ParseError { kind: ParseErrorKind::UnexpectedToken("e".to_string()), offset: 1 })
+        );
+        assert_eq!(
+            parse("2."),
+            Err(ParseError { kind: ParseErrorKind::UnexpectedChar('.'), offset: 1 })
+        );
+        assert_eq!(
+            parse("1e999"),
+            Err(ParseError { kind: ParseErrorKind::NumberTooLarge("1e999".to_string()), offset: 0 })
+        );
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(
+            parse("10 * 9 + (3 - 4) * 5"),
+            Ok(op(
+                Operation::Add,
+                op(Operation::Mul, value(10), value(9)),
+                op(Operation::Mul, op(Operation::Sub, value(3), value(4)), value(5)),
+            ))
+        );
+    }
+
+    #[test]
+    fn left_associative() {
+        assert_eq!(
+            parse("1 - 2 - 3"),
+            Ok(op(Operation::Sub, op(Operation::Sub, value(1), value(2)), value(3)))
+        );
+        assert_eq!(
+            parse("8/4/2"),
+            Ok(op(Operation::Div, op(Operation::Div, value(8), value(4)), value(2)))
+        );
+    }
+
+    #[test]
+    fn parentheses() {
+        assert_eq!(
+            parse("1 - (2 - 3)"),
+            Ok(op(Operation::Sub, value(1), op(Operation::Sub, value(2), value(3))))
+        );
+        assert_eq!(parse("((7))"), Ok(value(7)));
+    }
+
+    #[test]
+    fn unary_minus() {
+        assert_eq!(parse("-5"), Ok(value(-5)));
+        assert_eq!(parse("-9223372036854775808"), Ok(value(i64::MIN)));
+        assert_eq!(
+            parse("2 * -(3 + 4)"),
+            Ok(op(
+                Operation::Mul,
+                value(2),
+                op(Operation::Sub, value(0), op(Operation::Add, value(3), value(4))),
+            ))
+        );
+        assert_eq!(parse("--1"), Ok(op(Operation::Sub, value(0), value(-1))));
+    }
+
+    #[test]
+    fn variables() {
+        let var = |name: &str| Expression::Variable(name.to_string());
+        assert_eq!(
+            parse("rate * hours_2 + _bonus"),
+            Ok(op(
+                Operation::Add,
+                op(Operation::Mul, var("rate"), var("hours_2")),
+                var("_bonus"),
+            ))
+        );
+        assert_eq!(parse("-x"), Ok(op(Operation::Sub, value(0), var("x"))));
+    }
+
+    #[test]
+    fn let_binding() {
+        assert_eq!(
+            parse("1 + let x = 2 in x * 3"),
+            Ok(op(
+                Operation::Add,
+                value(1),
+                Expression::Let {
+                    name: "x".to_string(),
+                    value: Box::new(value(2)),
+                    body: Box::new(op(Operation::Mul, Expression::Variable("x".to_string()), value(3))),
+                },
+            ))
+        );
+        assert_eq!(
+            parse("let 5 = 1 in 2"),
+            Err(ParseError { kind: ParseErrorKind::UnexpectedToken("5".to_string()), offset: 4 })
+        );
+        assert_eq!(
+            parse("let x = 1"),
+            Err(ParseError { kind: ParseErrorKind::UnexpectedEnd, offset: 9 })
+        );
+        assert_eq!(
+            parse("let in = 1 in 2"),
+            Err(ParseError { kind: ParseErrorKind::UnexpectedToken("in".to_string()), offset: 4 })
+        );
+    }
+
+    #[test]
+    fn operator_precedence() {
+        assert_eq!(
+            parse("a || b && c | d ^ e & f == g < h << i + j * k"),
+            parse("a || (b && (c | (d ^ (e & (f == (g < (h << (i + (j * k)))))))))")
+        );
+        assert_eq!(
+            parse("1 < 2 == 3 >= 4"),
+            Ok(op(
+                Operation::Eq,
+                op(Operation::Lt, value(1), value(2)),
+                op(Operation::Ge, value(3), value(4)),
+            ))
+        );
+        assert_eq!(
+            parse("7 % 3 * 2"),
+            Ok(op(Operation::Mul, op(Operation::Rem, value(7), value(3)), value(2)))
+        );
+        assert_eq!(parse("a >> b != c <= d"), parse("(a >> b) != (c <= d)"));
+    }
+
+    #[test]
+    fn power() {
+        assert_eq!(
+            parse("2 ** 3 ** 2"),
+            Ok(op(Operation::Pow, value(2), op(Operation::Pow, value(3), value(2))))
+        );
+        assert_eq!(
+            parse("-2 ** 2"),
+            Ok(op(Operation::Sub, value(0), op(Operation::Pow, value(2), value(2))))
+        );
+        assert_eq!(parse("2 ** -1"), Ok(op(Operation::Pow, value(2), value(-1))));
+        assert_eq!(
+            parse("2 * 3 ** 2"),
+            Ok(op(Operation::Mul, value(2), op(Operation::Pow, value(3), value(2))))
+        );
+    }
+
+    #[test]
+    fn conditional() {
+        assert_eq!(
+            parse("if x < 0 then 0 else x + 1"),
+            Ok(Expression::If {
+                cond: Box::new(op(Operation::Lt, Expression::Variable("x".to_string()), value(0))),
+                then: Box::new(value(0)),
+                otherwise: Box::new(op(Operation::Add, Expression::Variable("x".to_string()), value(1))),
+            })
+        );
+        assert_eq!(
+            parse("if 1 then 2"),
+            Err(ParseError { kind: ParseErrorKind::UnexpectedEnd, offset: 11 })
+        );
+        assert_eq!(
+            parse("if 1 else 2"),
+            Err(ParseError { kind: ParseErrorKind::UnexpectedToken("else".to_string()), offset: 5 })
+        );
+    }
+
+    #[test]
+    fn calls() {
+        let var = |name: &str| Expression::Variable(name.to_string());
+        let call = |name: &str, args| Expression::Call { name: name.to_string(), args };
+        assert_eq!(parse("f()"), Ok(call("f", vec![])));
+        assert_eq!(
+            parse("max(a, b + 1) * 2"),
+            Ok(op(
+                Operation::Mul,
+                call("max", vec![var("a"), op(Operation::Add, var("b"), value(1))]),
+                value(2)
+            ))
+        );
+        assert_eq!(parse("abs(abs(-1))"), Ok(call("abs", vec![call("abs", vec![value(-1)])])));
+        // whitespace in front of the parenthesis does not matter
+        assert_eq!(parse("f (1)"), Ok(call("f", vec![value(1)])));
+        assert_eq!(
+            parse("f(1,)"),
+            Err(ParseError { kind: ParseErrorKind::UnexpectedToken(")".to_string()), offset: 4 })
+        );
+        assert_eq!(
+            parse("f(1 2)"),
+            Err(ParseError { kind: ParseErrorKind::UnexpectedToken("2".to_string()), offset: 4 })
+        );
+    }
+
+    #[test]
+    fn function_definition() {
+        let var = |name: &str| Expression::Variable(name.to_string());
+        assert_eq!(
+            parse("let f(x, y) = x * y in f(2, 3)"),
+            Ok(Expression::Function {
+                name: "f".to_string(),
+                params: vec!["x".to_string(), "y".to_string()],
+                body: Box::new(op(Operation::Mul, var("x"), var("y"))),
+                rest: Box::new(Expression::Call {
+                    name: "f".to_string(),
+                    args: vec![value(2), value(3)],
+                }),
+            })
+        );
+        let no_params = parse("let f() = 1 in f()");
+        assert!(matches!(&no_params, Ok(Expression::Function { params, .. }) if params.is_empty()));
+        assert_eq!(
+            parse("let f(1) = 1 in 2"),
+            Err(ParseError { kind: ParseErrorKind::UnexpectedToken("1".to_string()), offset: 6 })
+        );
+        assert_eq!(
+            parse("let f(x) = x"),
+            Err(ParseError { kind: ParseErrorKind::UnexpectedEnd, offset: 12 })
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            parse("1 + $"),
+            Err(ParseError { kind: ParseErrorKind::UnexpectedChar('$'), offset: 4 })
+        );
+        assert_eq!(parse("1 +"), Err(ParseError { kind: ParseErrorKind::UnexpectedEnd, offset: 3 }));
+        assert_eq!(parse("(1 + 2"), Err(ParseError { kind: ParseErrorKind::UnexpectedEnd, offset: 6 }));
+        assert_eq!(
+            parse("1 2"),
+            Err(ParseError { kind: ParseErrorKind::UnexpectedToken("2".to_string()), offset: 2 })
+        );
+        assert_eq!(
+            parse("(1 + 2))"),
+            Err(ParseError { kind: ParseErrorKind::UnexpectedToken(")".to_string()), offset: 7 })
+        );
+        assert_eq!(
+            parse("* 3"),
+            Err(ParseError { kind: ParseErrorKind::UnexpectedToken("*".to_string()), offset: 0 })
+        );
+        assert_eq!(
+            parse("9223372036854775808"),
+            Err(ParseError {
+                kind: ParseErrorKind::NumberTooLarge("9223372036854775808".to_string()),
+                offset: 0
+            })
+        );
+        assert_eq!(parse(""), Err(ParseError { kind: ParseErrorKind::UnexpectedEnd, offset: 0 }));
+        assert_eq!(
+            parse("1 ! 2"),
+            Err(ParseError { kind: ParseErrorKind::UnexpectedChar('!'), offset: 2 })
+        );
+    }
+
+    #[test]
+    fn error_display() {
+        let error = parse("1 + é").unwrap_err();
+        assert_eq!(error.to_string(), "unexpected character 'é' at offset 4");
+    }
+
+    #[test]
+    fn nesting() {
+        let nested = |open: &str, depth| format!("{}1{}", open.repeat(depth), ")".repeat(depth));
+        assert_eq!(parse(&nested("(", MAX_NESTING - 1)), Ok(value(1)));
+        let error = parse(&nested("(", MAX_NESTING)).unwrap_err();
+        assert_eq!(error, ParseError { kind: ParseErrorKind::TooDeep, offset: MAX_NESTING });
+        assert_eq!(error.to_string(), format!("expression nested too deeply at offset {MAX_NESTING}"));
+
+        // far too deep is an error too, rather than a stack overflow
+        for open in ["(", "-(", "2 ** (", "f(", "let x = 1 in (", "if 1 then ("] {
+            let error = parse(&nested(open, 100_000)).unwrap_err();
+            assert_eq!(error.kind, ParseErrorKind::TooDeep, "{open}");
+        }
+        assert_eq!(parse(&"-".repeat(100_000)).unwrap_err().kind, ParseErrorKind::TooDeep);
+        // long expressions that do not nest are fine
+        assert!(parse(&vec!["1"; 100_000].join(" + ")).is_ok());
+    }
+}
@@ -0,0 +1,296 @@
+use std::fmt;
+
+use super::parser::{binding_power, UNARY_POWER};
+use super::{Expression, Number, Operation};
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let symbol = match self {
+            Operation::Add => "+",
+            Operation::Sub => "-",
+            Operation::Mul => "*",
+            Operation::Div => "/",
+            Operation::Rem => "%",
+            Operation::Pow => "**",
+            Operation::BitAnd => "&",
+            Operation::BitOr => "|",
+            Operation::BitXor => "^",
+            Operation::Shl => "<<",
+            Operation::Shr => ">>",
+            Operation::Lt => "<",
+            Operation::Le => "<=",
+            Operation::Gt => ">",
+            Operation::Ge => ">=",
+            Operation::Eq => "==",
+            Operation::Ne => "!=",
+            Operation::And => "&&",
+            Operation::Or => "||",
+        };
+        write!(f, "{symbol}")
+    }
+}
+
+/// How tightly `e` holds together when printed, in the same terms as
+/// `binding_power`. `let` and `if` reach as far right as they can, so they
+/// hold together least of all.
+fn power(e: &Expression) -> u8 {
+    match e {
+        Expression::Op { op, .. } => binding_power(*op),
+        // a fraction is printed as the division that gives it
+        Expression::Value(Number::Rational(_)) => binding_power(Operation::Div),
+        Expression::Value(val) if val.is_sign_negative() => UNARY_POWER,
+        Expression::Value(_) | Expression::Variable(_) | Expression::Call { .. } => u8::MAX,
+        Expression::Let { .. } | Expression::If { .. } | Expression::Function { .. } => 0,
+    }
+}
+
+/// Print `e` in infix notation. `last` says whether `e` ends the
+/// surrounding expression (nothing but a closing parenthesis or a keyword
+/// follows it), in which case a `let` or `if` needs no parentheses.
+fn infix(e: &Expression, last: bool, f: &mut fmt::Formatter) -> fmt::Result {
+    match e {
+        Expression::Op { op, left, right } => {
+            let power = binding_power(*op);
+            let (left_parens, right_parens) = if *op == Operation::Pow {
+                // right associative, and the exponent may be negated
+                (self::power(left) <= power, self::power(right) < UNARY_POWER)
+            } else {
+                (self::power(left) < power, self::power(right) <= power)
+            };
+            operand(left, left_parens, false, f)?;
+            write!(f, " {op} ")?;
+            operand(right, right_parens, last, f)
+        }
+        Expression::Value(val) => write!(f, "{val}"),
+        Expression::Variable(name) => write!(f, "{name}"),
+        Expression::Let { name, value, body } => {
+            write!(f, "let {name} = ")?;
+            infix(value, true, f)?;
+            write!(f, " in ")?;
+            infix(body, last, f)
+        }
+        Expression::If { cond, then, otherwise } => {
+            write!(f, "if ")?;
+            infix(cond, true, f)?;
+            write!(f, " then ")?;
+            infix(then, true, f)?;
+            write!(f, " else ")?;
+            infix(otherwise, last, f)
+        }
+        Expression::Call { name, args } => {
+            write!(f, "{name}(")?;
+            for (i, arg) in args.iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                infix(arg, true, f)?;
+            }
+            write!(f, ")")
+        }
+        Expression::Function { name, params, body, rest } => {
+            write!(f, "let {name}({}) = ", params.join(", "))?;
+            infix(body, true, f)?;
+            write!(f, " in ")?;
+            infix(rest, last, f)
+        }
+    }
+}
+
+/// Print an operand, in parentheses if precedence needs them or if it is a
+/// `let` or `if` that something else follows.
+fn operand(e: &Expression, parens: bool, last: bool, f: &mut fmt::Formatter) -> fmt::Result {
+    let open_ended =
+        matches!(e, Expression::Let { .. } | Expression::If { .. } | Expression::Function { .. });
+    if parens && !(open_ended && last) {
+        write!(f, "(")?;
+        infix(e, true, f)?;
+        write!(f, ")")
+    } else {
+        infix(e, last, f)
+    }
+}
+
+/// Infix notation with only the parentheses that precedence requires, for
+/// example `(1 + 2) * 3 - 4`. The output parses back into the same tree,
+/// except for fractions, which come back as the division `7 / 2` that
+/// evaluates to them, and infinite or `NaN` floats, which have no literal.
+/// Printing recurses once per level of the tree.
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        infix(self, true, f)
+    }
+}
+
+/// Prefix notation, as S-expressions: `(- (* (+ 1 2) 3) 4)`. A call is
+/// written `(max a b)`, a function definition `(let (f x y) body rest)`.
+pub struct Prefix<'e>(&'e Expression);
+
+impl fmt::Display for Prefix<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Expression::Op { op, left, right } => {
+                write!(f, "({op} {} {})", Prefix(left), Prefix(right))
+            }
+            Expression::Value(val) => write!(f, "{val}"),
+            Expression::Variable(name) => write!(f, "{name}"),
+            Expression::Let { name, value, body } => {
+                write!(f, "(let {name} {} {})", Prefix(value), Prefix(body))
+            }
+            Expression::If { cond, then, otherwise } => {
+                write!(f, "(if {} {} {})", Prefix(cond), Prefix(then), Prefix(otherwise))
+            }
+            Expression::Call { name, args } => {
+                write!(f, "({name}")?;
+                for arg in args {
+                    write!(f, " {}", Prefix(arg))?;
+                }
+                write!(f, ")")
+            }
+            Expression::Function { name, params, body, rest } => {
+                write!(f, "(let ({name}")?;
+                for param in params {
+                    write!(f, " {param}")?;
+                }
+                write!(f, ") {} {})", Prefix(body), Prefix(rest))
+            }
+        }
+    }
+}
+
+/// Reverse Polish notation: `1 2 + 3 * 4 -`. A `let` is written as its
+/// value, its body and then `let:name`, an `if` as its condition, both
+/// branches and then `if`. A call is its arguments and then the name with
+/// the argument count, as in `a b max/2`, and a function definition its
+/// body, the rest and then `let:f(x,y)`.
+pub struct Rpn<'e>(&'e Expression);
+
+impl fmt::Display for Rpn<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Expression::Op { op, left, right } => write!(f, "{} {} {op}", Rpn(left), Rpn(right)),
+            Expression::Value(val) => write!(f, "{val}"),
+            Expression::Variable(name) => write!(f, "{name}"),
+            Expression::Let { name, value, body } => {
+                write!(f, "{} {} let:{name}", Rpn(value), Rpn(body))
+            }
+            Expression::If { cond, then, otherwise } => {
+                write!(f, "{} {} {} if", Rpn(cond), Rpn(then), Rpn(otherwise))
+            }
+            Expression::Call { name, args } => {
+                for arg in args {
+                    write!(f, "{} ", Rpn(arg))?;
+                }
+                write!(f, "{name}/{}", args.len())
+            }
+            Expression::Function { name, params, body, rest } => {
+                write!(f, "{} {} let:{name}({})", Rpn(body), Rpn(rest), params.join(","))
+            }
+        }
+    }
+}
+
+impl Expression {
+    /// Display this expression in prefix notation.
+    pub fn prefix(&self) -> Prefix<'_> {
+        Prefix(self)
+    }
+
+    /// Display this expression in reverse Polish notation.
+    pub fn rpn(&self) -> Rpn<'_> {
+        Rpn(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::epression_evaluation::optimizer::Optimizer;
+    use crate::epression_evaluation::parser::parse;
+    use crate::epression_evaluation::random::Rng;
+
+    /// Print the parsed input back out again.
+    fn reprint(input: &str) -> String {
+        parse(input).unwrap().to_string()
+    }
+
+    #[test]
+    fn minimal_parentheses() {
+        assert_eq!(reprint("10 * 9 + (3 - 4) * 5"), "10 * 9 + (3 - 4) * 5");
+        assert_eq!(reprint("((1 + 2)) + (3)"), "1 + 2 + 3");
+        assert_eq!(reprint("1 + (2 + 3)"), "1 + (2 + 3)");
+        assert_eq!(reprint("1 - (2 - 3)"), "1 - (2 - 3)");
+        assert_eq!(reprint("(1 * 2) + (3 * 4)"), "1 * 2 + 3 * 4");
+        assert_eq!(reprint("(a || b) && (c | d)"), "(a || b) && c | d");
+        assert_eq!(reprint("(1 < 2) == (3 << 4)"), "1 < 2 == 3 << 4");
+        assert_eq!(reprint("a-b"), "a - b");
+    }
+
+    #[test]
+    fn powers_and_negatives() {
+        assert_eq!(reprint("2 ** 3 ** 2"), "2 ** 3 ** 2");
+        assert_eq!(reprint("(2 ** 3) ** 2"), "(2 ** 3) ** 2");
+        assert_eq!(reprint("(-2) ** 2"), "(-2) ** 2");
+        assert_eq!(reprint("-2 ** 2"), "0 - 2 ** 2");
+        assert_eq!(reprint("2 ** -1"), "2 ** -1");
+        assert_eq!(reprint("2 ** (1 + 1)"), "2 ** (1 + 1)");
+        assert_eq!(reprint("a - -5 * -9223372036854775808"), "a - -5 * -9223372036854775808");
+        assert_eq!(reprint("-2.5 ** 2 + 1e300"), "0 - 2.5 ** 2 + 1e300");
+        assert_eq!(reprint("(-0.5) ** 2"), "(-0.5) ** 2");
+    }
+
+    #[test]
+    fn fractions() {
+        let e = Optimizer::new().optimize(&parse("x ** (-7 / 2) / (1 / 3)").unwrap());
+        assert_eq!(e.to_string(), "x ** (-7/2) / (1/3)");
+        assert_eq!(e.prefix().to_string(), "(/ (** x -7/2) 1/3)");
+    }
+
+    #[test]
+    fn let_and_if() {
+        assert_eq!(reprint("let x = 1 + 2 in x * x"), "let x = 1 + 2 in x * x");
+        assert_eq!(reprint("1 + (let x = 2 in x)"), "1 + let x = 2 in x");
+        assert_eq!(reprint("(let x = 2 in x) + 1"), "(let x = 2 in x) + 1");
+        // the closing parenthesis ends the `let` just as well
+        assert_eq!(reprint("(1 + (let x = 2 in x)) * 3"), "(1 + let x = 2 in x) * 3");
+        assert_eq!(
+            reprint("if (if a then b else c) then (let y = 1 in y) else (if d then e else f)"),
+            "if if a then b else c then let y = 1 in y else if d then e else f"
+        );
+        assert_eq!(reprint("(if a then b else c) ** 2"), "(if a then b else c) ** 2");
+    }
+
+    #[test]
+    fn functions() {
+        assert_eq!(
+            reprint("max((1 + 2), abs(x - 1) * 2, (let y = 1 in y))"),
+            "max(1 + 2, abs(x - 1) * 2, let y = 1 in y)"
+        );
+        assert_eq!(reprint("f() ** 2"), "f() ** 2");
+        assert_eq!(reprint("let f(x,y) = x * y in f(2, 3) + 1"), "let f(x, y) = x * y in f(2, 3) + 1");
+        assert_eq!(reprint("(let f() = 1 in f()) + 1"), "(let f() = 1 in f()) + 1");
+
+        let e = parse("let sq(x) = x * x in max(sq(2), 3)").unwrap();
+        assert_eq!(e.prefix().to_string(), "(let (sq x) (* x x) (max (sq 2) 3))");
+        assert_eq!(e.rpn().to_string(), "x x * 2 sq/1 3 max/2 let:sq(x)");
+    }
+
+    #[test]
+    fn prefix_and_rpn() {
+        let e = parse("(1 + 2) * 3 - -4").unwrap();
+        assert_eq!(e.prefix().to_string(), "(- (* (+ 1 2) 3) -4)");
+        assert_eq!(e.rpn().to_string(), "1 2 + 3 * -4 -");
+
+        let e = parse("let x = 2 in if x > 1 then x else 0").unwrap();
+        assert_eq!(e.prefix().to_string(), "(let x 2 (if (> x 1) x 0))");
+        assert_eq!(e.rpn().to_string(), "2 x 1 > x 0 if let:x");
+    }
+
+    #[test]
+    fn round_trips_through_the_parser() {
+        let mut rng = Rng::new(9);
+        for _ in 0..5_000 {
+            let e = rng.expression(5);
+            let printed = e.to_string();
+            assert_eq!(parse(&printed).as_ref(), Ok(&e), "{printed}");
+        }
+    }
+}
@@ -0,0 +1,128 @@
+// Random expression trees, for property tests that check two ways of doing
+// the same thing agree. There are no external crates in this project, so
+// this is a tiny xorshift generator rather than proptest or quickcheck.
+
+use super::number::{Number, Rational};
+use super::{Environment, Expression, Operation};
+
+pub const OPERATIONS: [Operation; 19] = [
+    Operation::Add,
+    Operation::Sub,
+    Operation::Mul,
+    Operation::Div,
+    Operation::Rem,
+    Operation::Pow,
+    Operation::BitAnd,
+    Operation::BitOr,
+    Operation::BitXor,
+    Operation::Shl,
+    Operation::Shr,
+    Operation::Lt,
+    Operation::Le,
+    Operation::Gt,
+    Operation::Ge,
+    Operation::Eq,
+    Operation::Ne,
+    Operation::And,
+    Operation::Or,
+];
+
+/// Values that tend to find edge cases.
+const VALUES: [i64; 10] = [0, 1, -1, 2, 3, 7, -8, 63, i64::MAX, i64::MIN];
+
+/// The same for floats. Every one of them can be written as a literal.
+const FLOATS: [f64; 6] = [0.0, -0.0, 0.5, -2.5, 1e300, f64::MAX];
+
+/// `x`, `y` and `z` are bound by `environment`, `missing` never is.
+const VARIABLES: [&str; 4] = ["x", "y", "z", "missing"];
+
+/// The built-in functions, then the ones expressions define and one that
+/// is never defined anywhere.
+const FUNCTIONS: [&str; 7] = ["abs", "min", "max", "clamp", "f", "g", "missing"];
+
+/// Parameters shadow the environment, but not always all of it.
+const PARAMETERS: [&str; 2] = ["y", "x"];
+
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        // xorshift gets stuck on zero
+        Rng(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    pub fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// A number in `0..n`.
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    pub fn pick<T: Copy>(&mut self, items: &[T]) -> T {
+        items[self.below(items.len())]
+    }
+
+    /// An integer or, now and then, a float.
+    pub fn value(&mut self) -> Number {
+        match self.below(8) {
+            0 | 1 => Number::Int(self.next() as i64),
+            2 => Number::Float(self.pick(&FLOATS)),
+            _ => Number::Int(self.pick(&VALUES)),
+        }
+    }
+
+    /// Any number, including fractions, which have no literal of their
+    /// own.
+    pub fn number(&mut self) -> Number {
+        if self.below(4) == 0 {
+            let denom = self.pick(&VALUES).max(2);
+            Rational::new(self.pick(&VALUES), denom).map_or(Number::Int(0), Number::from)
+        } else {
+            self.value()
+        }
+    }
+
+    /// A random tree at most `depth` levels deep.
+    pub fn expression(&mut self, depth: usize) -> Expression {
+        self.tree(depth, true)
+    }
+
+    /// A random tree that only calls the functions an expression defines
+    /// if `recursive` is set. Function bodies never do, so that no
+    /// function can call itself over and over.
+    fn tree(&mut self, depth: usize, recursive: bool) -> Expression {
+        let boxed = |rng: &mut Rng| Box::new(rng.tree(depth - 1, recursive));
+        match if depth == 0 { self.below(2) } else { self.below(12) } {
+            0 => Expression::Value(self.value()),
+            1 => Expression::Variable(self.pick(&VARIABLES).to_string()),
+            2 => Expression::Let {
+                name: self.pick(&VARIABLES).to_string(),
+                value: boxed(self),
+                body: boxed(self),
+            },
+            3 => Expression::If { cond: boxed(self), then: boxed(self), otherwise: boxed(self) },
+            4 => {
+                let functions = if recursive { &FUNCTIONS[..] } else { &FUNCTIONS[..4] };
+                let args = (0..self.below(4)).map(|_| self.tree(depth - 1, recursive)).collect();
+                Expression::Call { name: self.pick(functions).to_string(), args }
+            }
+            5 => Expression::Function {
+                name: self.pick(&FUNCTIONS[4..6]).to_string(),
+                params: PARAMETERS[..self.below(3)].iter().map(|param| param.to_string()).collect(),
+                body: Box::new(self.tree(depth - 1, false)),
+                rest: boxed(self),
+            },
+            _ => Expression::Op { op: self.pick(&OPERATIONS), left: boxed(self), right: boxed(self) },
+        }
+    }
+
+    /// An environment binding `x`, `y` and `z`.
+    pub fn environment(&mut self) -> Environment {
+        Environment::new().with("x", self.number()).with("y", self.number()).with("z", self.number())
+    }
+}
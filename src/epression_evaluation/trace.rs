@@ -0,0 +1,125 @@
+use std::fmt;
+
+use super::{Environment, EvalError, Evaluator, Expression, Number, Operation, Path};
+
+/// One subexpression that evaluation reduced to a value.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Step {
+    /// Where the subexpression is, through the calls it was reached by.
+    pub path: Path,
+    /// The subexpression with its operands replaced by their values, as in
+    /// `-1 * 5`. A variable is just its name.
+    pub reduced: String,
+    pub value: Number,
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} => {}", self.reduced, self.value)
+    }
+}
+
+/// Every step of an evaluation, in the order they happened: operands come
+/// before the operation they belong to, so the last step is the result.
+/// Plain numbers, `let`s and `if`s are not steps of their own, they only
+/// pass on a value.
+///
+/// Printing gives one step per line, and with `{:#}` the path of each.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Trace(pub Vec<Step>);
+
+impl fmt::Display for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, step) in self.0.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            if f.alternate() {
+                write!(f, "{}: ", step.path)?;
+            }
+            write!(f, "{step}")?;
+        }
+        Ok(())
+    }
+}
+
+/// `left op right` with both operands already worked out.
+pub(super) fn operation(op: Operation, left: Number, right: Number) -> String {
+    let reduced = Expression::Op {
+        op,
+        left: Box::new(Expression::Value(left)),
+        right: Box::new(Expression::Value(right)),
+    };
+    reduced.to_string()
+}
+
+/// A logical operator whose right operand was never needed.
+pub(super) fn short_circuit(op: Operation, left: Number) -> String {
+    format!("{} {op} ...", Expression::Value(left))
+}
+
+/// A call with its arguments worked out.
+pub(super) fn call(name: &str, args: &[Number]) -> String {
+    let args = args.iter().map(|arg| Expression::Value(*arg)).collect();
+    Expression::Call { name: name.to_string(), args }.to_string()
+}
+
+impl Evaluator {
+    /// Evaluate like `eval_in`, also recording each subexpression as it is
+    /// reduced to a value. The trace is returned on failure as well, and
+    /// then ends with the last step that succeeded.
+    pub fn trace_in(&self, e: &Expression, env: &Environment) -> (Result<Number, EvalError>, Trace) {
+        let (result, steps) = self.evaluate(e, env, Some(Vec::new()));
+        (result, Trace(steps.unwrap_or_default()))
+    }
+
+    /// Trace an expression that has no free variables.
+    pub fn trace(&self, e: &Expression) -> (Result<Number, EvalError>, Trace) {
+        self.trace_in(e, &Environment::new())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::epression_evaluation::{parser, Branch};
+
+    fn reductions(input: &str, env: &Environment) -> Vec<String> {
+        let (_, trace) = Evaluator::new().trace_in(&parser::parse(input).unwrap(), env);
+        trace.0.iter().map(Step::to_string).collect()
+    }
+
+    #[test]
+    fn records_each_reduction() {
+        let e = parser::parse("10 * 9 + (3 - 4) * 5").unwrap();
+        let (result, trace) = Evaluator::new().trace(&e);
+        assert_eq!(result, Ok(Number::Int(85)));
+        assert_eq!(trace.to_string(), "10 * 9 => 90\n3 - 4 => -1\n-1 * 5 => -5\n90 + -5 => 85");
+        assert_eq!(trace.0[1].path, Path(vec![Branch::Right, Branch::Left]));
+        assert_eq!(format!("{trace:#}").lines().last(), Some("root: 90 + -5 => 85"));
+    }
+
+    #[test]
+    fn variables_branches_and_calls() {
+        let env = Environment::new().with("hours", 45).with("rate", 20);
+        assert_eq!(
+            reductions("if hours > 40 then max(rate, 7 / 2) else 0", &env),
+            ["hours => 45", "45 > 40 => 1", "rate => 20", "7 / 2 => 7/2", "max(20, 7/2) => 20"]
+        );
+        assert_eq!(reductions("0 && missing || 1", &env), ["0 && ... => 0", "0 || 1 => 1"]);
+        assert_eq!(
+            reductions("let sq(x) = x * x in sq(3) + 1", &env),
+            ["x => 3", "x => 3", "3 * 3 => 9", "sq(3) => 9", "9 + 1 => 10"]
+        );
+    }
+
+    #[test]
+    fn stops_at_errors() {
+        let e = parser::parse("1 + 2 + 3 / (2 - 2)").unwrap();
+        let (result, trace) = Evaluator::new().trace(&e);
+        assert!(matches!(result, Err(EvalError::DivisionByZero { .. })));
+        assert_eq!(trace.to_string(), "1 + 2 => 3\n2 - 2 => 0");
+        // tracing changes nothing about the result
+        assert_eq!(result, Evaluator::new().eval(&e));
+    }
+}
@@ -0,0 +1,8 @@
+// the exercises that grew into something reusable live in the library half
+// of the crate, so that binaries and benchmarks can get at them as well as
+// main.rs
+pub mod cipher;
+pub mod epression_evaluation;
+pub mod package_builder;
+pub mod rot13;
+pub mod transform;
@@ -2,18 +2,17 @@ mod arrays;
 mod references;
 mod user_types;
 mod pattern_matching;
-mod epression_evaluation;
 mod methods;
 mod traits;
 mod logger_exercise;
 mod generics;
 mod generic_min;
 mod standard_lib;
-mod rot13;
 mod memory_management;
-mod package_builder;
 
 use user_types::Point;
+use rust_book_google::package_builder;
+use rust_book_google::rot13;
 
 // simple mapping of the function from arrays.rs
 // note we don't need crate here
@@ -1,21 +1,42 @@
+pub mod registry;
+pub mod version;
+
+use registry::Registry;
+use version::{Version, VersionError, VersionReq};
+
 #[derive(Debug)]
-enum Language {
+pub enum Language {
     Rust,
     Java,
     Perl,
 }
 
 #[derive(Clone, Debug)]
-struct Dependency {
+pub struct Dependency {
     name: String,
-    version_expression: String,
+    version_expression: VersionReq,
+}
+
+impl Dependency {
+    pub fn new(name: impl Into<String>, version_expression: VersionReq) -> Self {
+        Dependency{name: name.into(), version_expression}
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The versions of the package that will do.
+    pub fn version_expression(&self) -> &VersionReq {
+        &self.version_expression
+    }
 }
 
 /// A representation of a software package.
 #[derive(Debug)]
-struct Package {
+pub struct Package {
     name: String,
-    version: String,
+    version: Version,
     authors: Vec<String>,
     dependencies: Vec<Dependency>,
     language: Option<Language>,
@@ -24,21 +45,22 @@ struct Package {
 impl Package {
     /// Return a representation of this package as a dependency, for use in
     /// building other packages.
-    fn as_dependency(&self) -> Dependency {
-        Dependency{name: self.name.clone(), version_expression: self.version.clone()}
+    /// Any version compatible with this one will do.
+    pub fn as_dependency(&self) -> Dependency {
+        Dependency{name: self.name.clone(), version_expression: VersionReq::caret(&self.version)}
     }
 }
 
 /// A builder for a Package. Use `build()` to create the `Package` itself.
-struct PackageBuilder(Package);
+pub struct PackageBuilder(Package);
 
 impl PackageBuilder {
     // remember, this implies that name must be a type that we can 
     // convert into a string
-    fn new(name: impl Into<String>) -> Self {
+    pub fn new(name: impl Into<String>) -> Self {
         PackageBuilder(Package{
             name: name.into(),
-            version: "0.1".to_string(),
+            version: Version::new(0, 1, 0),
             authors: vec![],
             dependencies: vec![],
             language: None,
@@ -50,13 +72,15 @@ impl PackageBuilder {
     /// a reference, it consumes self). But then it returns Self. This makes
     /// it very convenient to construct the package piece by piece, by
     /// continously using the . stuff to add new values (see function below)
-    fn version(mut self, version: impl Into<String>) -> Self {
-        self.0.version = version.into();
-        self
+    /// The version has to be a valid semantic version such as "1.2.3",
+    /// otherwise the builder is gone and we get told why instead.
+    pub fn version(mut self, version: &str) -> Result<Self, VersionError> {
+        self.0.version = version.parse()?;
+        Ok(self)
     }
 
     /// Set the package authors.
-    fn authors(mut self, authors: Vec<String>) -> Self {
+    pub fn authors(mut self, authors: Vec<String>) -> Self {
         for author in authors {
             self.0.authors.push(author);
         }
@@ -64,33 +88,78 @@ impl PackageBuilder {
     }
 
     /// Add an additional dependency.
-    fn dependency(mut self, dependency: Dependency) -> Self {
+    pub fn dependency(mut self, dependency: Dependency) -> Self {
         self.0.dependencies.push(dependency);
         self
     }
 
     /// Set the language. If not set, language defaults to None.
-    fn language(mut self, language: Language) -> Self {
+    pub fn language(mut self, language: Language) -> Self {
         self.0.language = Some(language);
         self
     }
 
-    fn build(self) -> Package {
+    pub fn build(self) -> Package {
         self.0
     }
 }
 
 pub fn test_package_builder() {
-    let base64 = PackageBuilder::new("base64").version("0.13").build();
+    let base64 = PackageBuilder::new("base64").version("0.13.1").unwrap().build();
     println!("base64: {base64:?}");
     let log =
-        PackageBuilder::new("log").version("0.4").language(Language::Rust).build();
+        PackageBuilder::new("log").version("0.4.20").unwrap().language(Language::Rust).build();
     println!("log: {log:?}");
     let serde = PackageBuilder::new("serde")
         .authors(vec!["djmitche".into()])
-        .version(String::from("4.0"))
+        .version("4.0.0").unwrap()
         .dependency(base64.as_dependency())
         .dependency(log.as_dependency())
         .build();
     println!("serde: {serde:?}");
+
+    // a version has to be a real version now
+    if let Err(error) = PackageBuilder::new("broken").version("4.0") {
+        println!("broken: {error}");
+    }
+
+    // and with every version of everything in one place, we can work out
+    // which ones to use
+    let mut registry = Registry::new();
+    registry.publish(base64);
+    registry.publish(PackageBuilder::new("log").version("0.4.21").unwrap().build());
+    registry.publish(log);
+    let app = PackageBuilder::new("app").dependency(serde.as_dependency()).build();
+    registry.publish(serde);
+    match registry.resolve(&app) {
+        Ok(chosen) => {
+            for (name, version) in chosen {
+                println!("app uses {name} {version}");
+            }
+        }
+        Err(error) => println!("app cannot be built: {error}"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn validates_versions() {
+        let package = PackageBuilder::new("log").version("0.4.20-rc.1").unwrap().build();
+        assert_eq!(package.version.to_string(), "0.4.20-rc.1");
+        assert_eq!(PackageBuilder::new("log").build().version, Version::new(0, 1, 0));
+        let error = PackageBuilder::new("log").version("0.4").err().unwrap();
+        assert_eq!(error.to_string(), "expected \".\" at offset 3");
+    }
+
+    #[test]
+    fn dependencies_take_compatible_versions() {
+        let log = PackageBuilder::new("log").version("0.4.20").unwrap().build();
+        let requirement = log.as_dependency().version_expression;
+        assert_eq!(requirement.to_string(), "^0.4.20");
+        assert!(requirement.matches(&"0.4.21".parse().unwrap()));
+        assert!(!requirement.matches(&"0.5.0".parse().unwrap()));
+    }
 }
\ No newline at end of file
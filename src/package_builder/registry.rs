@@ -0,0 +1,421 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use super::version::{Version, VersionReq};
+use super::Package;
+
+/// Every published version of every package.
+#[derive(Debug, Default)]
+pub struct Registry {
+    packages: HashMap<String, BTreeMap<Version, Package>>,
+}
+
+/// A requirement on a package, and which package has it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Demand {
+    pub requirement: VersionReq,
+    /// The name and version of the package, as in `serde 4.0.0`.
+    pub required_by: String,
+}
+
+impl fmt::Display for Demand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} required by {}", self.requirement, self.required_by)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ResolveError {
+    /// A package that is not in the registry at all.
+    UnknownPackage { name: String, required_by: String },
+    /// No version of a package satisfies all of the demands on it, along
+    /// with the versions there are to choose from.
+    Conflict { name: String, demands: Vec<Demand>, available: Vec<Version> },
+}
+
+impl fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ResolveError::UnknownPackage { name, required_by } => {
+                write!(f, "there is no package {name}, required by {required_by}")
+            }
+            ResolveError::Conflict { name, demands, available } => {
+                writeln!(f, "no version of {name} satisfies every requirement on it:")?;
+                for demand in demands {
+                    writeln!(f, "  {demand}")?;
+                }
+                let available: Vec<String> = available.iter().map(Version::to_string).collect();
+                write!(f, "available versions: {}", available.join(", "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for ResolveError {}
+
+/// A demand that still has to be met while resolving.
+#[derive(Clone)]
+struct Pending {
+    name: String,
+    demand: Demand,
+}
+
+/// A step of resolving, to be undone when a choice before it turns out not
+/// to work.
+enum Undo {
+    Demand(String),
+    Chosen(String),
+    /// A demand taken off the pending ones.
+    Popped(Pending),
+}
+
+/// A package with versions to choose from. Instead of recursing once per
+/// dependency (which overflows the stack on large registries), resolving
+/// keeps a stack of these to go back to.
+struct Choice<'r> {
+    name: String,
+    /// The versions that meet the demands on it, newest first.
+    candidates: Vec<&'r Package>,
+    /// How many of the candidates have been tried.
+    tried: usize,
+    /// How many demands were still to be met when the choice came up.
+    pending: usize,
+    /// How many steps there were when the choice came up.
+    steps: usize,
+    /// What to report if no candidate works.
+    reported: Option<ResolveError>,
+}
+
+/// What resolving has settled on so far.
+#[derive(Default)]
+struct Resolution<'r> {
+    chosen: BTreeMap<String, Version>,
+    /// Every demand met so far, per package.
+    demands: HashMap<String, Vec<Demand>>,
+    pending: Vec<Pending>,
+    /// How `chosen`, `demands` and `pending` got here, in order. Only the
+    /// demands a choice brings along are not in it: they are all added at
+    /// once, and taken off again with the choice.
+    steps: Vec<Undo>,
+    choices: Vec<Choice<'r>>,
+}
+
+impl Resolution<'_> {
+    /// Undo every step after the first `steps`, latest first.
+    fn undo(&mut self, steps: usize) {
+        for step in self.steps.drain(steps..).rev() {
+            match step {
+                Undo::Popped(pending) => self.pending.push(pending),
+                Undo::Demand(name) => {
+                    self.demands.get_mut(&name).unwrap().pop();
+                }
+                Undo::Chosen(name) => {
+                    self.chosen.remove(&name);
+                }
+            }
+        }
+    }
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Registry::default()
+    }
+
+    /// Add a package to the registry, replacing any with the same name and
+    /// version.
+    pub fn publish(&mut self, package: Package) {
+        let versions = self.packages.entry(package.name.clone()).or_default();
+        versions.insert(package.version.clone(), package);
+    }
+
+    pub fn get(&self, name: &str, version: &Version) -> Option<&Package> {
+        self.packages.get(name)?.get(version)
+    }
+
+    /// All versions of a package, newest first.
+    pub fn versions(&self, name: &str) -> impl Iterator<Item = &Package> {
+        self.packages.get(name).into_iter().flat_map(|versions| versions.values().rev())
+    }
+
+    /// Pick one version of every package `root` depends on, directly or
+    /// not, so that every dependency is satisfied. Newer versions are
+    /// tried first, and an older one only when the newer ones lead to a
+    /// conflict further down; so this may try many combinations before
+    /// finding one, or finding out there is none.
+    ///
+    /// When there is none, the conflict reported is what stopped the
+    /// newest version of a package, unless all that stopped it was a
+    /// demand for another version of that same package: then it is what
+    /// stopped the other versions.
+    pub fn resolve(&self, root: &Package) -> Result<BTreeMap<String, Version>, ResolveError> {
+        let mut resolution = Resolution { pending: self.demands_of(root), ..Resolution::default() };
+        loop {
+            match self.meet_pending(&mut resolution) {
+                Ok(()) => return Ok(resolution.chosen),
+                Err(error) => self.backtrack(&mut resolution, error)?,
+            }
+        }
+    }
+
+    fn demands_of(&self, package: &Package) -> Vec<Pending> {
+        let required_by = format!("{} {}", package.name, package.version);
+        // in reverse, so that the first dependency is the first one off
+        // the stack
+        let demands = package.dependencies.iter().rev().map(|dependency| Pending {
+            name: dependency.name.clone(),
+            demand: Demand {
+                requirement: dependency.version_expression.clone(),
+                required_by: required_by.clone(),
+            },
+        });
+        demands.collect()
+    }
+
+    /// Meet every pending demand, and all the ones that come with the
+    /// versions chosen along the way, taking the newest version that will
+    /// do whenever there is a choice.
+    fn meet_pending<'r>(&'r self, resolution: &mut Resolution<'r>) -> Result<(), ResolveError> {
+        while let Some(pending) = resolution.pending.pop() {
+            let Pending { name, demand } = pending.clone();
+            resolution.steps.push(Undo::Popped(pending));
+            let Some(versions) = self.packages.get(&name) else {
+                return Err(ResolveError::UnknownPackage { name, required_by: demand.required_by });
+            };
+            resolution.demands.entry(name.clone()).or_default().push(demand);
+            resolution.steps.push(Undo::Demand(name.clone()));
+            let demands = &resolution.demands[&name];
+            if let Some(version) = resolution.chosen.get(&name) {
+                // chosen already, so the new demand has to go along with it
+                if !demands.last().unwrap().requirement.matches(version) {
+                    return Err(self.conflict(&name, demands));
+                }
+                continue;
+            }
+
+            let candidates: Vec<&Package> = versions
+                .values()
+                .rev()
+                .filter(|package| demands.iter().all(|d| d.requirement.matches(&package.version)))
+                .collect();
+            if candidates.is_empty() {
+                return Err(self.conflict(&name, demands));
+            }
+            let pending = resolution.pending.len();
+            let steps = resolution.steps.len();
+            let choice = Choice { name, candidates, tried: 0, pending, steps, reported: None };
+            self.try_next(resolution, choice);
+        }
+        Ok(())
+    }
+
+    /// Choose the next candidate of `choice`, and go on with the demands it
+    /// brings along.
+    fn try_next<'r>(&self, resolution: &mut Resolution<'r>, mut choice: Choice<'r>) {
+        let package = choice.candidates[choice.tried];
+        choice.tried += 1;
+        resolution.chosen.insert(choice.name.clone(), package.version.clone());
+        resolution.steps.push(Undo::Chosen(choice.name.clone()));
+        resolution.pending.extend(self.demands_of(package));
+        resolution.choices.push(choice);
+    }
+
+    /// `error` ruled out the latest choice made: undo everything since, and
+    /// try the next candidate of the latest choice that has one left. Fails
+    /// when none has.
+    fn backtrack(
+        &self,
+        resolution: &mut Resolution,
+        mut error: ResolveError,
+    ) -> Result<(), ResolveError> {
+        while let Some(mut choice) = resolution.choices.pop() {
+            resolution.undo(choice.steps);
+            // what is left of the demands the tried candidate brought along
+            resolution.pending.truncate(choice.pending);
+            // that some later demand did not want this very version is no
+            // news when another version failed for a different reason
+            let name = &choice.name;
+            match &choice.reported {
+                Some(first) if !is_conflict_on(first, name) || is_conflict_on(&error, name) => {}
+                _ => choice.reported = Some(error),
+            }
+            if choice.tried < choice.candidates.len() {
+                self.try_next(resolution, choice);
+                return Ok(());
+            }
+            // so the choice before this one did not work either
+            error = choice.reported.unwrap();
+        }
+        Err(error)
+    }
+
+    /// The error for when no version of `name` meets `demands`. It copies
+    /// every demand and version, so it is only made to be returned.
+    fn conflict(&self, name: &str, demands: &[Demand]) -> ResolveError {
+        ResolveError::Conflict {
+            name: name.to_string(),
+            demands: demands.to_vec(),
+            available: self.packages[name].keys().cloned().collect(),
+        }
+    }
+}
+
+fn is_conflict_on(error: &ResolveError, package: &str) -> bool {
+    matches!(error, ResolveError::Conflict { name, .. } if name == package)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::package_builder::{Dependency, PackageBuilder};
+
+    fn package(name: &str, version: &str, dependencies: &[(&str, &str)]) -> Package {
+        let mut builder = PackageBuilder::new(name).version(version).unwrap();
+        for (name, requirement) in dependencies {
+            builder = builder.dependency(Dependency::new(*name, requirement.parse().unwrap()));
+        }
+        builder.build()
+    }
+
+    /// A name, a version and the dependencies of a package, as `(name, requirement)`.
+    type Published<'a> = (&'a str, &'a str, &'a [(&'a str, &'a str)]);
+
+    fn registry(packages: &[Published]) -> Registry {
+        let mut registry = Registry::new();
+        for (name, version, dependencies) in packages {
+            registry.publish(package(name, version, dependencies));
+        }
+        registry
+    }
+
+    fn resolve(registry: &Registry, dependencies: &[(&str, &str)]) -> Result<String, String> {
+        let root = package("app", "1.0.0", dependencies);
+        let chosen = registry.resolve(&root).map_err(|error| error.to_string())?;
+        let chosen: Vec<String> =
+            chosen.iter().map(|(name, version)| format!("{name} {version}")).collect();
+        Ok(chosen.join(", "))
+    }
+
+    #[test]
+    fn registry_lookups() {
+        let registry = registry(&[("log", "0.4.20", &[]), ("log", "0.4.19", &[]), ("log", "0.5.0", &[])]);
+        let versions: Vec<String> = registry.versions("log").map(|p| p.version.to_string()).collect();
+        assert_eq!(versions, ["0.5.0", "0.4.20", "0.4.19"]);
+        assert_eq!(registry.versions("serde").count(), 0);
+        assert!(registry.get("log", &"0.4.19".parse().unwrap()).is_some());
+        assert!(registry.get("log", &"0.4.18".parse().unwrap()).is_none());
+    }
+
+    #[test]
+    fn picks_the_newest_that_will_do() {
+        let registry = registry(&[
+            ("log", "0.4.19", &[]),
+            ("log", "0.4.20", &[]),
+            ("log", "0.5.0", &[]),
+            ("serde", "1.0.0", &[("log", "^0.4.19")]),
+            ("serde", "1.1.0", &[("log", "^0.4.19"), ("itoa", "1")]),
+            ("itoa", "1.0.9", &[]),
+            ("itoa", "2.0.0", &[]),
+        ]);
+        assert_eq!(resolve(&registry, &[("serde", "1")]).unwrap(), "itoa 1.0.9, log 0.4.20, serde 1.1.0");
+        let older = resolve(&registry, &[("serde", "~1.0"), ("log", "*")]);
+        assert_eq!(older.unwrap(), "log 0.4.20, serde 1.0.0");
+        assert_eq!(resolve(&registry, &[("log", "<0.4.20")]).unwrap(), "log 0.4.19");
+        assert_eq!(resolve(&registry, &[]).unwrap(), "");
+    }
+
+    #[test]
+    fn backtracks_to_older_versions() {
+        let registry = registry(&[
+            ("web", "2.0.0", &[("http", "^2")]),
+            ("web", "1.0.0", &[("http", "^1")]),
+            ("http", "1.0.0", &[]),
+            ("http", "2.0.0", &[]),
+        ]);
+        // the newest web wants an http that the app does not
+        assert_eq!(resolve(&registry, &[("web", "*"), ("http", "^1")]).unwrap(), "http 1.0.0, web 1.0.0");
+        assert_eq!(resolve(&registry, &[("http", "^1"), ("web", "*")]).unwrap(), "http 1.0.0, web 1.0.0");
+    }
+
+    #[test]
+    fn cycles() {
+        let registry = registry(&[("a", "1.0.0", &[("b", "1")]), ("b", "1.0.0", &[("a", "1")])]);
+        assert_eq!(resolve(&registry, &[("a", "1")]).unwrap(), "a 1.0.0, b 1.0.0");
+    }
+
+    #[test]
+    fn explains_conflicts() {
+        let registry = registry(&[
+            ("a", "1.0.0", &[("c", "^1")]),
+            ("b", "1.0.0", &[("c", ">=2, <3")]),
+            ("c", "1.0.0", &[]),
+            ("c", "2.0.0", &[]),
+        ]);
+        assert_eq!(
+            resolve(&registry, &[("a", "1"), ("b", "1")]).unwrap_err(),
+            "no version of c satisfies every requirement on it:
+  ^1 required by a 1.0.0
+  >=2, <3 required by b 1.0.0
+available versions: 1.0.0, 2.0.0"
+        );
+        assert_eq!(
+            resolve(&registry, &[("c", "^3")]).unwrap_err(),
+            "no version of c satisfies every requirement on it:
+  ^3 required by app 1.0.0
+available versions: 1.0.0, 2.0.0"
+        );
+        let missing = resolve(&registry, &[("d", "1")]);
+        assert_eq!(missing.unwrap_err(), "there is no package d, required by app 1.0.0");
+    }
+
+    #[test]
+    fn explains_the_real_reason() {
+        // c 2.0.0 is only ruled out by b wanting c 1, c 1.0.0 by what it
+        // depends on, which is the one worth telling
+        let registry = registry(&[
+            ("b", "1.0.0", &[("c", "<2")]),
+            ("c", "2.0.0", &[]),
+            ("c", "1.0.0", &[("gone", "1")]),
+        ]);
+        let error = registry.resolve(&package("app", "1.0.0", &[("c", ">=1"), ("b", "1")])).unwrap_err();
+        assert_eq!(
+            error,
+            ResolveError::UnknownPackage { name: "gone".into(), required_by: "c 1.0.0".into() }
+        );
+    }
+
+    #[test]
+    fn long_chains() {
+        // far deeper than a recursive search could go, and every 2.0.0
+        // only fails at the very end
+        let mut registry = Registry::new();
+        for i in 0..20_000 {
+            let next = format!("p{}", i + 1);
+            registry.publish(package(&format!("p{i}"), "1.0.0", &[(&next, "1")]));
+            registry.publish(package(&format!("p{i}"), "2.0.0", &[(&next, "2")]));
+        }
+        registry.publish(package("p20000", "1.0.0", &[]));
+        let chosen = registry.resolve(&package("app", "1.0.0", &[("p0", "*")])).unwrap();
+        assert_eq!(chosen.len(), 20_001);
+        assert!(chosen.values().all(|version| version.to_string() == "1.0.0"));
+    }
+
+    #[test]
+    fn wide_graphs() {
+        // every choice comes up with thousands of demands still pending,
+        // and its newest version is given up on
+        let mut registry = Registry::new();
+        let mut dependencies = Vec::new();
+        for i in 0..20_000 {
+            let (name, base) = (format!("w{i}"), format!("b{i}"));
+            registry.publish(package(&name, "1.0.0", &[(&base, "1")]));
+            registry.publish(package(&name, "2.0.0", &[(&base, "2")]));
+            registry.publish(package(&base, "1.0.0", &[]));
+            dependencies.push(name);
+        }
+        let dependencies: Vec<_> = dependencies.iter().map(|name| (name.as_str(), "*")).collect();
+        let chosen = registry.resolve(&package("app", "1.0.0", &dependencies)).unwrap();
+        assert_eq!(chosen.len(), 40_000);
+        assert!(chosen.values().all(|version| version.to_string() == "1.0.0"));
+    }
+}
@@ -0,0 +1,609 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+
+/// A semantic version, `major.minor.patch` with an optional pre-release
+/// (`-alpha.1`) and build metadata (`+sha.5114f85`), as in
+/// <https://semver.org>.
+///
+/// Versions are ordered by precedence: a pre-release comes before the
+/// release it leads up to. Build metadata does not count towards
+/// precedence, it only orders versions that are otherwise the same.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Version {
+    pub major: u64,
+    pub minor: u64,
+    pub patch: u64,
+    pub pre: Vec<Identifier>,
+    pub build: Vec<String>,
+}
+
+/// A dot separated part of a pre-release. Numbers sort before words.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Identifier {
+    Numeric(u64),
+    AlphaNumeric(String),
+}
+
+impl Version {
+    pub fn new(major: u64, minor: u64, patch: u64) -> Self {
+        Version { major, minor, patch, pre: Vec::new(), build: Vec::new() }
+    }
+}
+
+/// Compare pre-releases, where having none at all is the greatest.
+fn compare_pre(a: &[Identifier], b: &[Identifier]) -> Ordering {
+    match (a.is_empty(), b.is_empty()) {
+        (true, true) => Ordering::Equal,
+        (true, false) => Ordering::Greater,
+        (false, true) => Ordering::Less,
+        (false, false) => a.cmp(b),
+    }
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.major, self.minor, self.patch)
+            .cmp(&(other.major, other.minor, other.patch))
+            .then_with(|| compare_pre(&self.pre, &other.pre))
+            .then_with(|| self.build.cmp(&other.build))
+    }
+}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for Identifier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Identifier::Numeric(n) => write!(f, "{n}"),
+            Identifier::AlphaNumeric(s) => write!(f, "{s}"),
+        }
+    }
+}
+
+/// Write `.`-separated parts after `prefix`, if there are any.
+fn write_parts<T: fmt::Display>(f: &mut fmt::Formatter, prefix: char, parts: &[T]) -> fmt::Result {
+    for (i, part) in parts.iter().enumerate() {
+        write!(f, "{}{part}", if i == 0 { prefix } else { '.' })?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)?;
+        write_parts(f, '-', &self.pre)?;
+        write_parts(f, '+', &self.build)
+    }
+}
+
+/// Why a version or a requirement could not be parsed, along with the
+/// byte offset where it went wrong.
+#[derive(Debug, Clone, PartialEq)]
+pub enum VersionError {
+    /// Something else was found where `expected` should have been.
+    Expected { expected: &'static str, offset: usize },
+    /// A number with a leading zero, or too large for a `u64`.
+    InvalidNumber { number: String, offset: usize },
+}
+
+impl fmt::Display for VersionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VersionError::Expected { expected, offset } => {
+                write!(f, "expected {expected} at offset {offset}")
+            }
+            VersionError::InvalidNumber { number, offset } => {
+                write!(f, "invalid number {number:?} at offset {offset}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for VersionError {}
+
+/// Reads versions and requirements a piece at a time.
+struct Cursor<'a> {
+    input: &'a str,
+    offset: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn rest(&self) -> &'a str {
+        &self.input[self.offset..]
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.offset += rest.len() - rest.trim_start().len();
+    }
+
+    /// Step over `prefix` if the input goes on with it.
+    fn eat(&mut self, prefix: &str) -> bool {
+        let found = self.rest().starts_with(prefix);
+        if found {
+            self.offset += prefix.len();
+        }
+        found
+    }
+
+    fn expected(&self, expected: &'static str) -> VersionError {
+        VersionError::Expected { expected, offset: self.offset }
+    }
+
+    /// The longest run of characters allowed in an identifier.
+    fn identifier(&mut self) -> Result<&'a str, VersionError> {
+        let rest = self.rest();
+        let len = rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '-')).unwrap_or(rest.len());
+        if len == 0 {
+            return Err(self.expected("an identifier"));
+        }
+        self.offset += len;
+        Ok(&rest[..len])
+    }
+
+    fn number(&mut self) -> Result<u64, VersionError> {
+        let rest = self.rest();
+        let len = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+        if len == 0 {
+            return Err(self.expected("a number"));
+        }
+        let digits = &rest[..len];
+        let invalid = || VersionError::InvalidNumber { number: digits.to_string(), offset: self.offset };
+        if len > 1 && digits.starts_with('0') {
+            return Err(invalid());
+        }
+        let n = digits.parse().map_err(|_| invalid())?;
+        self.offset += len;
+        Ok(n)
+    }
+
+    /// `-pre.release`, if there is one.
+    fn pre(&mut self) -> Result<Vec<Identifier>, VersionError> {
+        let mut pre = Vec::new();
+        if !self.eat("-") {
+            return Ok(pre);
+        }
+        loop {
+            let start = self.offset;
+            let identifier = self.identifier()?;
+            if identifier.bytes().all(|b| b.is_ascii_digit()) {
+                self.offset = start;
+                pre.push(Identifier::Numeric(self.number()?));
+            } else {
+                pre.push(Identifier::AlphaNumeric(identifier.to_string()));
+            }
+            if !self.eat(".") {
+                return Ok(pre);
+            }
+        }
+    }
+
+    /// `+build.metadata`, if there is one.
+    fn build(&mut self) -> Result<Vec<String>, VersionError> {
+        let mut build = Vec::new();
+        if self.eat("+") {
+            build.push(self.identifier()?.to_string());
+            while self.eat(".") {
+                build.push(self.identifier()?.to_string());
+            }
+        }
+        Ok(build)
+    }
+
+    fn end(&mut self) -> Result<(), VersionError> {
+        self.skip_whitespace();
+        if !self.rest().is_empty() {
+            return Err(self.expected("the end"));
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Version {
+    type Err = VersionError;
+
+    fn from_str(input: &str) -> Result<Self, VersionError> {
+        let mut cursor = Cursor { input, offset: 0 };
+        cursor.skip_whitespace();
+        let major = cursor.number()?;
+        let mut next = || if cursor.eat(".") { cursor.number() } else { Err(cursor.expected("\".\"")) };
+        let (minor, patch) = (next()?, next()?);
+        let version = Version { major, minor, patch, pre: cursor.pre()?, build: cursor.build()? };
+        cursor.end()?;
+        Ok(version)
+    }
+}
+
+/// How a `Comparator` compares.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    /// `=1.2.3`
+    Exact,
+    /// `>1.2.3`
+    Greater,
+    /// `>=1.2.3`
+    GreaterEq,
+    /// `<1.2.3`
+    Less,
+    /// `<=1.2.3`
+    LessEq,
+    /// `~1.2.3`, the same minor version at least as new.
+    Tilde,
+    /// `^1.2.3`, or just `1.2.3`: a compatible version at least as new,
+    /// which is the same major version (or minor version, before 1.0.0).
+    Caret,
+}
+
+impl Op {
+    const SYMBOLS: [(&'static str, Op); 7] = [
+        (">=", Op::GreaterEq),
+        ("<=", Op::LessEq),
+        ("=", Op::Exact),
+        (">", Op::Greater),
+        ("<", Op::Less),
+        ("~", Op::Tilde),
+        ("^", Op::Caret),
+    ];
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (symbol, _) = Op::SYMBOLS.iter().find(|(_, op)| op == self).unwrap();
+        write!(f, "{symbol}")
+    }
+}
+
+/// One condition of a `VersionReq`. The minor and patch versions may be
+/// left out, `>=1` is the same as `>=1.0.0` and `=1.2` matches any patch
+/// of 1.2.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Comparator {
+    pub op: Op,
+    pub major: u64,
+    pub minor: Option<u64>,
+    pub patch: Option<u64>,
+    /// Only given along with a patch version.
+    pub pre: Vec<Identifier>,
+}
+
+impl Comparator {
+    pub fn matches(&self, v: &Version) -> bool {
+        match self.op {
+            Op::Exact => self.matches_exact(v),
+            Op::Greater => self.matches_greater(v),
+            Op::GreaterEq => self.matches_exact(v) || self.matches_greater(v),
+            Op::Less => self.matches_less(v),
+            Op::LessEq => self.matches_exact(v) || self.matches_less(v),
+            Op::Tilde => self.matches_tilde(v),
+            Op::Caret => self.matches_caret(v),
+        }
+    }
+
+    fn matches_exact(&self, v: &Version) -> bool {
+        v.major == self.major
+            && self.minor.is_none_or(|minor| v.minor == minor)
+            && self.patch.is_none_or(|patch| v.patch == patch && v.pre == self.pre)
+    }
+
+    /// Greater than every version the comparator could mean.
+    fn matches_greater(&self, v: &Version) -> bool {
+        if v.major != self.major {
+            return v.major > self.major;
+        }
+        let Some(minor) = self.minor else { return false };
+        if v.minor != minor {
+            return v.minor > minor;
+        }
+        let Some(patch) = self.patch else { return false };
+        if v.patch != patch {
+            return v.patch > patch;
+        }
+        compare_pre(&v.pre, &self.pre) == Ordering::Greater
+    }
+
+    /// Less than every version the comparator could mean.
+    fn matches_less(&self, v: &Version) -> bool {
+        if v.major != self.major {
+            return v.major < self.major;
+        }
+        let Some(minor) = self.minor else { return false };
+        if v.minor != minor {
+            return v.minor < minor;
+        }
+        let Some(patch) = self.patch else { return false };
+        if v.patch != patch {
+            return v.patch < patch;
+        }
+        compare_pre(&v.pre, &self.pre) == Ordering::Less
+    }
+
+    fn matches_tilde(&self, v: &Version) -> bool {
+        if v.major != self.major || self.minor.is_some_and(|minor| v.minor != minor) {
+            return false;
+        }
+        match self.patch {
+            Some(patch) if v.patch != patch => v.patch > patch,
+            _ => compare_pre(&v.pre, &self.pre) != Ordering::Less,
+        }
+    }
+
+    fn matches_caret(&self, v: &Version) -> bool {
+        if v.major != self.major {
+            return false;
+        }
+        let Some(minor) = self.minor else { return true };
+        let Some(patch) = self.patch else {
+            return if self.major > 0 { v.minor >= minor } else { v.minor == minor };
+        };
+        if self.major > 0 {
+            if v.minor != minor {
+                return v.minor > minor;
+            }
+        } else if v.minor != minor || (minor == 0 && v.patch != patch) {
+            // before 1.0.0 the first number that is not 0 is the major one
+            return false;
+        }
+        if v.patch != patch {
+            return v.patch > patch;
+        }
+        compare_pre(&v.pre, &self.pre) != Ordering::Less
+    }
+
+    /// A pre-release is only considered by a comparator that names a
+    /// pre-release of the same version, `>=1.0.0-beta` allows
+    /// `1.0.0-rc.1` but not `1.1.0-alpha`.
+    fn allows_pre_release_of(&self, v: &Version) -> bool {
+        !self.pre.is_empty()
+            && (self.major, self.minor, self.patch) == (v.major, Some(v.minor), Some(v.patch))
+    }
+}
+
+impl fmt::Display for Comparator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}", self.op, self.major)?;
+        if let Some(minor) = self.minor {
+            write!(f, ".{minor}")?;
+        }
+        if let Some(patch) = self.patch {
+            write!(f, ".{patch}")?;
+        }
+        write_parts(f, '-', &self.pre)
+    }
+}
+
+/// Which versions will do, as a list of comparators that must all match,
+/// such as `>=1.2, <2`. No comparators at all, written `*`, match any
+/// version. Pre-releases only match where a comparator asks for them, see
+/// `Comparator`.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct VersionReq {
+    pub comparators: Vec<Comparator>,
+}
+
+impl VersionReq {
+    /// Any version at all, `*`.
+    pub const STAR: VersionReq = VersionReq { comparators: Vec::new() };
+
+    /// Versions compatible with `version`, as in `^1.2.3`.
+    pub fn caret(version: &Version) -> Self {
+        let comparator = Comparator {
+            op: Op::Caret,
+            major: version.major,
+            minor: Some(version.minor),
+            patch: Some(version.patch),
+            pre: version.pre.clone(),
+        };
+        VersionReq { comparators: vec![comparator] }
+    }
+
+    pub fn matches(&self, version: &Version) -> bool {
+        let pre_release_allowed = || self.comparators.iter().any(|c| c.allows_pre_release_of(version));
+        self.comparators.iter().all(|c| c.matches(version))
+            && (version.pre.is_empty() || pre_release_allowed())
+    }
+}
+
+impl fmt::Display for VersionReq {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.comparators.is_empty() {
+            return write!(f, "*");
+        }
+        for (i, comparator) in self.comparators.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{comparator}")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for VersionReq {
+    type Err = VersionError;
+
+    fn from_str(input: &str) -> Result<Self, VersionError> {
+        let mut cursor = Cursor { input, offset: 0 };
+        cursor.skip_whitespace();
+        if cursor.eat("*") {
+            cursor.end()?;
+            return Ok(VersionReq::STAR);
+        }
+
+        let mut comparators = Vec::new();
+        loop {
+            let op = Op::SYMBOLS.iter().find(|(symbol, _)| cursor.eat(symbol)).map(|(_, op)| *op);
+            cursor.skip_whitespace();
+            let major = cursor.number()?;
+            // `1.*` and `1.2.*` are the same as leaving the rest out
+            let mut part = || match cursor.eat(".") {
+                false => Ok(None),
+                true if cursor.eat("*") => Ok(None),
+                true => cursor.number().map(Some),
+            };
+            let minor = part()?;
+            let patch = if minor.is_some() { part()? } else { None };
+            let pre = if patch.is_some() { cursor.pre()? } else { Vec::new() };
+            // build metadata means nothing to a requirement
+            cursor.build()?;
+            comparators.push(Comparator { op: op.unwrap_or(Op::Caret), major, minor, patch, pre });
+
+            cursor.skip_whitespace();
+            if cursor.rest().is_empty() {
+                return Ok(VersionReq { comparators });
+            }
+            if !cursor.eat(",") {
+                return Err(cursor.expected("\",\""));
+            }
+            cursor.skip_whitespace();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn v(input: &str) -> Version {
+        input.parse().unwrap()
+    }
+
+    fn req(input: &str) -> VersionReq {
+        input.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_versions() {
+        assert_eq!(v("1.2.3"), Version::new(1, 2, 3));
+        let full = v("1.0.0-alpha.1.x-y+build.5.a-b");
+        let word = |s: &str| Identifier::AlphaNumeric(s.to_string());
+        assert_eq!(full.pre, [word("alpha"), Identifier::Numeric(1), word("x-y")]);
+        assert_eq!(full.build, ["build", "5", "a-b"]);
+        let inputs = ["1.2.3", "0.0.0", "1.0.0-rc.1", "1.0.0+20130313144700", "1.0.0-0.3+exp.sha.5114f85"];
+        for input in inputs {
+            assert_eq!(v(input).to_string(), input);
+        }
+    }
+
+    #[test]
+    fn invalid_versions() {
+        let expected = |expected, offset| Err(VersionError::Expected { expected, offset });
+        assert_eq!("".parse::<Version>(), expected("a number", 0));
+        assert_eq!("1.2".parse::<Version>(), expected("\".\"", 3));
+        assert_eq!("1.2.3.4".parse::<Version>(), expected("the end", 5));
+        assert_eq!("1.2.3-".parse::<Version>(), expected("an identifier", 6));
+        assert_eq!("1.2.3-a..b".parse::<Version>(), expected("an identifier", 8));
+        assert_eq!("1.2.3+".parse::<Version>(), expected("an identifier", 6));
+        assert_eq!("v1.2.3".parse::<Version>(), expected("a number", 0));
+        assert_eq!(
+            "01.2.3".parse::<Version>(),
+            Err(VersionError::InvalidNumber { number: "01".into(), offset: 0 })
+        );
+        assert_eq!(
+            "1.2.3-alpha.01".parse::<Version>(),
+            Err(VersionError::InvalidNumber { number: "01".into(), offset: 12 })
+        );
+        let too_large = "1.99999999999999999999.0".parse::<Version>().unwrap_err();
+        assert_eq!(too_large.to_string(), "invalid number \"99999999999999999999\" at offset 2");
+    }
+
+    #[test]
+    fn precedence() {
+        // straight from the semver specification
+        let ordered = [
+            "1.0.0-alpha",
+            "1.0.0-alpha.1",
+            "1.0.0-alpha.beta",
+            "1.0.0-beta",
+            "1.0.0-beta.2",
+            "1.0.0-beta.11",
+            "1.0.0-rc.1",
+            "1.0.0",
+            "1.0.1",
+            "1.1.0",
+            "2.0.0",
+            "10.0.0",
+        ];
+        for pair in ordered.windows(2) {
+            assert!(v(pair[0]) < v(pair[1]), "{} < {}", pair[0], pair[1]);
+        }
+        // metadata only breaks ties
+        assert!(v("1.0.0+a") < v("1.0.0+b"));
+        assert!(v("1.0.0+b") < v("1.0.1+a"));
+        assert_ne!(v("1.0.0+a"), v("1.0.0"));
+    }
+
+    #[test]
+    fn parse_requirements() {
+        assert_eq!(req("*"), VersionReq::STAR);
+        assert_eq!(req(" * ").to_string(), "*");
+        assert_eq!(req("1.2.3").to_string(), "^1.2.3");
+        assert_eq!(req(">=1, <2").to_string(), ">=1, <2");
+        assert_eq!(req(">= 1.2 ,< 2.0.0-rc.1").to_string(), ">=1.2, <2.0.0-rc.1");
+        assert_eq!(req("~0.4.3").to_string(), "~0.4.3");
+        assert_eq!(req("1.*"), req("1"));
+        assert_eq!(req("=1.2.*"), req("=1.2"));
+        assert_eq!(req("^1.2.3+build"), req("^1.2.3"));
+
+        let expected = |expected, offset| Err(VersionError::Expected { expected, offset });
+        assert_eq!("".parse::<VersionReq>(), expected("a number", 0));
+        assert_eq!(">=".parse::<VersionReq>(), expected("a number", 2));
+        assert_eq!(">=1 <2".parse::<VersionReq>(), expected("\",\"", 4));
+        assert_eq!(">=1,".parse::<VersionReq>(), expected("a number", 4));
+        assert_eq!("* , 1".parse::<VersionReq>(), expected("the end", 2));
+        assert_eq!("=>1".parse::<VersionReq>(), expected("a number", 1));
+    }
+
+    /// Check which of `versions` match `requirement`, given as `+` for a
+    /// match and `-` for none.
+    fn check(requirement: &str, versions: &[&str], expected: &str) {
+        let requirement = req(requirement);
+        let matched: String = versions
+            .iter()
+            .map(|version| if requirement.matches(&v(version)) { '+' } else { '-' })
+            .collect();
+        assert_eq!(matched, expected, "{requirement} on {versions:?}");
+    }
+
+    #[test]
+    fn matching() {
+        let versions =
+            ["0.0.1", "0.0.2", "0.1.0", "0.1.5", "0.2.0", "1.0.0", "1.2.0", "1.2.3", "1.3.0", "2.0.0"];
+        check("*", &versions, "++++++++++");
+        check("^1.2", &versions, "------+++-");
+        check("1.2.3", &versions, "-------++-");
+        check("^1", &versions, "-----++++-");
+        check("^0.1.2", &versions, "---+------");
+        check("^0.0.1", &versions, "+---------");
+        check("^0.0", &versions, "++--------");
+        check("^0", &versions, "+++++-----");
+        check("~1.2", &versions, "------++--");
+        check("~1.2.1", &versions, "-------+--");
+        check("~0.1", &versions, "--++------");
+        check("~1", &versions, "-----++++-");
+        check("=1.2", &versions, "------++--");
+        check("=1.2.0", &versions, "------+---");
+        check(">=1, <2", &versions, "-----++++-");
+        check(">1.2", &versions, "--------++");
+        check(">1.2.0", &versions, "-------+++");
+        check("<=1.2", &versions, "++++++++--");
+        check("<1.2", &versions, "++++++----");
+        check(">0.1.0, <=1", &versions, "---++++++-");
+    }
+
+    #[test]
+    fn matching_pre_releases() {
+        let versions = ["1.0.0-alpha", "1.0.0-beta", "1.0.0", "1.1.0-alpha", "1.1.0"];
+        check("*", &versions, "--+-+");
+        check("^1", &versions, "--+-+");
+        check(">=1.0.0-beta", &versions, "-++-+");
+        check("^1.0.0-alpha", &versions, "+++-+");
+        check("<1.0.0", &versions, "-----");
+        check("=1.1.0-alpha", &versions, "---+-");
+        check("~1.1.0-alpha", &versions, "---++");
+    }
+}
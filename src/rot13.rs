@@ -1,6 +1,8 @@
-use std::io::Read;
+pub mod poll;
 
-struct RotDecoder<R> 
+use std::io::{Read, Write};
+
+pub struct RotDecoder<R>
 where
     R: Read
 {
@@ -8,35 +10,294 @@ where
     rot: u8,
 }
 
+impl <R: Read> RotDecoder<R> {
+    pub fn new(input: R, rot: u8) -> Self {
+        RotDecoder { input, rot }
+    }
+}
+
 const SIZE_OF_ALPHABET: u8 = 26;
+
+// Rotate a letter `rot` places along the alphabet, keeping its case. Any
+// other byte stays as it is.
+pub fn rotate(b: u8, rot: u8) -> u8 {
+    if !b.is_ascii_alphabetic() {
+        return b;
+    }
+    let base = if b.is_ascii_uppercase() {'A'} else {'a'} as u8;
+    // reduced first, so the sum cannot overflow a u8
+    (b - base + rot % SIZE_OF_ALPHABET) % SIZE_OF_ALPHABET + base
+}
 // Implement the `Read` trait for `RotDecoder`.
 impl <R: Read> Read for RotDecoder<R> {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
         // remember, self is of type R which already implements the Read trait
         let size: usize = self.input.read(buf)?;
-        for b in &mut buf[..] {
-            if b.is_ascii_alphabetic() {
-                let base = if b.is_ascii_uppercase() {'A'} else {'a'} as u8;
-                // now rotate the letter, keeping in mind the rollover past 26
-                *b = (*b - base + self.rot) % 26 + base;
+        // only the bytes just read, whatever follows is still the caller's
+        for b in &mut buf[..size] {
+            *b = rotate(*b, self.rot);
+        }
+
+        Ok(size)
+    }
+}
+
+// The other way around: rotate bytes as they are written, so that a
+// `RotDecoder` with the same `rot` gives them back. Only a chunk at a time
+// is rotated, whatever the size of the write.
+pub struct RotEncoder<W>
+where
+    W: Write
+{
+    output: W,
+    rot: u8,
+}
+
+impl <W: Write> RotEncoder<W> {
+    pub fn new(output: W, rot: u8) -> Self {
+        RotEncoder { output, rot }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.output
+    }
+}
+
+const CHUNK: usize = 512;
+
+impl <W: Write> Write for RotEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut chunk = [0u8; CHUNK];
+        let len = buf.len().min(CHUNK);
+        // rotating forward by the rest of the alphabet undoes the decoder
+        let back = SIZE_OF_ALPHABET - self.rot % SIZE_OF_ALPHABET;
+        for (out, &b) in chunk.iter_mut().zip(&buf[..len]) {
+            *out = rotate(b, back);
+        }
+        // the output may take less than we gave it, and the caller is told
+        // exactly how much, so nothing is rotated twice or lost
+        self.output.write(&chunk[..len])
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.output.flush()
+    }
+}
+
+// The letters that rotate into each other, for the UTF-8 aware decoder
+// below. Each case is rotated on its own, by `rot` modulo its length.
+pub struct Alphabet {
+    lower: &'static str,
+    upper: &'static str,
+}
+
+impl Alphabet {
+    pub const LATIN: Alphabet = Alphabet {
+        lower: "abcdefghijklmnopqrstuvwxyz",
+        upper: "ABCDEFGHIJKLMNOPQRSTUVWXYZ",
+    };
+    // the final sigma ς is left alone, it has no place in the order
+    pub const GREEK: Alphabet = Alphabet {
+        lower: "αβγδεζηθικλμνξοπρστυφχψω",
+        upper: "ΑΒΓΔΕΖΗΘΙΚΛΜΝΞΟΠΡΣΤΥΦΧΨΩ",
+    };
+    pub const CYRILLIC: Alphabet = Alphabet {
+        lower: "абвгдеёжзийклмнопрстуфхцчшщъыьэюя",
+        upper: "АБВГДЕЁЖЗИЙКЛМНОПРСТУФХЦЧШЩЪЫЬЭЮЯ",
+    };
+
+    fn rotate(&self, c: char, rot: u8) -> Option<char> {
+        for letters in [self.lower, self.upper] {
+            if let Some(i) = letters.chars().position(|l| l == c) {
+                let len = letters.chars().count();
+                return letters.chars().nth((i + rot as usize) % len);
+            }
+        }
+        None
+    }
+}
+
+// Like `RotDecoder`, but reads its input as UTF-8: a character split
+// between two reads is put back together before it is rotated, so that
+// letters of other alphabets can be rotated too. Whatever is not valid
+// UTF-8 comes out as U+FFFD, so the output always is.
+pub struct Utf8RotDecoder<R>
+where
+    R: Read
+{
+    input: R,
+    rot: u8,
+    alphabets: Vec<Alphabet>,
+    // the start of a character whose other bytes have not been read yet
+    pending: Vec<u8>,
+    // rotated, but did not fit in the caller's buffer yet
+    ready: Vec<u8>,
+    // how much of `ready` has been read
+    start: usize,
+}
+
+impl <R: Read> Utf8RotDecoder<R> {
+    // Rotates the Latin alphabet only, as `RotDecoder` does.
+    pub fn new(input: R, rot: u8) -> Self {
+        Utf8RotDecoder {
+            input,
+            rot,
+            alphabets: vec![Alphabet::LATIN],
+            pending: Vec::new(),
+            ready: Vec::new(),
+            start: 0,
+        }
+    }
+
+    pub fn alphabet(mut self, alphabet: Alphabet) -> Self {
+        self.alphabets.push(alphabet);
+        self
+    }
+
+    fn rotate(&self, c: char) -> char {
+        self.alphabets.iter().find_map(|a| a.rotate(c, self.rot)).unwrap_or(c)
+    }
+
+    // Rotate all complete characters in `pending` into `ready`.
+    fn decode(&mut self) {
+        let pending = std::mem::take(&mut self.pending);
+        let mut rest = &pending[..];
+        loop {
+            let (valid, error) = match std::str::from_utf8(rest) {
+                Ok(text) => (text, None),
+                Err(e) => (std::str::from_utf8(&rest[..e.valid_up_to()]).unwrap(), Some(e)),
+            };
+            let mut encoded = [0u8; 4];
+            for c in valid.chars() {
+                let c = self.rotate(c);
+                self.ready.extend_from_slice(c.encode_utf8(&mut encoded).as_bytes());
             }
+            match error {
+                None => rest = &[],
+                Some(e) => match e.error_len() {
+                    // the rest of the character is still to come
+                    None => rest = &rest[e.valid_up_to()..],
+                    Some(len) => {
+                        self.ready.extend_from_slice(REPLACEMENT.as_bytes());
+                        rest = &rest[e.valid_up_to() + len..];
+                        continue;
+                    }
+                },
+            }
+            break;
         }
+        self.pending = rest.to_vec();
+    }
+}
+
+const REPLACEMENT: &str = "\u{FFFD}";
 
+impl <R: Read> Read for Utf8RotDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.start == self.ready.len() {
+            // clearing it rather than moving what is left up after every
+            // read, as `transform::Reader` does
+            self.ready.clear();
+            self.start = 0;
+        }
+        while self.ready.is_empty() {
+            let mut chunk = [0u8; CHUNK];
+            let size = self.input.read(&mut chunk)?;
+            if size == 0 {
+                // the input ended in the middle of a character
+                if !self.pending.is_empty() {
+                    self.pending.clear();
+                    self.ready.extend_from_slice(REPLACEMENT.as_bytes());
+                }
+                break;
+            }
+            self.pending.extend_from_slice(&chunk[..size]);
+            self.decode();
+        }
+        let ready = &self.ready[self.start..];
+        let size = buf.len().min(ready.len());
+        buf[..size].copy_from_slice(&ready[..size]);
+        self.start += size;
         Ok(size)
     }
 }
 
+// How often each letter turns up in English text, in percent.
+const ENGLISH: [f64; SIZE_OF_ALPHABET as usize] = [
+    8.167, 1.492, 2.782, 4.253, 12.702, 2.228, 2.015, 6.094, 6.966, 0.153, 0.772, 4.025, 2.406,
+    6.749, 7.507, 1.929, 0.095, 5.987, 6.327, 9.056, 2.758, 0.978, 2.360, 0.150, 1.974, 0.074,
+];
+
+// What `crack` made of a text.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Crack {
+    // The rotation to decode it with, as in `RotDecoder::new(text, rot)`.
+    pub rot: u8,
+    // From 0, when another rotation looks just as much like English (or
+    // there are no letters at all), towards 1 the further ahead this one is.
+    pub confidence: f64,
+}
+
+// Guess the rotation of a text from its letter frequencies: every rotation
+// is scored by how far its letter counts are from English ones (the
+// chi-squared statistic), and the closest wins. It takes a few dozen
+// letters to be sure of.
+pub fn crack(text: &[u8]) -> Crack {
+    let mut counts = [0usize; SIZE_OF_ALPHABET as usize];
+    for b in text.iter().filter(|b| b.is_ascii_alphabetic()) {
+        counts[(b.to_ascii_lowercase() - b'a') as usize] += 1;
+    }
+    let letters = counts.iter().sum::<usize>() as f64;
+    if letters == 0.0 {
+        return Crack { rot: 0, confidence: 0.0 };
+    }
+
+    let chi_squared = |rot: usize| -> f64 {
+        ENGLISH.iter().enumerate().map(|(letter, percent)| {
+            // the plain `letter` was written as the one `rot` places back
+            let seen = counts[(letter + 26 - rot) % 26] as f64;
+            let expected = percent / 100.0 * letters;
+            (seen - expected).powi(2) / expected
+        }).sum()
+    };
+    let mut scores: Vec<(f64, u8)> =
+        (0..SIZE_OF_ALPHABET).map(|rot| (chi_squared(rot.into()), rot)).collect();
+    scores.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    let (best, rot) = scores[0];
+    let runner_up = scores[1].0;
+    let confidence = if runner_up > 0.0 { 1.0 - best / runner_up } else { 0.0 };
+    Crack { rot, confidence }
+}
+
 pub fn test_rot13() {
     let mut rot =
         RotDecoder { input: "Gb trg gb gur bgure fvqr!".as_bytes(), rot: 13 };
     let mut result = String::new();
     rot.read_to_string(&mut result).unwrap();
     println!("{}", result);
+
+    // and back again, straight to stdout
+    let mut rot = RotEncoder { output: std::io::stdout(), rot: 13 };
+    writeln!(rot, "{}", result).unwrap();
+    rot.flush().unwrap();
+
+    // other alphabets take more than one byte a letter
+    let mut rot = Utf8RotDecoder::new("Ξπυμ εβη! Гдьхшё, аьд!".as_bytes(), 13)
+        .alphabet(Alphabet::GREEK)
+        .alphabet(Alphabet::CYRILLIC);
+    let mut result = String::new();
+    rot.read_to_string(&mut result).unwrap();
+    println!("{}", result);
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::transform::conformance::{self, Narrow, Trickle};
 
     #[test]
     fn joke() {
@@ -60,4 +321,169 @@ mod test {
             }
         }
     }
+
+    #[test]
+    fn short_reads() {
+        let input = b"Gb trg gb gur bgure fvqr!";
+        for max in 1..input.len() {
+            let mut rot = RotDecoder { input: Trickle::new(input, max), rot: 13 };
+            let mut result = String::new();
+            rot.read_to_string(&mut result).unwrap();
+            assert_eq!(&result, "To get to the other side!", "reading at most {max} at a time");
+        }
+    }
+
+    #[test]
+    fn prefilled_buffer() {
+        let mut rot = RotDecoder { input: "Uryyb".as_bytes(), rot: 13 };
+        let mut buf = *b"xxxxxxxxxxAbc";
+        assert_eq!(rot.read(&mut buf).unwrap(), 5);
+        assert_eq!(&buf, b"HelloxxxxxAbc");
+        assert_eq!(rot.read(&mut buf).unwrap(), 0);
+        assert_eq!(&buf, b"HelloxxxxxAbc");
+    }
+
+    #[test]
+    fn any_rotation() {
+        for rot in 0..=255u8 {
+            let mut decoder = RotDecoder { input: "Zz Aa".as_bytes(), rot };
+            let mut result = String::new();
+            decoder.read_to_string(&mut result).unwrap();
+            let mut expected = RotDecoder { input: "Zz Aa".as_bytes(), rot: rot % 26 };
+            let mut reduced = String::new();
+            expected.read_to_string(&mut reduced).unwrap();
+            assert_eq!(result, reduced, "rot {rot}");
+        }
+        let mut rot = RotDecoder { input: "Zz Aa".as_bytes(), rot: 255 };
+        let mut result = String::new();
+        rot.read_to_string(&mut result).unwrap();
+        assert_eq!(&result, "Uu Vv");
+    }
+
+    fn decode_utf8(input: &[u8], max: usize, rot: u8) -> Vec<u8> {
+        let mut rot = Utf8RotDecoder::new(Trickle::new(input, max), rot)
+            .alphabet(Alphabet::GREEK)
+            .alphabet(Alphabet::CYRILLIC);
+        let mut result = Vec::new();
+        rot.read_to_end(&mut result).unwrap();
+        result
+    }
+
+    #[test]
+    fn utf8_alphabets() {
+        let greek = "αβγ ψω ΑΩ ς";
+        assert_eq!(decode_utf8(greek.as_bytes(), 100, 1), "βγδ ωα ΒΑ ς".as_bytes());
+        let russian = "Привет, мир! Zz";
+        assert_eq!(decode_utf8(russian.as_bytes(), 100, 1), "Рсйгёу, нйс! Aa".as_bytes());
+        // rotating by the rest of each alphabet comes back round
+        let there = decode_utf8(russian.as_bytes(), 100, 5);
+        assert_eq!(decode_utf8(&there, 100, 28), "Привет, мир! Gg".as_bytes());
+        // the Latin alphabet alone leaves the others be
+        let mut rot = Utf8RotDecoder::new(russian.as_bytes(), 1);
+        let mut result = String::new();
+        rot.read_to_string(&mut result).unwrap();
+        assert_eq!(result, "Привет, мир! Aa");
+    }
+
+    #[test]
+    fn utf8_split_across_reads() {
+        let input = "Ελλάδα, Россия, Gb trg gb gur bgure fvqr! ✓".as_bytes();
+        let whole = decode_utf8(input, 1000, 13);
+        assert!(std::str::from_utf8(&whole).is_ok());
+        for max in 1..8 {
+            assert_eq!(decode_utf8(input, max, 13), whole, "reading at most {max} at a time");
+        }
+        // a caller reading a byte at a time still gets all of it
+        let mut rot = Utf8RotDecoder::new(input, 13);
+        let mut result = Vec::new();
+        let mut byte = [0u8; 1];
+        while rot.read(&mut byte).unwrap() == 1 {
+            result.push(byte[0]);
+        }
+        assert_eq!(result, "Ελλάδα, Россия, To get to the other side! ✓".as_bytes());
+    }
+
+    #[test]
+    fn utf8_never_invalid() {
+        // a stray continuation byte, an invalid lead byte and a character
+        // cut off by the end of the input
+        let input = b"a\x80b\xffc\xce";
+        for max in 1..4 {
+            let result = decode_utf8(input, max, 1);
+            assert_eq!(String::from_utf8(result).unwrap(), "b\u{FFFD}c\u{FFFD}d\u{FFFD}");
+        }
+        let binary: Vec<u8> = (0..=255u8).cycle().take(3000).collect();
+        for max in [1, 2, 3, 100] {
+            assert!(String::from_utf8(decode_utf8(&binary, max, 7)).is_ok());
+        }
+    }
+
+    const PLAIN: &str = "It was the best of times, it was the worst of times, it was the age of \
+        wisdom, it was the age of foolishness, it was the epoch of belief, it was the epoch of \
+        incredulity, it was the season of Light, it was the season of Darkness.";
+
+    #[test]
+    fn cracks_every_rotation() {
+        for rot in 0..26 {
+            let mut encoder = RotEncoder::new(Vec::new(), rot);
+            encoder.write_all(PLAIN.as_bytes()).unwrap();
+            let crack = crack(&encoder.output);
+            assert_eq!(crack.rot, rot);
+            assert!(crack.confidence > 0.5, "rot {rot}: {crack:?}");
+
+            let mut result = String::new();
+            RotDecoder::new(encoder.output.as_slice(), crack.rot).read_to_string(&mut result).unwrap();
+            assert_eq!(result, PLAIN);
+        }
+        assert_eq!(crack(b"Gb trg gb gur bgure fvqr!").rot, 13);
+    }
+
+    #[test]
+    fn crack_confidence() {
+        assert_eq!(crack(b""), Crack { rot: 0, confidence: 0.0 });
+        assert_eq!(crack(b"1234 !?").confidence, 0.0);
+        // every letter once looks no more like English one way than another
+        let all = crack(b"abcdefghijklmnopqrstuvwxyz");
+        let long = crack(PLAIN.as_bytes());
+        assert!(all.confidence < 0.01, "{all:?}");
+        assert!(long.confidence > crack(b"It was the best").confidence);
+    }
+
+    #[test]
+    fn encode() {
+        let mut rot = RotEncoder { output: Vec::new(), rot: 13 };
+        rot.write_all(b"To get to the other side!").unwrap();
+        assert_eq!(rot.output, b"Gb trg gb gur bgure fvqr!");
+
+        let mut rot = RotEncoder { output: Vec::new(), rot: 3 };
+        rot.write_all(b"Khoor, Zruog!").unwrap();
+        assert_eq!(rot.output, b"Hello, World!");
+    }
+
+    #[test]
+    fn partial_writes() {
+        let input: Vec<u8> = (0..=255u8).cycle().take(2000).collect();
+        for max in [1, 3, CHUNK, 5000] {
+            let mut rot = RotEncoder { output: Narrow::new(max), rot: 5 };
+            rot.write_all(&input).unwrap();
+            rot.flush().unwrap();
+            assert!(rot.output.flushed);
+
+            let mut decoded = Vec::new();
+            let written = rot.output.written;
+            RotDecoder { input: written.as_slice(), rot: 5 }.read_to_end(&mut decoded).unwrap();
+            assert_eq!(decoded, input, "writing at most {max} at a time");
+        }
+    }
+
+    #[test]
+    fn conforming() {
+        for rot in [0, 3, 13, 200] {
+            let rotated = |rot| move |input: &[u8]| input.iter().map(|&b| rotate(b, rot)).collect();
+            conformance::reads(rotated(rot), |trickle| RotDecoder::new(trickle, rot));
+            let back = SIZE_OF_ALPHABET - rot % SIZE_OF_ALPHABET;
+            let into_inner = |rot: RotEncoder<_>| Ok(rot.into_inner());
+            conformance::writes(rotated(back), |narrow| RotEncoder::new(narrow, rot), into_inner);
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,203 @@
+// A poll-based `RotDecoder`, for event loops that cannot block on a read.
+// `AsyncRead` has the shape of the trait of the same name in the async
+// runtimes but is defined here, so that nothing beyond std is needed;
+// wrapping a runtime's reader in it takes a few lines.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use super::rotate;
+
+/// A source of bytes that may not have any yet. `Poll::Pending` means none
+/// are ready, and that the waker of `cx` will be woken once there may be.
+/// Otherwise it works like `Read::read`: `Ok(0)` is the end of the input.
+pub trait AsyncRead {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>>;
+}
+
+impl<R: AsyncRead + Unpin + ?Sized> AsyncRead for &mut R {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut **self).poll_read(cx, buf)
+    }
+}
+
+/// Always ready.
+impl AsyncRead for &[u8] {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(io::Read::read(&mut *self, buf))
+    }
+}
+
+/// Rotates what is read through it, as `RotDecoder` does. While the input
+/// is pending, so is the decoder, and `buf` is left alone.
+pub struct AsyncRotDecoder<R> {
+    input: R,
+    rot: u8,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRotDecoder<R> {
+    pub fn new(input: R, rot: u8) -> Self {
+        AsyncRotDecoder { input, rot }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.input
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for AsyncRotDecoder<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let size = ready!(Pin::new(&mut self.input).poll_read(cx, buf))?;
+        for b in &mut buf[..size] {
+            *b = rotate(*b, self.rot);
+        }
+        Poll::Ready(Ok(size))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::task::{Wake, Waker};
+
+    /// Counts how often it was woken.
+    #[derive(Default)]
+    struct Wakes(AtomicUsize);
+
+    impl Wake for Wakes {
+        fn wake(self: Arc<Self>) {
+            self.wake_by_ref();
+        }
+
+        fn wake_by_ref(self: &Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Pending before every chunk, waking the waker straight away as if the
+    /// data had just come in. A chunk that is an error is returned as one.
+    struct Mock {
+        chunks: VecDeque<io::Result<Vec<u8>>>,
+        arrived: bool,
+    }
+
+    impl Mock {
+        fn new(chunks: &[&str]) -> Self {
+            let chunks = chunks.iter().map(|chunk| Ok(chunk.as_bytes().to_vec())).collect();
+            Mock { chunks, arrived: false }
+        }
+    }
+
+    impl AsyncRead for Mock {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            if !self.arrived && !self.chunks.is_empty() {
+                self.arrived = true;
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            let Some(chunk) = self.chunks.front_mut() else {
+                return Poll::Ready(Ok(0));
+            };
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(_) => return Poll::Ready(Err(self.chunks.pop_front().unwrap().unwrap_err())),
+            };
+            let size = buf.len().min(chunk.len());
+            buf[..size].copy_from_slice(&chunk[..size]);
+            chunk.drain(..size);
+            if chunk.is_empty() {
+                self.chunks.pop_front();
+                self.arrived = false;
+            }
+            Poll::Ready(Ok(size))
+        }
+    }
+
+    /// Poll `reader` to the end, as an event loop would, and count how often
+    /// it was pending. Being pending without arranging to be woken would
+    /// hang a real event loop, so that fails the test.
+    fn read_all(mut reader: impl AsyncRead + Unpin, buf_size: usize) -> io::Result<(String, usize)> {
+        let wakes = Arc::new(Wakes::default());
+        let waker = Waker::from(wakes.clone());
+        let mut cx = Context::from_waker(&waker);
+        let mut result = Vec::new();
+        let mut buf = vec![b'#'; buf_size];
+        let mut pending = 0;
+        loop {
+            match Pin::new(&mut reader).poll_read(&mut cx, &mut buf) {
+                Poll::Pending => {
+                    pending += 1;
+                    assert_eq!(wakes.0.load(Ordering::SeqCst), pending, "pending without a wake-up");
+                    assert!(buf.iter().all(|&b| b == b'#'), "pending but wrote to the buffer");
+                }
+                Poll::Ready(Ok(0)) => break,
+                Poll::Ready(Ok(size)) => {
+                    result.extend_from_slice(&buf[..size]);
+                    buf.fill(b'#');
+                }
+                Poll::Ready(Err(e)) => return Err(e),
+            }
+        }
+        Ok((String::from_utf8(result).unwrap(), pending))
+    }
+
+    #[test]
+    fn decodes_between_pending() {
+        let mock = Mock::new(&["Gb trg ", "gb gur", " bgure fvqr!"]);
+        let (result, pending) = read_all(AsyncRotDecoder::new(mock, 13), 64).unwrap();
+        assert_eq!(result, "To get to the other side!");
+        assert_eq!(pending, 3);
+
+        // a buffer smaller than the chunks takes several reads of each
+        let mock = Mock::new(&["Gb trg ", "gb gur", " bgure fvqr!"]);
+        let (result, pending) = read_all(AsyncRotDecoder::new(mock, 13), 4).unwrap();
+        assert_eq!(result, "To get to the other side!");
+        assert_eq!(pending, 3);
+    }
+
+    #[test]
+    fn ready_input_and_nesting() {
+        let (result, pending) = read_all(AsyncRotDecoder::new("Uryyb".as_bytes(), 13), 2).unwrap();
+        assert_eq!((result.as_str(), pending), ("Hello", 0));
+
+        // decoders nest, and a rotation of 13 twice is none at all
+        let mut mock = Mock::new(&["Uryyb", ", ", "jbeyq"]);
+        let inner = AsyncRotDecoder::new(&mut mock, 13);
+        let (result, _) = read_all(AsyncRotDecoder::new(inner, 13 + 26 * 5), 3).unwrap();
+        assert_eq!(result, "Uryyb, jbeyq");
+        assert!(mock.chunks.is_empty());
+    }
+
+    #[test]
+    fn errors_pass_through() {
+        let mut mock = Mock::new(&["Uryyb"]);
+        mock.chunks.push_back(Err(io::Error::new(io::ErrorKind::ConnectionReset, "gone")));
+        let error = read_all(AsyncRotDecoder::new(&mut mock, 13), 16).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionReset);
+        assert_eq!(error.to_string(), "gone");
+    }
+}
@@ -0,0 +1,317 @@
+// Byte transforms that can be chained and run over any stream. A
+// `Transform` only says what becomes of the bytes; `Reader` and `Writer`
+// do the streaming for all of them:
+//
+//     let t = Rot(13).then(Uppercase).then(Hex).then(Base64::default());
+//     let mut encoded = Reader::new(input, t);
+//
+// Transforms may change the length of what goes through them, and may hold
+// on to a few bytes until they know what to make of them, which is what
+// `finish` is for.
+
+#[cfg(test)]
+pub(crate) mod conformance;
+
+use std::io::{self, Read, Write};
+
+use crate::rot13;
+
+/// How much of the input is transformed at a time.
+const CHUNK: usize = 4 * 1024;
+
+pub trait Transform {
+    /// Append what becomes of `input` to `output`.
+    fn transform(&mut self, input: &[u8], output: &mut Vec<u8>);
+
+    /// The input has ended, append anything still held back.
+    fn finish(&mut self, _output: &mut Vec<u8>) {}
+
+    /// Feed what this transform makes into `next`.
+    fn then<T: Transform>(self, next: T) -> Then<Self, T>
+    where
+        Self: Sized,
+    {
+        Then { first: self, second: next, between: Vec::new() }
+    }
+}
+
+/// Two transforms one after the other, see `Transform::then`.
+pub struct Then<A, B> {
+    first: A,
+    second: B,
+    between: Vec<u8>,
+}
+
+impl<A: Transform, B: Transform> Transform for Then<A, B> {
+    fn transform(&mut self, input: &[u8], output: &mut Vec<u8>) {
+        self.between.clear();
+        self.first.transform(input, &mut self.between);
+        self.second.transform(&self.between, output);
+    }
+
+    fn finish(&mut self, output: &mut Vec<u8>) {
+        self.between.clear();
+        self.first.finish(&mut self.between);
+        self.second.transform(&self.between, output);
+        self.second.finish(output);
+    }
+}
+
+/// Rotate letters along the alphabet, as `rot13::RotDecoder` does.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rot(pub u8);
+
+impl Transform for Rot {
+    fn transform(&mut self, input: &[u8], output: &mut Vec<u8>) {
+        output.extend(input.iter().map(|&b| rot13::rotate(b, self.0)));
+    }
+}
+
+/// Make ASCII letters uppercase.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Uppercase;
+
+impl Transform for Uppercase {
+    fn transform(&mut self, input: &[u8], output: &mut Vec<u8>) {
+        output.extend(input.iter().map(u8::to_ascii_uppercase));
+    }
+}
+
+/// Write every byte as two lowercase hex digits.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Hex;
+
+impl Transform for Hex {
+    fn transform(&mut self, input: &[u8], output: &mut Vec<u8>) {
+        const DIGITS: &[u8; 16] = b"0123456789abcdef";
+        for &b in input {
+            output.extend([DIGITS[usize::from(b >> 4)], DIGITS[usize::from(b & 0xf)]]);
+        }
+    }
+}
+
+/// Standard base64, with `=` padding. Three bytes make four characters, so
+/// up to two are held back until more come or the input ends.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Base64 {
+    held: Vec<u8>,
+}
+
+impl Base64 {
+    const ALPHABET: &'static [u8; 64] =
+        b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    /// Encode up to three bytes, padding out the missing ones.
+    fn group(bytes: &[u8], output: &mut Vec<u8>) {
+        let byte = |i| bytes.get(i).copied().unwrap_or(0) as u32;
+        let bits = byte(0) << 16 | byte(1) << 8 | byte(2);
+        for i in 0..4 {
+            if i <= bytes.len() {
+                output.push(Self::ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize]);
+            } else {
+                output.push(b'=');
+            }
+        }
+    }
+}
+
+impl Transform for Base64 {
+    fn transform(&mut self, input: &[u8], output: &mut Vec<u8>) {
+        self.held.extend_from_slice(input);
+        let whole = self.held.len() / 3 * 3;
+        for group in self.held[..whole].chunks(3) {
+            Self::group(group, output);
+        }
+        self.held.drain(..whole);
+    }
+
+    fn finish(&mut self, output: &mut Vec<u8>) {
+        if !self.held.is_empty() {
+            Self::group(&self.held, output);
+            self.held.clear();
+        }
+    }
+}
+
+/// Transforms what is read from the reader it wraps.
+pub struct Reader<R, T> {
+    input: R,
+    transform: T,
+    /// Transformed, but not all of it read yet.
+    ready: Vec<u8>,
+    /// How much of `ready` has been read.
+    start: usize,
+    /// The input has ended and the transform was finished.
+    done: bool,
+}
+
+impl<R: Read, T: Transform> Reader<R, T> {
+    pub fn new(input: R, transform: T) -> Self {
+        Reader { input, transform, ready: Vec::new(), start: 0, done: false }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.input
+    }
+}
+
+impl<R: Read, T: Transform> Read for Reader<R, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.start == self.ready.len() {
+            // clearing it rather than moving what is left up after every
+            // read, which may be little out of a lot
+            self.ready.clear();
+            self.start = 0;
+        }
+        // a transform may make nothing of a read, so read until it does
+        while self.ready.is_empty() && !self.done && !buf.is_empty() {
+            let mut chunk = [0u8; CHUNK];
+            // an error leaves everything as it was, so the read can be tried
+            // again
+            let size = self.input.read(&mut chunk)?;
+            if size == 0 {
+                self.transform.finish(&mut self.ready);
+                self.done = true;
+            } else {
+                self.transform.transform(&chunk[..size], &mut self.ready);
+            }
+        }
+        let ready = &self.ready[self.start..];
+        let size = buf.len().min(ready.len());
+        buf[..size].copy_from_slice(&ready[..size]);
+        self.start += size;
+        Ok(size)
+    }
+}
+
+/// Transforms what is written to it before passing it on. What it made is
+/// passed on a chunk at a time, so call `finish` at the end: it lets the
+/// transform add what it held back and passes on the rest. Whatever is
+/// left when it is dropped is lost.
+pub struct Writer<W, T> {
+    output: W,
+    transform: T,
+    /// Transformed, but not passed on yet.
+    pending: Vec<u8>,
+}
+
+impl<W: Write, T: Transform> Writer<W, T> {
+    pub fn new(output: W, transform: T) -> Self {
+        Writer { output, transform, pending: Vec::new() }
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        self.transform.finish(&mut self.pending);
+        self.flush()?;
+        Ok(self.output)
+    }
+
+    /// Pass on everything pending, however many writes it takes.
+    fn pass_on(&mut self) -> io::Result<()> {
+        let mut written = 0;
+        let result = loop {
+            if written == self.pending.len() {
+                break Ok(());
+            }
+            match self.output.write(&self.pending[written..]) {
+                Ok(0) => break Err(io::ErrorKind::WriteZero.into()),
+                Ok(size) => written += size,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => break Err(e),
+            }
+        };
+        // keep what was not written for the next try
+        self.pending.drain(..written);
+        result
+    }
+}
+
+impl<W: Write, T: Transform> Write for Writer<W, T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.pending.len() >= CHUNK {
+            self.pass_on()?;
+        }
+        let size = buf.len().min(CHUNK);
+        self.transform.transform(&buf[..size], &mut self.pending);
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.pass_on()?;
+        self.output.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Run the shared checks on `Reader` and `Writer` with what `make`
+    /// makes, against the transform applied to the whole input at once.
+    fn conforms<T: Transform>(make: impl Fn() -> T) {
+        let expected = |input: &[u8]| {
+            let mut transform = make();
+            let mut output = Vec::new();
+            transform.transform(input, &mut output);
+            transform.finish(&mut output);
+            output
+        };
+        conformance::reads(expected, |trickle| Reader::new(trickle, make()));
+        conformance::writes(expected, |narrow| Writer::new(narrow, make()), Writer::finish);
+    }
+
+    fn apply(mut transform: impl Transform, input: &str) -> String {
+        let mut output = Vec::new();
+        transform.transform(input.as_bytes(), &mut output);
+        transform.finish(&mut output);
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn transforms() {
+        assert_eq!(apply(Rot(13), "Uryyb, jbeyq!"), "Hello, world!");
+        assert_eq!(apply(Uppercase, "Hello, world!"), "HELLO, WORLD!");
+        assert_eq!(apply(Hex, "Hi\n"), "48690a");
+        let base64 = [("", ""), ("M", "TQ=="), ("Ma", "TWE="), ("Man", "TWFu"), ("Many", "TWFueQ==")];
+        for (plain, encoded) in base64 {
+            assert_eq!(apply(Base64::default(), plain), encoded);
+        }
+        let chain = Rot(13).then(Uppercase).then(Hex).then(Base64::default());
+        assert_eq!(apply(chain, "Uryyb"), "NDg0NTRjNGM0Zg==");
+    }
+
+    #[test]
+    fn base64_across_pieces() {
+        let mut base64 = Base64::default();
+        let mut output = Vec::new();
+        for piece in [&b"M"[..], b"a", b"", b"nyM", b"a"] {
+            base64.transform(piece, &mut output);
+        }
+        base64.finish(&mut output);
+        assert_eq!(output, b"TWFueU1h");
+    }
+
+    #[test]
+    fn chains_of_streams() {
+        // adapters nest like any other reader and writer
+        let reader = Reader::new(Reader::new("Uryyb".as_bytes(), Rot(13)), Uppercase.then(Hex));
+        let mut result = String::new();
+        Reader::new(reader, Base64::default()).read_to_string(&mut result).unwrap();
+        assert_eq!(result, "NDg0NTRjNGM0Zg==");
+
+        let mut writer = Writer::new(Vec::new(), Rot(13).then(Uppercase).then(Hex));
+        writer.write_all(b"Uryyb").unwrap();
+        assert_eq!(writer.finish().unwrap(), b"48454c4c4f");
+    }
+
+    #[test]
+    fn conforming() {
+        conforms(|| Rot(13));
+        conforms(|| Rot(200));
+        conforms(|| Uppercase);
+        conforms(|| Hex);
+        conforms(Base64::default);
+        conforms(|| Rot(13).then(Uppercase).then(Hex).then(Base64::default()));
+        conforms(|| Base64::default().then(Base64::default()));
+    }
+}
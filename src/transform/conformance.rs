@@ -0,0 +1,154 @@
+// Readers and writers that make life hard for the stream adapters in this
+// crate, and the checks every adapter has to pass with them: the same
+// result for the same input however it is read or written, through short
+// reads, partial writes and interruptions, and nothing more after the end
+// of the input.
+
+use std::io::{self, Read, Write};
+
+/// Hands out at most `max` bytes a read, and when interrupting fails with
+/// `Interrupted` before each read of data.
+pub(crate) struct Trickle {
+    data: Vec<u8>,
+    position: usize,
+    max: usize,
+    interrupt: bool,
+    interrupted: bool,
+}
+
+impl Trickle {
+    pub(crate) fn new(data: &[u8], max: usize) -> Self {
+        Trickle { data: data.to_vec(), position: 0, max, interrupt: false, interrupted: false }
+    }
+
+    pub(crate) fn interrupting(mut self) -> Self {
+        self.interrupt = true;
+        self
+    }
+}
+
+impl Read for Trickle {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let rest = &self.data[self.position..];
+        // the end is not interrupted, so it stays the end
+        if self.interrupt && !rest.is_empty() {
+            self.interrupted = !self.interrupted;
+            if self.interrupted {
+                return Err(io::ErrorKind::Interrupted.into());
+            }
+        }
+        let size = buf.len().min(self.max).min(rest.len());
+        buf[..size].copy_from_slice(&rest[..size]);
+        self.position += size;
+        Ok(size)
+    }
+}
+
+/// Takes at most `max` bytes a write, and when interrupting fails with
+/// `Interrupted` before each.
+pub(crate) struct Narrow {
+    pub(crate) written: Vec<u8>,
+    /// Everything written so far has been flushed.
+    pub(crate) flushed: bool,
+    max: usize,
+    interrupt: bool,
+    interrupted: bool,
+}
+
+impl Narrow {
+    pub(crate) fn new(max: usize) -> Self {
+        Narrow { written: Vec::new(), flushed: true, max, interrupt: false, interrupted: false }
+    }
+
+    pub(crate) fn interrupting(mut self) -> Self {
+        self.interrupt = true;
+        self
+    }
+}
+
+impl Write for Narrow {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.interrupt {
+            self.interrupted = !self.interrupted;
+            if self.interrupted {
+                return Err(io::ErrorKind::Interrupted.into());
+            }
+        }
+        let size = buf.len().min(self.max);
+        self.written.extend_from_slice(&buf[..size]);
+        self.flushed &= size == 0;
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flushed = true;
+        Ok(())
+    }
+}
+
+/// How much a `Trickle` or `Narrow` takes at a time, and whether it is
+/// interrupted, in each round of the checks.
+const ROUNDS: [(usize, bool); 4] = [(1, false), (2, true), (7, false), (5000, true)];
+
+/// What the checks go through. The last is longer than any adapter's
+/// buffer, and has every byte value in it.
+fn inputs() -> Vec<Vec<u8>> {
+    let long = (0..=255u8).cycle().take(3 * 4096 + 1).collect();
+    vec![Vec::new(), b"a".to_vec(), b"Hello, World!".to_vec(), vec![0, 1, 2, 255, 254], long]
+}
+
+/// Check a reading adapter: reading through what `make` wraps around a
+/// reader of the input has to give `expected(input)`.
+pub(crate) fn reads<R: Read>(expected: impl Fn(&[u8]) -> Vec<u8>, make: impl Fn(Trickle) -> R) {
+    for input in inputs() {
+        let expected = expected(&input);
+        for (max, interrupt) in ROUNDS {
+            let trickle = Trickle::new(&input, max);
+            let mut reader = make(if interrupt { trickle.interrupting() } else { trickle });
+            let mut result = Vec::new();
+            // read_to_end tries again after an interruption
+            reader.read_to_end(&mut result).unwrap();
+            assert_eq!(result, expected, "reading at most {max} at a time");
+            // the end stays the end
+            assert_eq!(reader.read(&mut [0; 8]).unwrap(), 0);
+            assert_eq!(reader.read(&mut [0; 8]).unwrap(), 0);
+        }
+
+        // reading into small buffers
+        let mut reader = make(Trickle::new(&input, usize::MAX));
+        let mut result = Vec::new();
+        let mut buf = [0; 3];
+        loop {
+            match reader.read(&mut buf).unwrap() {
+                0 => break,
+                size => result.extend_from_slice(&buf[..size]),
+            }
+        }
+        assert_eq!(result, expected, "reading 3 at a time");
+        assert_eq!(reader.read(&mut []).unwrap(), 0);
+    }
+}
+
+/// Check a writing adapter: writing the input through what `make` wraps
+/// around a writer, flushing and then unwrapping it with `finish` has to
+/// leave `expected(input)` written and flushed.
+pub(crate) fn writes<W: Write>(
+    expected: impl Fn(&[u8]) -> Vec<u8>,
+    make: impl Fn(Narrow) -> W,
+    finish: impl Fn(W) -> io::Result<Narrow>,
+) {
+    for input in inputs() {
+        let expected = expected(&input);
+        for (max, interrupt) in ROUNDS {
+            let narrow = Narrow::new(max);
+            let mut writer = make(if interrupt { narrow.interrupting() } else { narrow });
+            for piece in input.chunks(max) {
+                writer.write_all(piece).unwrap();
+            }
+            writer.flush().unwrap();
+            let narrow = finish(writer).unwrap();
+            assert_eq!(narrow.written, expected, "writing at most {max} at a time");
+            assert!(narrow.flushed, "writing at most {max} at a time");
+        }
+    }
+}